@@ -0,0 +1,29 @@
+use std::time::Duration;
+use tcp_server::client::AsyncClient;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let client = AsyncClient::connect("127.0.0.1:8080").await?;
+
+    println!("Sending ping...");
+    println!("Received: {}", client.ping().await?);
+
+    println!("\nStoring from 100 tasks over one connection...");
+    let mut tasks = Vec::new();
+    for i in 0..100 {
+        let client = client.clone();
+        tasks.push(tokio::spawn(async move {
+            client.store(&format!("task:{}", i), format!("{}", i).into_bytes()).await
+        }));
+    }
+    for task in tasks {
+        task.await??;
+    }
+    println!("Done");
+
+    println!("\nRetrieving with a 100ms timeout...");
+    let value = client.with_timeout(Duration::from_millis(100)).retrieve("task:42").await?;
+    println!("task:42 = {:?}", value.map(|v| String::from_utf8_lossy(&v).to_string()));
+
+    Ok(())
+}
@@ -40,7 +40,7 @@ fn main() -> io::Result<()> {
                 let value = parts[2..].join(" ");
                 // using your bincode serialization format
                 let payload = bincode::serialize(&(key, value))
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    .map_err(io::Error::other)?;
                 create_message(request_id, 2, &payload)?  // OpCode::Store
             }
             "RETRIEVE" => {
@@ -49,7 +49,7 @@ fn main() -> io::Result<()> {
                     continue;
                 }
                 let payload = bincode::serialize(&parts[1])
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    .map_err(io::Error::other)?;
                 create_message(request_id, 3, &payload)?  // OpCode::Retrieve
             }
             "DELETE" => {
@@ -58,7 +58,7 @@ fn main() -> io::Result<()> {
                     continue;
                 }
                 let payload = bincode::serialize(&parts[1])
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    .map_err(io::Error::other)?;
                 create_message(request_id, 4, &payload)?  // OpCode::Delete
             }
             "LIST" => {
@@ -83,9 +83,9 @@ fn main() -> io::Result<()> {
                             Err(_) => println!("Success (ID: {}): <binary data>", resp_id),
                         }
                     },
-                    3 => { // error
+                    3 => { // error: code byte, then the message
                         println!("Error (ID: {}): {}", resp_id,
-                            String::from_utf8_lossy(&payload));
+                            String::from_utf8_lossy(payload.get(1..).unwrap_or_default()));
                     },
                     _ => println!("Unknown response type: {}", msg_type),
                 }
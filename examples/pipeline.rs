@@ -0,0 +1,23 @@
+use tcp_server::client::Client;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = Client::connect("127.0.0.1:8080")?;
+
+    println!("Storing 1000 keys in one round trip...");
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.store(&format!("key:{}", i), format!("value:{}", i).into_bytes())?;
+    }
+    let responses = pipeline.execute()?;
+    let failed = responses.iter().filter(|response| response.is_error()).count();
+    println!("Stored {} keys ({} failed)", responses.len() - failed, failed);
+
+    println!("\nReading a few back...");
+    let mut pipeline = client.pipeline();
+    pipeline.retrieve("key:1")?.retrieve("key:500")?.retrieve("missing")?;
+    for response in pipeline.execute()? {
+        println!("{} -> {}", response.request_id, String::from_utf8_lossy(&response.payload));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,25 @@
+use std::thread;
+use tcp_server::client::{ClientPool, PoolConfig};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = ClientPool::new("127.0.0.1:8080", PoolConfig {
+        max_size: 4,
+        ..PoolConfig::default()
+    });
+
+    let workers: Vec<_> = (0..16)
+        .map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                pool.with_client(|client| client.store(&format!("worker:{}", i), vec![i as u8]))
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        worker.join().expect("worker panicked")?;
+    }
+
+    println!("{}", pool.metrics());
+    Ok(())
+}
@@ -0,0 +1,391 @@
+use crate::error::{Result, ServerError};
+use crate::protocol::codec::MessageCodec;
+use crate::protocol::message::{ErrorCode, Message, OpCode, DEFAULT_MAX_FRAME_SIZE};
+use crate::pubsub::{Published, SlowSubscriberPolicy, WatchTarget, DEFAULT_PUSH_BUFFER};
+use crate::storage::KeyEvent;
+use futures::{SinkExt, Stream, StreamExt};
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_QUEUE_SIZE: usize = 1024;
+
+type ResponseSender = oneshot::Sender<Result<Message>>;
+type PushSender = mpsc::Sender<Message>;
+type PushReceiver = mpsc::Receiver<Message>;
+
+/// Connection settings for [`AsyncClient::connect_with_config`].
+#[derive(Debug, Clone, Copy)]
+pub struct AsyncClientConfig {
+    pub max_frame_size: usize,
+    /// Push frames buffered per stream until the application reads them.
+    pub push_buffer: usize,
+    /// What happens to a push that arrives while its stream's buffer is
+    /// full, as on the server: drop it, or close the connection.
+    pub slow_subscriber_policy: SlowSubscriberPolicy,
+}
+
+impl Default for AsyncClientConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            push_buffer: DEFAULT_PUSH_BUFFER,
+            slow_subscriber_policy: SlowSubscriberPolicy::default(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    waiters: HashMap<u32, ResponseSender>,
+    closed: bool,
+}
+
+impl Pending {
+    fn close(&mut self, reason: &str) {
+        self.closed = true;
+        for (_, waiter) in self.waiters.drain() {
+            let _ = waiter.send(Err(ServerError::Connection(reason.to_string())));
+        }
+    }
+}
+
+/// Tokio client that multiplexes concurrent requests over one connection.
+///
+/// Clones share the connection: a background writer task sends requests as
+/// they are queued and a background reader task hands each response to the
+/// caller waiting on its `request_id`. Every call fails with
+/// `ErrorKind::TimedOut` if no response arrives within the client's timeout.
+#[derive(Clone)]
+pub struct AsyncClient {
+    requests: mpsc::Sender<Message>,
+    pending: Arc<Mutex<Pending>>,
+    request_id: Arc<AtomicU32>,
+    timeout: Duration,
+    // push frames, until a caller takes them with `messages` or `events`
+    published: Arc<Mutex<Option<PushReceiver>>>,
+    events: Arc<Mutex<Option<PushReceiver>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AsyncClient {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Self::connect_with_config(addr, AsyncClientConfig::default()).await
+    }
+
+    pub async fn connect_with_max_frame_size<A: ToSocketAddrs>(
+        addr: A,
+        max_frame_size: usize,
+    ) -> Result<Self> {
+        let config = AsyncClientConfig {
+            max_frame_size,
+            ..AsyncClientConfig::default()
+        };
+        Self::connect_with_config(addr, config).await
+    }
+
+    pub async fn connect_with_config<A: ToSocketAddrs>(addr: A, config: AsyncClientConfig) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+        let codec = MessageCodec::with_max_frame_size(config.max_frame_size);
+
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let dropped = Arc::new(AtomicU64::new(0));
+        let disconnect = Arc::new(Notify::new());
+        let (requests, queue) = mpsc::channel(REQUEST_QUEUE_SIZE);
+        let (messages, published) = mpsc::channel(config.push_buffer.max(1));
+        let (changes, events) = mpsc::channel(config.push_buffer.max(1));
+
+        tokio::spawn(Self::write_loop(
+            FramedWrite::new(writer, codec),
+            queue,
+            pending.clone(),
+            disconnect.clone(),
+        ));
+        tokio::spawn(Self::read_loop(
+            FramedRead::new(reader, codec),
+            pending.clone(),
+            [messages, changes],
+            config.slow_subscriber_policy,
+            dropped.clone(),
+            disconnect,
+        ));
+
+        Ok(Self {
+            requests,
+            pending,
+            request_id: Arc::new(AtomicU32::new(1)),
+            timeout: DEFAULT_TIMEOUT,
+            published: Arc::new(Mutex::new(Some(published))),
+            events: Arc::new(Mutex::new(Some(events))),
+            dropped,
+        })
+    }
+
+    /// Returns a handle on the same connection whose calls use `timeout`.
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Self {
+            timeout,
+            ..self.clone()
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub async fn ping(&self) -> Result<String> {
+        let response = self.call(OpCode::Ping, Vec::new()).await?;
+        Ok(String::from_utf8_lossy(&response.payload).to_string())
+    }
+
+    pub async fn store<T: Serialize>(&self, key: &str, value: T) -> Result<()> {
+        let payload = bincode::serialize(&(key, value)).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let response = self.call(OpCode::Store, payload).await?;
+        Self::check(response).map(|_| ())
+    }
+
+    pub async fn retrieve(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let payload = bincode::serialize(&key).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let response = self.call(OpCode::Retrieve, payload).await?;
+        if response.error_code() == Some(ErrorCode::KeyNotFound) {
+            return Ok(None);
+        }
+        Self::check(response).map(|response| Some(response.payload))
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        let payload = bincode::serialize(&key).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let response = self.call(OpCode::Delete, payload).await?;
+        Self::check(response).map(|_| ())
+    }
+
+    pub async fn list(&self) -> Result<Vec<String>> {
+        let response = Self::check(self.call(OpCode::List, Vec::new()).await?)?;
+        bincode::deserialize(&response.payload)
+            .map_err(|e| ServerError::Serialization(e.to_string()))
+    }
+
+    /// Subscribes this connection to `channels`; see [`AsyncClient::messages`].
+    pub async fn subscribe(&self, channels: &[&str]) -> Result<u32> {
+        self.subscription(OpCode::Subscribe, channels).await
+    }
+
+    pub async fn psubscribe(&self, patterns: &[&str]) -> Result<u32> {
+        self.subscription(OpCode::PSubscribe, patterns).await
+    }
+
+    pub async fn unsubscribe(&self, names: &[&str]) -> Result<u32> {
+        self.subscription(OpCode::Unsubscribe, names).await
+    }
+
+    pub async fn publish(&self, channel: &str, payload: &[u8]) -> Result<u64> {
+        let payload = bincode::serialize(&(channel, payload)).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let response = Self::check(self.call(OpCode::Publish, payload).await?)?;
+        bincode::deserialize(&response.payload)
+            .map_err(|e| ServerError::Serialization(e.to_string()))
+    }
+
+    /// Stream of the messages published to this connection's subscriptions,
+    /// ending when the connection closes. Up to `push_buffer` messages are
+    /// buffered from the first subscription on; only one stream can be taken
+    /// per connection, later calls return `None`.
+    pub fn messages(&self) -> Option<impl Stream<Item = Published> + Unpin> {
+        Self::push_stream(&self.published)
+    }
+
+    /// Pushes a [`KeyEvent`] for every change to the keys `targets` cover;
+    /// see [`AsyncClient::events`].
+    pub async fn watch(&self, targets: &[WatchTarget]) -> Result<u32> {
+        self.watch_request(OpCode::Watch, targets).await
+    }
+
+    pub async fn unwatch(&self, targets: &[WatchTarget]) -> Result<u32> {
+        self.watch_request(OpCode::Unwatch, targets).await
+    }
+
+    /// Stream of the changes to watched keys; like [`AsyncClient::messages`],
+    /// it can be taken once.
+    pub fn events(&self) -> Option<impl Stream<Item = KeyEvent> + Unpin> {
+        Self::push_stream(&self.events)
+    }
+
+    /// Pushes dropped so far because their stream's buffer was full, under
+    /// [`SlowSubscriberPolicy::Drop`].
+    pub fn dropped_pushes(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn push_stream<T: DeserializeOwned>(slot: &Mutex<Option<PushReceiver>>) -> Option<impl Stream<Item = T> + Unpin> {
+        let pushes = slot.lock().unwrap().take()?;
+        Some(Box::pin(futures::stream::unfold(pushes, |mut pushes| async move {
+            loop {
+                let push = pushes.recv().await?;
+                match bincode::deserialize(&push.payload) {
+                    Ok(item) => return Some((item, pushes)),
+                    Err(e) => warn!("Discarding malformed push: {}", e),
+                }
+            }
+        })))
+    }
+
+    async fn watch_request(&self, op_code: OpCode, targets: &[WatchTarget]) -> Result<u32> {
+        let payload = bincode::serialize(&targets).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let response = Self::check(self.call(op_code, payload).await?)?;
+        bincode::deserialize(&response.payload)
+            .map_err(|e| ServerError::Serialization(e.to_string()))
+    }
+
+    async fn subscription(&self, op_code: OpCode, names: &[&str]) -> Result<u32> {
+        let payload = bincode::serialize(&names).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let response = Self::check(self.call(op_code, payload).await?)?;
+        bincode::deserialize(&response.payload)
+            .map_err(|e| ServerError::Serialization(e.to_string()))
+    }
+
+    /// Sends a raw request and waits for the response carrying its `request_id`.
+    pub async fn call(&self, op_code: OpCode, payload: Vec<u8>) -> Result<Message> {
+        let request_id = self.request_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.closed {
+                return Err(ServerError::Connection("Connection closed".into()));
+            }
+            pending.waiters.insert(request_id, tx);
+        }
+
+        let message = Message::new_request(request_id, op_code, payload);
+        if self.requests.send(message).await.is_err() {
+            self.forget(request_id);
+            return Err(ServerError::Connection("Connection closed".into()));
+        }
+
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) => Err(ServerError::Connection("Connection closed".into())),
+            Err(_) => {
+                self.forget(request_id);
+                Err(Error::new(ErrorKind::TimedOut, format!("Request {} timed out", request_id)).into())
+            }
+        }
+    }
+
+    fn forget(&self, request_id: u32) {
+        self.pending.lock().unwrap().waiters.remove(&request_id);
+    }
+
+    fn check(response: Message) -> Result<Message> {
+        if response.is_error() {
+            Err(ServerError::Client(response.error_message()))
+        } else {
+            Ok(response)
+        }
+    }
+
+    async fn write_loop(
+        mut sink: FramedWrite<OwnedWriteHalf, MessageCodec>,
+        mut queue: mpsc::Receiver<Message>,
+        pending: Arc<Mutex<Pending>>,
+        disconnect: Arc<Notify>,
+    ) {
+        loop {
+            let message = tokio::select! {
+                message = queue.recv() => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                // dropping the write half closes the connection
+                _ = disconnect.notified() => return,
+            };
+            let mut result = sink.feed(message).await;
+
+            // coalesce requests queued by other callers into one flush
+            while result.is_ok() {
+                match queue.try_recv() {
+                    Ok(message) => result = sink.feed(message).await,
+                    Err(_) => break,
+                }
+            }
+
+            if let Err(e) = result.and(sink.flush().await) {
+                warn!("Error writing request: {}", e);
+                pending.lock().unwrap().close("Connection closed");
+                return;
+            }
+        }
+
+        debug!("All client handles dropped, closing connection");
+    }
+
+    async fn read_loop(
+        mut frames: FramedRead<OwnedReadHalf, MessageCodec>,
+        pending: Arc<Mutex<Pending>>,
+        [messages, changes]: [PushSender; 2],
+        policy: SlowSubscriberPolicy,
+        dropped: Arc<AtomicU64>,
+        disconnect: Arc<Notify>,
+    ) {
+        while let Some(frame) = frames.next().await {
+            match frame {
+                Ok(push) if push.is_push() => {
+                    let pushes = match push.op_code {
+                        OpCode::Publish => &messages,
+                        OpCode::Watch => &changes,
+                        _ => continue,
+                    };
+                    match pushes.try_send(push) {
+                        // nobody may be listening; that is fine
+                        Ok(()) | Err(TrySendError::Closed(_)) => {}
+                        Err(TrySendError::Full(_)) => match policy {
+                            SlowSubscriberPolicy::Drop => {
+                                let dropped = dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                                debug!("Push buffer is full, dropped {} push(es)", dropped);
+                            }
+                            SlowSubscriberPolicy::Disconnect => {
+                                warn!("Push buffer is full, closing the connection");
+                                pending.lock().unwrap().close("Push buffer full");
+                                disconnect.notify_one();
+                                return;
+                            }
+                        },
+                    }
+                }
+                Ok(response) if response.is_going_away() => {
+                    // refuse new calls; responses already owed are still delivered
+                    debug!("Server is shutting down");
+                    pending.lock().unwrap().closed = true;
+                }
+                Ok(response) => {
+                    let waiter = pending.lock().unwrap().waiters.remove(&response.request_id);
+                    match waiter {
+                        Some(waiter) => {
+                            let _ = waiter.send(Ok(response));
+                        }
+                        None => debug!("Discarding response for unknown request {}", response.request_id),
+                    }
+                }
+                Err(e) => {
+                    warn!("Error reading response: {}", e);
+                    pending.lock().unwrap().close(&e.to_string());
+                    return;
+                }
+            }
+        }
+
+        pending.lock().unwrap().close("Connection closed");
+    }
+}
@@ -0,0 +1,30 @@
+use super::Client;
+use crate::error::Result;
+use crate::pubsub::Published;
+
+/// Blocking iterator over the messages published to a [`Client`]'s
+/// subscriptions, from [`Client::messages`]. It yields the first error (a
+/// read timeout, a closed connection) and then ends.
+pub struct Messages<'a> {
+    client: &'a mut Client,
+    failed: bool,
+}
+
+impl<'a> Messages<'a> {
+    pub(super) fn new(client: &'a mut Client) -> Self {
+        Self { client, failed: false }
+    }
+}
+
+impl Iterator for Messages<'_> {
+    type Item = Result<Published>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let next = self.client.next_message();
+        self.failed = next.is_err();
+        Some(next)
+    }
+}
@@ -1,24 +1,94 @@
+mod async_client;
+mod messages;
+mod pipeline;
+mod pool;
+
+pub use async_client::{AsyncClient, AsyncClientConfig};
+pub use messages::Messages;
+pub use pipeline::Pipeline;
+pub use pool::{ClientPool, PoolConfig, PoolMetrics, PooledClient};
+
 use crate::error::{Result, ServerError};
-use crate::protocol::message::{Message, OpCode};
-use std::net::TcpStream;
+use crate::protocol::codec::MessageCodec;
+use crate::protocol::message::{ErrorCode, Message, OpCode, DEFAULT_MAX_FRAME_SIZE};
+use crate::protocol::KeyResult;
+use crate::pubsub::{Published, WatchTarget};
+use crate::storage::{KeyEvent, KeyTtl, ScanCursor, ScanFilter, ScanPage, StoreStats, TxOp, TxOutcome, Versioned, WriteCondition};
+use crate::utils::Stream;
+use bytes::BytesMut;
+use log::warn;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{recv, send, MsgFlags};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read};
+use std::os::unix::io::AsRawFd;
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio_util::codec::Decoder;
 use serde::Serialize;
 
+// bytes asked of the socket per read
+const READ_CHUNK: usize = 64 * 1024;
+
 pub struct Client {
-    stream: TcpStream,
+    stream: Stream,
     request_id: AtomicU32,
+    codec: MessageCodec,
+    // received but not yet decoded
+    read_buf: BytesMut,
+    // push frames that arrived while waiting for a response
+    pushes: VecDeque<Message>,
+    // an exchange failed part way, so the stream is out of step
+    broken: bool,
 }
 
 impl Client {
+    /// Connects to `host:port`, or to a server's Unix domain socket with
+    /// `unix:/path/to.sock`.
     pub fn connect(addr: &str) -> Result<Self> {
-        let stream = TcpStream::connect(addr)?;
-        stream.set_nodelay(true)?;
+        let stream = Stream::connect(addr)?;
         Ok(Self {
             stream,
             request_id: AtomicU32::new(1),
+            codec: MessageCodec::with_max_frame_size(DEFAULT_MAX_FRAME_SIZE),
+            read_buf: BytesMut::new(),
+            pushes: VecDeque::new(),
+            broken: false,
         })
     }
 
+    /// Limits the payload size accepted in responses. A response over the limit
+    /// fails with `ServerError::FrameTooLarge` and leaves the connection unusable.
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.codec = MessageCodec::with_max_frame_size(max_frame_size);
+    }
+
+    /// Whether a request failed at the connection level (I/O error, oversized
+    /// frame, server going away), leaving the connection unusable.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Sets the read and write timeout of the underlying socket. `None` blocks
+    /// indefinitely.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.set_write_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Starts a batch of requests that are written back to back and answered
+    /// in a single round trip.
+    ///
+    /// The tokio server may apply the requests of one batch in any order, even
+    /// requests for the same key, unless its `SERVER_MAX_IN_FLIGHT` is 1; the
+    /// raw servers apply them in order. Send a request that depends on another
+    /// one, like a RETRIEVE of a key the batch stores, in a later batch.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline::new(self)
+    }
+
     pub fn ping(&mut self) -> Result<String> {
         let request_id = self.next_request_id();
         let message = Message::new_request(request_id, OpCode::Ping, Vec::new());
@@ -32,9 +102,7 @@ impl Client {
         let message = Message::new_request(request_id, OpCode::Store, payload);
         let response = self.send_and_receive(message)?;
         if response.is_error() {
-            Err(ServerError::Client(
-                String::from_utf8_lossy(&response.payload).to_string()
-            ))
+            Err(ServerError::Client(response.error_message()))
         } else {
             Ok(())
         }
@@ -46,12 +114,10 @@ impl Client {
         let message = Message::new_request(request_id, OpCode::Retrieve, payload);
         let response = self.send_and_receive(message)?;
         if response.is_error() {
-            if String::from_utf8_lossy(&response.payload).contains("Key not found") {
+            if response.error_code() == Some(ErrorCode::KeyNotFound) {
                 Ok(None)
             } else {
-                Err(ServerError::Client(
-                    String::from_utf8_lossy(&response.payload).to_string()
-                ))
+                Err(ServerError::Client(response.error_message()))
             }
         } else {
             Ok(Some(response.payload))
@@ -64,31 +130,402 @@ impl Client {
         let message = Message::new_request(request_id, OpCode::Delete, payload);
         let response = self.send_and_receive(message)?;
         if response.is_error() {
-            Err(ServerError::Client(
-                String::from_utf8_lossy(&response.payload).to_string()
-            ))
+            Err(ServerError::Client(response.error_message()))
         } else {
             Ok(())
         }
     }
 
+    /// Like [`Client::retrieve`], but also returns the version the value was
+    /// written at, for a later [`Client::compare_and_swap`].
+    pub fn retrieve_versioned(&mut self, key: &str) -> Result<Option<Versioned>> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&key).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let message = Message::new_request(request_id, OpCode::RetrieveVersion, payload);
+        self.send_and_decode(message)
+    }
+
+    /// Stores `value` only if `condition` holds when the server applies it.
+    /// Returns the new version, or `None` if the condition did not hold.
+    pub fn store_if<T: Serialize>(&mut self, key: &str, value: T, condition: WriteCondition) -> Result<Option<u64>> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&(key, value, condition))
+            .map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let message = Message::new_request(request_id, OpCode::CompareAndSwap, payload);
+        self.send_and_decode(message)
+    }
+
+    /// Replaces the value of `key` only if it is still at `version`.
+    pub fn compare_and_swap<T: Serialize>(&mut self, key: &str, value: T, version: u64) -> Result<Option<u64>> {
+        self.store_if(key, value, WriteCondition::IfVersion(version))
+    }
+
+    pub fn store_if_absent<T: Serialize>(&mut self, key: &str, value: T) -> Result<Option<u64>> {
+        self.store_if(key, value, WriteCondition::IfAbsent)
+    }
+
+    pub fn store_if_present<T: Serialize>(&mut self, key: &str, value: T) -> Result<Option<u64>> {
+        self.store_if(key, value, WriteCondition::IfPresent)
+    }
+
+    /// Adds one to the counter at `key` and returns the new value.
+    pub fn incr(&mut self, key: &str) -> Result<i64> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&key).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        self.send_counter(Message::new_request(request_id, OpCode::Incr, payload))
+    }
+
+    pub fn decr(&mut self, key: &str) -> Result<i64> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&key).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        self.send_counter(Message::new_request(request_id, OpCode::Decr, payload))
+    }
+
+    /// Adds `delta` to the counter at `key`, creating it at zero first if it
+    /// is missing. Fails with `ServerError::NotAnInteger` if the stored value
+    /// is not a decimal `i64` or the result would overflow.
+    pub fn incr_by(&mut self, key: &str, delta: i64) -> Result<i64> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&(key, delta)).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        self.send_counter(Message::new_request(request_id, OpCode::IncrBy, payload))
+    }
+
+    /// Applies `ops` atomically: either every check passes and every write is
+    /// applied, or the outcome says which check failed and nothing changed.
+    pub fn transaction(&mut self, ops: &[TxOp]) -> Result<TxOutcome> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&ops).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let message = Message::new_request(request_id, OpCode::Transaction, payload);
+        self.send_and_decode(message)
+    }
+
+    /// Fetches several keys in one round trip. Results are in `keys` order.
+    pub fn retrieve_many(&mut self, keys: &[&str]) -> Result<Vec<KeyResult>> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&keys).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let message = Message::new_request(request_id, OpCode::MGet, payload);
+        self.send_and_decode(message)
+    }
+
+    /// Stores several entries in one round trip. Each entry succeeds or fails
+    /// on its own; results are in `entries` order.
+    pub fn store_many<T: Serialize>(&mut self, entries: &[(&str, T)]) -> Result<Vec<KeyResult>> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&entries).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let message = Message::new_request(request_id, OpCode::MSet, payload);
+        self.send_and_decode(message)
+    }
+
+    /// Deletes several keys in one round trip. Results are in `keys` order.
+    pub fn delete_many(&mut self, keys: &[&str]) -> Result<Vec<KeyResult>> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&keys).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let message = Message::new_request(request_id, OpCode::MDelete, payload);
+        self.send_and_decode(message)
+    }
+
+    /// Returns every key in one response; prefer [`Client::scan`] for large stores.
     pub fn list(&mut self) -> Result<Vec<String>> {
         let request_id = self.next_request_id();
         let message = Message::new_request(request_id, OpCode::List, Vec::new());
         let response = self.send_and_receive(message)?;
         if response.is_error() {
-            Err(ServerError::Client(
-                String::from_utf8_lossy(&response.payload).to_string()
-            ))
+            Err(ServerError::Client(response.error_message()))
         } else {
             bincode::deserialize(&response.payload)
                 .map_err(|e| ServerError::Serialization(e.to_string()))
         }
     }
 
+    pub fn store_with_ttl<T: Serialize>(&mut self, key: &str, value: T, ttl: Duration) -> Result<()> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&(key, value, ttl.as_millis() as u64))
+            .map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let message = Message::new_request(request_id, OpCode::StoreEx, payload);
+        let response = self.send_and_receive(message)?;
+        if response.is_error() {
+            Err(ServerError::Client(response.error_message()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sets a TTL on an existing key. Returns `false` if the key does not exist.
+    pub fn expire(&mut self, key: &str, ttl: Duration) -> Result<bool> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&(key, ttl.as_millis() as u64))
+            .map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let message = Message::new_request(request_id, OpCode::Expire, payload);
+        self.send_and_decode(message)
+    }
+
+    pub fn ttl(&mut self, key: &str) -> Result<KeyTtl> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&key).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let message = Message::new_request(request_id, OpCode::Ttl, payload);
+        self.send_and_decode(message).map(KeyTtl::from_millis)
+    }
+
+    /// Removes the TTL from a key. Returns `false` if the key does not exist or
+    /// had no TTL.
+    pub fn persist(&mut self, key: &str) -> Result<bool> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&key).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let message = Message::new_request(request_id, OpCode::Persist, payload);
+        self.send_and_decode(message)
+    }
+
+    pub fn stats(&mut self) -> Result<StoreStats> {
+        let request_id = self.next_request_id();
+        let message = Message::new_request(request_id, OpCode::Stats, Vec::new());
+        self.send_and_decode(message)
+    }
+
+    /// Fetches the page of keys after `cursor`. Start from `ScanCursor::start()`
+    /// and keep passing back the returned cursor until it is done; `count` is
+    /// a hint for how many keys the server examines per page.
+    pub fn scan(&mut self, cursor: &ScanCursor, count: u32, filter: Option<&ScanFilter>) -> Result<ScanPage> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&(cursor, count, filter)).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let message = Message::new_request(request_id, OpCode::Scan, payload);
+        self.send_and_decode(message)
+    }
+
+    /// Subscribes to `channels` and returns how many channels and patterns
+    /// this connection is subscribed to now. Read the messages with
+    /// [`Client::next_message`] or [`Client::messages`].
+    pub fn subscribe(&mut self, channels: &[&str]) -> Result<u32> {
+        self.send_subscription(OpCode::Subscribe, channels)
+    }
+
+    /// Subscribes to every channel matching one of the glob `patterns`.
+    pub fn psubscribe(&mut self, patterns: &[&str]) -> Result<u32> {
+        self.send_subscription(OpCode::PSubscribe, patterns)
+    }
+
+    /// Drops the named channel and pattern subscriptions, or all of them if
+    /// `names` is empty.
+    pub fn unsubscribe(&mut self, names: &[&str]) -> Result<u32> {
+        self.send_subscription(OpCode::Unsubscribe, names)
+    }
+
+    /// Publishes `payload` on `channel` and returns how many subscriptions it
+    /// was delivered to.
+    pub fn publish(&mut self, channel: &str, payload: &[u8]) -> Result<u64> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&(channel, payload)).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        let message = Message::new_request(request_id, OpCode::Publish, payload);
+        self.send_and_decode(message)
+    }
+
+    /// Blocks until the next message published to one of this connection's
+    /// subscriptions arrives, or the read timeout expires.
+    pub fn next_message(&mut self) -> Result<Published> {
+        let push = self.next_push(OpCode::Publish)?;
+        bincode::deserialize(&push.payload).map_err(|e| ServerError::Serialization(e.to_string()))
+    }
+
+    /// Iterates over published messages; see [`Client::next_message`].
+    pub fn messages(&mut self) -> Messages<'_> {
+        Messages::new(self)
+    }
+
+    /// Asks for a push on every change to the keys `targets` cover and
+    /// returns how many targets this connection watches now. Read the
+    /// changes with [`Client::next_event`].
+    pub fn watch(&mut self, targets: &[WatchTarget]) -> Result<u32> {
+        self.send_watch(OpCode::Watch, targets)
+    }
+
+    pub fn watch_key(&mut self, key: &str) -> Result<u32> {
+        self.watch(&[WatchTarget::Key(key.to_string())])
+    }
+
+    pub fn watch_prefix(&mut self, prefix: &str) -> Result<u32> {
+        self.watch(&[WatchTarget::Prefix(prefix.to_string())])
+    }
+
+    /// Drops the given watches, or all of them if `targets` is empty.
+    pub fn unwatch(&mut self, targets: &[WatchTarget]) -> Result<u32> {
+        self.send_watch(OpCode::Unwatch, targets)
+    }
+
+    /// Blocks until the next change to a watched key arrives, or the read
+    /// timeout expires.
+    pub fn next_event(&mut self) -> Result<KeyEvent> {
+        let push = self.next_push(OpCode::Watch)?;
+        bincode::deserialize(&push.payload).map_err(|e| ServerError::Serialization(e.to_string()))
+    }
+
+    /// Asks the server to take a snapshot in the background.
+    pub fn snapshot(&mut self) -> Result<()> {
+        let request_id = self.next_request_id();
+        let message = Message::new_request(request_id, OpCode::Snapshot, Vec::new());
+        let response = self.send_and_receive(message)?;
+        if response.is_error() {
+            Err(ServerError::Client(response.error_message()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn send_and_decode<T: serde::de::DeserializeOwned>(&mut self, message: Message) -> Result<T> {
+        decode_response(self.send_and_receive(message)?)
+    }
+
+    fn send_subscription(&mut self, op_code: OpCode, names: &[&str]) -> Result<u32> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&names).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        self.send_and_decode(Message::new_request(request_id, op_code, payload))
+    }
+
+    fn send_watch(&mut self, op_code: OpCode, targets: &[WatchTarget]) -> Result<u32> {
+        let request_id = self.next_request_id();
+        let payload = bincode::serialize(&targets).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        self.send_and_decode(Message::new_request(request_id, op_code, payload))
+    }
+
+    fn next_push(&mut self, op_code: OpCode) -> Result<Message> {
+        let result = self.receive_push(op_code);
+        self.broken |= result.is_err();
+        result
+    }
+
+    fn receive_push(&mut self, op_code: OpCode) -> Result<Message> {
+        if let Some(index) = self.pushes.iter().position(|push| push.op_code == op_code) {
+            return Ok(self.pushes.remove(index).unwrap());
+        }
+
+        loop {
+            let message = self.read_message()?;
+            if message.is_going_away() {
+                return Err(ServerError::Connection("Server is shutting down".into()));
+            }
+            if !message.is_push() {
+                warn!("Discarding unexpected response for request {}", message.request_id);
+            } else if message.op_code == op_code {
+                return Ok(message);
+            } else {
+                self.pushes.push_back(message);
+            }
+        }
+    }
+
+    fn send_counter(&mut self, message: Message) -> Result<i64> {
+        let response = self.send_and_receive(message)?;
+        if response.error_code() == Some(ErrorCode::NotAnInteger) {
+            return Err(ServerError::NotAnInteger);
+        }
+        decode_response(response)
+    }
+
     fn send_and_receive(&mut self, message: Message) -> Result<Message> {
-        message.write_to(&mut self.stream)?;
-        Message::read_from(&mut self.stream)
+        self.send_all(vec![message])?
+            .pop()
+            .ok_or_else(|| ServerError::Client("Missing response".into()))
+    }
+
+    /// Writes every request without waiting for responses, then pairs the
+    /// responses (which may arrive in any order) with their requests by
+    /// `request_id`. Responses are returned in request order.
+    fn send_all(&mut self, requests: Vec<Message>) -> Result<Vec<Message>> {
+        let result = self.exchange(requests);
+        self.broken |= result.is_err();
+        result
+    }
+
+    fn exchange(&mut self, requests: Vec<Message>) -> Result<Vec<Message>> {
+        let mut buf = Vec::new();
+        for request in &requests {
+            request.write_to(&mut buf)?;
+        }
+        self.write_draining(&buf)?;
+
+        let mut pending: HashMap<u32, Option<Message>> =
+            requests.iter().map(|request| (request.request_id, None)).collect();
+        let mut outstanding = pending.len();
+
+        while outstanding > 0 {
+            let response = self.read_message()?;
+            if response.is_push() {
+                self.pushes.push_back(response);
+                continue;
+            }
+            if response.is_going_away() {
+                return Err(ServerError::Connection("Server is shutting down".into()));
+            }
+            match pending.get_mut(&response.request_id) {
+                Some(slot @ None) => {
+                    *slot = Some(response);
+                    outstanding -= 1;
+                }
+                _ => warn!("Discarding unexpected response for request {}", response.request_id),
+            }
+        }
+
+        Ok(requests
+            .iter()
+            .filter_map(|request| pending.get_mut(&request.request_id).and_then(Option::take))
+            .collect())
+    }
+
+    /// Writes `out` while reading whatever arrives meanwhile into `read_buf`.
+    /// A server stops reading once too many responses wait to be sent, so a
+    /// batch written without reading could block both ends for good.
+    fn write_draining(&mut self, out: &[u8]) -> Result<()> {
+        let timeout = match self.stream.read_timeout()? {
+            Some(timeout) => timeout.as_millis().min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        let fd = self.stream.as_raw_fd();
+        let mut written = 0;
+
+        while written < out.len() {
+            let mut fds = [PollFd::new(&self.stream, PollFlags::POLLIN | PollFlags::POLLOUT)];
+            match poll(&mut fds, timeout) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::TimedOut, "Timed out writing requests").into()),
+                Ok(_) | Err(Errno::EINTR) => {}
+                Err(e) => return Err(e.into()),
+            }
+            let ready = fds[0].revents().unwrap_or(PollFlags::empty());
+
+            if ready.intersects(PollFlags::POLLIN | PollFlags::POLLHUP | PollFlags::POLLERR) {
+                let start = self.read_buf.len();
+                self.read_buf.resize(start + READ_CHUNK, 0);
+                let received = recv(fd, &mut self.read_buf[start..], MsgFlags::MSG_DONTWAIT);
+                self.read_buf.truncate(start + received.unwrap_or(0));
+                match received {
+                    // the server hung up; whatever it sent last is in `read_buf`
+                    Ok(0) => return Ok(()),
+                    Ok(_) | Err(Errno::EAGAIN) | Err(Errno::EINTR) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            if ready.contains(PollFlags::POLLOUT) {
+                match send(fd, &out[written..], MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL) {
+                    Ok(n) => written += n,
+                    Err(Errno::EAGAIN) | Err(Errno::EINTR) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes the next frame from `read_buf`, reading more as needed.
+    fn read_message(&mut self) -> Result<Message> {
+        loop {
+            if let Some(message) = self.codec.decode(&mut self.read_buf)? {
+                return Ok(message);
+            }
+            let start = self.read_buf.len();
+            self.read_buf.resize(start + READ_CHUNK, 0);
+            let read = self.stream.read(&mut self.read_buf[start..]);
+            self.read_buf.truncate(start + *read.as_ref().unwrap_or(&0));
+            if read? == 0 {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "Connection closed by server").into());
+            }
+        }
     }
 
     fn next_request_id(&self) -> u32 {
@@ -101,3 +538,12 @@ impl Drop for Client {
         let _ = self.stream.shutdown(std::net::Shutdown::Both);
     }
 }
+
+fn decode_response<T: serde::de::DeserializeOwned>(response: Message) -> Result<T> {
+    if response.is_error() {
+        Err(ServerError::Client(response.error_message()))
+    } else {
+        bincode::deserialize(&response.payload)
+            .map_err(|e| ServerError::Serialization(e.to_string()))
+    }
+}
@@ -0,0 +1,67 @@
+use super::Client;
+use crate::error::{Result, ServerError};
+use crate::protocol::message::{Message, OpCode};
+use serde::Serialize;
+
+/// A batch of requests sent over a [`Client`] without waiting for each
+/// response in turn. Build it with [`Client::pipeline`], queue requests and
+/// call [`Pipeline::execute`] to get the responses back in queue order.
+pub struct Pipeline<'a> {
+    client: &'a mut Client,
+    requests: Vec<Message>,
+}
+
+impl<'a> Pipeline<'a> {
+    pub(super) fn new(client: &'a mut Client) -> Self {
+        Self {
+            client,
+            requests: Vec::new(),
+        }
+    }
+
+    pub fn ping(&mut self) -> &mut Self {
+        self.push(OpCode::Ping, Vec::new());
+        self
+    }
+
+    pub fn store<T: Serialize>(&mut self, key: &str, value: T) -> Result<&mut Self> {
+        let payload = bincode::serialize(&(key, value)).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        self.push(OpCode::Store, payload);
+        Ok(self)
+    }
+
+    pub fn retrieve(&mut self, key: &str) -> Result<&mut Self> {
+        let payload = bincode::serialize(&key).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        self.push(OpCode::Retrieve, payload);
+        Ok(self)
+    }
+
+    pub fn delete(&mut self, key: &str) -> Result<&mut Self> {
+        let payload = bincode::serialize(&key).map_err(|e| ServerError::Serialization(e.to_string()))?;
+        self.push(OpCode::Delete, payload);
+        Ok(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Sends every queued request and returns one response per request, in the
+    /// order they were queued. Error responses are returned as messages rather
+    /// than failing the whole batch.
+    pub fn execute(self) -> Result<Vec<Message>> {
+        if self.requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.client.send_all(self.requests)
+    }
+
+    fn push(&mut self, op_code: OpCode, payload: Vec<u8>) {
+        let request_id = self.client.next_request_id();
+        self.requests.push(Message::new_request(request_id, op_code, payload));
+    }
+}
@@ -0,0 +1,315 @@
+use super::Client;
+use crate::error::{Result, ServerError};
+use log::{debug, warn};
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Maximum number of open connections.
+    pub max_size: usize,
+    /// How long `get` waits for a free or fresh connection before failing.
+    pub checkout_timeout: Duration,
+    /// Idle connections not checked for this long are pinged on checkout.
+    /// `Duration::ZERO` pings on every checkout.
+    pub validation_interval: Duration,
+    /// Read and write timeout applied to every pooled connection.
+    pub io_timeout: Option<Duration>,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 8,
+            checkout_timeout: Duration::from_secs(5),
+            validation_interval: Duration::from_secs(30),
+            io_timeout: Some(Duration::from_secs(5)),
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+struct IdleClient {
+    client: Client,
+    last_checked: Instant,
+}
+
+struct PoolState {
+    idle: VecDeque<IdleClient>,
+    // idle connections, checked out connections and connects in progress
+    open: usize,
+    backoff: Duration,
+    next_attempt: Option<Instant>,
+}
+
+struct PoolInner {
+    addr: String,
+    config: PoolConfig,
+    state: Mutex<PoolState>,
+    available: Condvar,
+    total_connects: AtomicU64,
+    failed_connects: AtomicU64,
+    failed_validations: AtomicU64,
+}
+
+/// Thread-safe pool of blocking [`Client`] connections to one server.
+///
+/// Connections are opened lazily up to `max_size`. A failed connect delays the
+/// next attempt with exponential backoff, and connections that fail a `PING`
+/// on checkout are replaced transparently.
+#[derive(Clone)]
+pub struct ClientPool {
+    inner: Arc<PoolInner>,
+}
+
+impl ClientPool {
+    pub fn new(addr: &str, config: PoolConfig) -> Self {
+        let backoff = config.initial_backoff;
+        Self {
+            inner: Arc::new(PoolInner {
+                addr: addr.to_string(),
+                config,
+                state: Mutex::new(PoolState {
+                    idle: VecDeque::new(),
+                    open: 0,
+                    backoff,
+                    next_attempt: None,
+                }),
+                available: Condvar::new(),
+                total_connects: AtomicU64::new(0),
+                failed_connects: AtomicU64::new(0),
+                failed_validations: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Checks out a connection, waiting up to `checkout_timeout` for one to
+    /// become available.
+    pub fn get(&self) -> Result<PooledClient> {
+        let inner = &self.inner;
+        let deadline = Instant::now() + inner.config.checkout_timeout;
+        let mut state = inner.state.lock().unwrap();
+
+        loop {
+            if let Some(idle) = state.idle.pop_front() {
+                drop(state);
+                if let Some(idle) = self.validate(idle) {
+                    return Ok(PooledClient::new(idle, inner.clone()));
+                }
+                state = inner.state.lock().unwrap();
+                state.open -= 1;
+                continue;
+            }
+
+            let now = Instant::now();
+            if state.open < inner.config.max_size {
+                match state.next_attempt {
+                    Some(at) if at > now => {
+                        if at >= deadline {
+                            return Err(ServerError::Connection(format!(
+                                "Unable to connect to {}: backing off after failed connects",
+                                inner.addr
+                            )));
+                        }
+                        state = inner.available.wait_timeout(state, at - now).unwrap().0;
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                state.open += 1;
+                drop(state);
+                let result = self.connect();
+                state = inner.state.lock().unwrap();
+
+                match result {
+                    Ok(client) => {
+                        state.backoff = inner.config.initial_backoff;
+                        state.next_attempt = None;
+                        let idle = IdleClient {
+                            client,
+                            last_checked: Instant::now(),
+                        };
+                        return Ok(PooledClient::new(idle, inner.clone()));
+                    }
+                    Err(e) => {
+                        warn!("Failed to connect to {}: {}", inner.addr, e);
+                        state.open -= 1;
+                        state.next_attempt = Some(Instant::now() + state.backoff);
+                        state.backoff = (state.backoff * 2).min(inner.config.max_backoff);
+                        inner.available.notify_one();
+                        if Instant::now() >= deadline {
+                            return Err(e);
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            if now >= deadline {
+                return Err(ServerError::Connection(format!(
+                    "Timed out waiting for a connection to {}",
+                    inner.addr
+                )));
+            }
+            state = inner.available.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Runs `f` on a pooled connection, which goes back to the pool unless a
+    /// request broke it.
+    pub fn with_client<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Client) -> Result<T>,
+    {
+        let mut client = self.get()?;
+        f(&mut client)
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        let state = self.inner.state.lock().unwrap();
+        PoolMetrics {
+            idle: state.idle.len(),
+            in_use: state.open - state.idle.len(),
+            max_size: self.inner.config.max_size,
+            total_connects: self.inner.total_connects.load(Ordering::Relaxed),
+            failed_connects: self.inner.failed_connects.load(Ordering::Relaxed),
+            failed_validations: self.inner.failed_validations.load(Ordering::Relaxed),
+        }
+    }
+
+    fn connect(&self) -> Result<Client> {
+        let inner = &self.inner;
+        let result = Client::connect(&inner.addr).and_then(|client| {
+            client.set_timeout(inner.config.io_timeout)?;
+            Ok(client)
+        });
+
+        match result {
+            Ok(client) => {
+                inner.total_connects.fetch_add(1, Ordering::Relaxed);
+                debug!("Opened pooled connection to {}", inner.addr);
+                Ok(client)
+            }
+            Err(e) => {
+                inner.failed_connects.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    fn validate(&self, mut idle: IdleClient) -> Option<IdleClient> {
+        if idle.last_checked.elapsed() < self.inner.config.validation_interval {
+            return Some(idle);
+        }
+
+        match idle.client.ping() {
+            Ok(_) => {
+                idle.last_checked = Instant::now();
+                Some(idle)
+            }
+            Err(e) => {
+                debug!("Dropping pooled connection to {}: {}", self.inner.addr, e);
+                self.inner.failed_validations.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+}
+
+/// A connection checked out of a [`ClientPool`]. It goes back to the pool when
+/// dropped, unless [`PooledClient::discard`] was called or a request broke it
+/// (see [`Client::is_broken`]).
+pub struct PooledClient {
+    client: Option<IdleClient>,
+    pool: Arc<PoolInner>,
+}
+
+impl PooledClient {
+    fn new(client: IdleClient, pool: Arc<PoolInner>) -> Self {
+        Self {
+            client: Some(client),
+            pool,
+        }
+    }
+
+    /// Closes the connection instead of returning it to the pool.
+    pub fn discard(mut self) {
+        self.client.take();
+        self.release();
+    }
+
+    fn release(&self) {
+        let mut state = self.pool.state.lock().unwrap();
+        state.open -= 1;
+        self.pool.available.notify_one();
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client.as_ref().unwrap().client
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        &mut self.client.as_mut().unwrap().client
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        match self.client.take() {
+            Some(idle) if idle.client.is_broken() => {
+                debug!("Dropping broken pooled connection to {}", self.pool.addr);
+                self.release();
+            }
+            Some(idle) => {
+                let mut state = self.pool.state.lock().unwrap();
+                state.idle.push_back(idle);
+                self.pool.available.notify_one();
+            }
+            None => {}
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PoolMetrics {
+    pub idle: usize,
+    pub in_use: usize,
+    pub max_size: usize,
+    pub total_connects: u64,
+    pub failed_connects: u64,
+    pub failed_validations: u64,
+}
+
+impl std::fmt::Display for PoolMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Pool Metrics:\n\
+             Idle: {}\n\
+             In Use: {}\n\
+             Max Size: {}\n\
+             Total Connects: {}\n\
+             Failed Connects: {}\n\
+             Failed Validations: {}\n",
+            self.idle,
+            self.in_use,
+            self.max_size,
+            self.total_connects,
+            self.failed_connects,
+            self.failed_validations
+        )
+    }
+}
@@ -1,5 +1,10 @@
+use crate::pubsub::SlowSubscriberPolicy;
+use crate::server::ServerMode;
+use crate::storage::{EvictionPolicy, FsyncPolicy};
+use crate::utils::ListenAddr;
 use serde::Deserialize;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -14,11 +19,40 @@ pub enum ConfigError {
 pub struct ServerConfig {
     pub host: IpAddr,
     pub port: u16,
+    /// With an IPv6 `host`, refuse IPv4 clients instead of serving both.
+    pub ipv6_only: bool,
+    /// Listen on this Unix domain socket instead of `host` and `port`.
+    pub unix_socket: Option<PathBuf>,
+    /// Permission bits of the `unix_socket` file.
+    pub unix_socket_mode: u32,
     pub backlog: i32,
     pub max_connections: usize,
     pub read_timeout_ms: u64,
     pub write_timeout_ms: u64,
     pub buffer_size: usize,
+    pub max_storage_size: u64,
+    pub echo_mode: bool,
+    pub max_frame_size: usize,
+    pub max_in_flight: usize,
+    /// Implementation `ServerBuilder` builds.
+    pub mode: ServerMode,
+    /// Event loop threads of the raw server; `0` means one per CPU.
+    pub workers: usize,
+    pub drain_timeout_ms: u64,
+    /// `0` disables the background sweep; expired keys are then only
+    /// reclaimed when accessed.
+    pub expiry_sweep_interval_ms: u64,
+    pub eviction_policy: EvictionPolicy,
+    pub aof_enabled: bool,
+    pub aof_path: PathBuf,
+    pub aof_fsync: FsyncPolicy,
+    pub snapshot_enabled: bool,
+    pub snapshot_dir: PathBuf,
+    pub snapshot_interval_ms: u64,
+    pub snapshot_write_threshold: u64,
+    pub snapshot_retain: usize,
+    pub push_buffer: usize,
+    pub slow_subscriber_policy: SlowSubscriberPolicy,
 }
 
 impl Default for ServerConfig {
@@ -26,11 +60,33 @@ impl Default for ServerConfig {
         Self {
             host: "127.0.0.1".parse().unwrap(),
             port: 8080,
+            ipv6_only: false,
+            unix_socket: None,
+            unix_socket_mode: 0o660,
             backlog: 128,
             max_connections: 1000,
             read_timeout_ms: 5000,
             write_timeout_ms: 5000,
             buffer_size: 4096,
+            max_storage_size: 1024 * 1024 * 1024,
+            echo_mode: false,
+            max_frame_size: 16 * 1024 * 1024,
+            max_in_flight: 64,
+            mode: ServerMode::Tokio,
+            workers: 0,
+            drain_timeout_ms: 10000,
+            expiry_sweep_interval_ms: 1000,
+            eviction_policy: EvictionPolicy::NoEviction,
+            aof_enabled: false,
+            aof_path: PathBuf::from("appendonly.aof"),
+            aof_fsync: FsyncPolicy::EverySec,
+            snapshot_enabled: false,
+            snapshot_dir: PathBuf::from("snapshots"),
+            snapshot_interval_ms: 300000,
+            snapshot_write_threshold: 10000,
+            snapshot_retain: 3,
+            push_buffer: 1024,
+            slow_subscriber_policy: SlowSubscriberPolicy::Drop,
         }
     }
 }
@@ -39,7 +95,7 @@ impl ServerConfig {
     pub fn new() -> Result<Self, ConfigError> {
         dotenv::dotenv().ok();
 
-        let config = Self {
+        let mut config = Self {
             host: std::env::var("SERVER_HOST")
                 .unwrap_or_else(|_| "127.0.0.1".to_string())
                 .parse()
@@ -48,6 +104,16 @@ impl ServerConfig {
                 .unwrap_or_else(|_| "8080".to_string())
                 .parse()
                 .map_err(|e| ConfigError::ConfigError(format!("Invalid port: {}", e)))?,
+            ipv6_only: std::env::var("SERVER_IPV6_ONLY")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid IPv6-only flag: {}", e)))?,
+            unix_socket: None,
+            unix_socket_mode: u32::from_str_radix(
+                &std::env::var("SERVER_UNIX_SOCKET_MODE").unwrap_or_else(|_| "660".to_string()),
+                8,
+            )
+            .map_err(|e| ConfigError::ConfigError(format!("Invalid Unix socket mode: {}", e)))?,
             backlog: std::env::var("SERVER_BACKLOG")
                 .unwrap_or_else(|_| "128".to_string())
                 .parse()
@@ -68,8 +134,109 @@ impl ServerConfig {
                 .unwrap_or_else(|_| "4096".to_string())
                 .parse()
                 .map_err(|e| ConfigError::ConfigError(format!("Invalid buffer size: {}", e)))?,
+            max_storage_size: std::env::var("SERVER_MAX_STORAGE_SIZE")
+                .unwrap_or_else(|_| "1073741824".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid max storage size: {}", e)))?,
+            echo_mode: std::env::var("SERVER_ECHO_MODE")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid echo mode: {}", e)))?,
+            max_frame_size: std::env::var("SERVER_MAX_FRAME_SIZE")
+                .unwrap_or_else(|_| "16777216".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid max frame size: {}", e)))?,
+            max_in_flight: std::env::var("SERVER_MAX_IN_FLIGHT")
+                .unwrap_or_else(|_| "64".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid max in-flight requests: {}", e)))?,
+            mode: std::env::var("SERVER_MODE")
+                .unwrap_or_else(|_| "tokio".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid server mode: {}", e)))?,
+            workers: std::env::var("SERVER_WORKERS")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid worker count: {}", e)))?,
+            drain_timeout_ms: std::env::var("SERVER_DRAIN_TIMEOUT_MS")
+                .unwrap_or_else(|_| "10000".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid drain timeout: {}", e)))?,
+            expiry_sweep_interval_ms: std::env::var("SERVER_EXPIRY_SWEEP_INTERVAL_MS")
+                .unwrap_or_else(|_| "1000".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid expiry sweep interval: {}", e)))?,
+            eviction_policy: std::env::var("SERVER_EVICTION_POLICY")
+                .unwrap_or_else(|_| "noeviction".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid eviction policy: {}", e)))?,
+            aof_enabled: std::env::var("SERVER_AOF_ENABLED")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid AOF enabled flag: {}", e)))?,
+            aof_path: std::env::var("SERVER_AOF_PATH")
+                .unwrap_or_else(|_| "appendonly.aof".to_string())
+                .into(),
+            aof_fsync: std::env::var("SERVER_AOF_FSYNC")
+                .unwrap_or_else(|_| "everysec".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid AOF fsync policy: {}", e)))?,
+            snapshot_enabled: std::env::var("SERVER_SNAPSHOT_ENABLED")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid snapshot enabled flag: {}", e)))?,
+            snapshot_dir: std::env::var("SERVER_SNAPSHOT_DIR")
+                .unwrap_or_else(|_| "snapshots".to_string())
+                .into(),
+            snapshot_interval_ms: std::env::var("SERVER_SNAPSHOT_INTERVAL_MS")
+                .unwrap_or_else(|_| "300000".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid snapshot interval: {}", e)))?,
+            snapshot_write_threshold: std::env::var("SERVER_SNAPSHOT_WRITE_THRESHOLD")
+                .unwrap_or_else(|_| "10000".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid snapshot write threshold: {}", e)))?,
+            snapshot_retain: std::env::var("SERVER_SNAPSHOT_RETAIN")
+                .unwrap_or_else(|_| "3".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid snapshot retain count: {}", e)))?,
+            push_buffer: std::env::var("SERVER_PUSH_BUFFER")
+                .unwrap_or_else(|_| "1024".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid push buffer: {}", e)))?,
+            slow_subscriber_policy: std::env::var("SERVER_SLOW_SUBSCRIBER_POLICY")
+                .unwrap_or_else(|_| "drop".to_string())
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid slow subscriber policy: {}", e)))?,
         };
 
+        // `host:port` or `unix:/path`, overriding SERVER_HOST and SERVER_PORT
+        if let Some(listen) = std::env::var("SERVER_LISTEN").ok().filter(|listen| !listen.is_empty()) {
+            let addr = listen
+                .parse()
+                .map_err(|e| ConfigError::ConfigError(format!("Invalid listen address: {}", e)))?;
+            config.set_listen_addr(addr);
+        }
+
         Ok(config)
     }
+
+    /// Where the server listens: `unix_socket` if set, otherwise `host` and `port`.
+    pub fn listen_addr(&self) -> ListenAddr {
+        match &self.unix_socket {
+            Some(path) => ListenAddr::Unix(path.clone()),
+            None => ListenAddr::Tcp(SocketAddr::new(self.host, self.port)),
+        }
+    }
+
+    pub fn set_listen_addr(&mut self, addr: ListenAddr) {
+        match addr {
+            ListenAddr::Tcp(addr) => {
+                self.host = addr.ip();
+                self.port = addr.port();
+                self.unix_socket = None;
+            }
+            ListenAddr::Unix(path) => self.unix_socket = Some(path),
+        }
+    }
 }
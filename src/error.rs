@@ -30,6 +30,23 @@ pub enum ServerError {
 
     #[error("Storage error: {0}")]
     Storage(String),
+
+    /// INCR/DECR/INCRBY hit a value that is not a decimal i64, or the result
+    /// would overflow.
+    #[error("Value is not an integer or out of range")]
+    NotAnInteger,
+
+    /// The kernel can't run `IoUringServer`: io_uring is missing, disabled,
+    /// or lacks an operation the server needs.
+    #[error("io_uring unavailable: {0}; use the epoll or tokio server instead")]
+    IoUringUnavailable(String),
+
+    #[error("Frame too large: payload of {payload_len} bytes exceeds limit of {max_frame_size} bytes")]
+    FrameTooLarge {
+        request_id: u32,
+        payload_len: u32,
+        max_frame_size: usize,
+    },
 }
 
 impl From<Box<bincode::ErrorKind>> for ServerError {
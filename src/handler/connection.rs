@@ -1,29 +1,35 @@
 use crate::error::Result;
+use crate::utils::{PeerAddr, Stream};
 use log::{debug, error};
 use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub struct ConnectionHandler {
-    stream: TcpStream,
-    peer_addr: SocketAddr,
+    stream: Stream,
+    peer_addr: PeerAddr,
     buffer_size: usize,
 }
 
 impl ConnectionHandler {
-    pub fn new(stream: TcpStream, peer_addr: SocketAddr, buffer_size: usize) -> Self {
+    /// Takes a `TcpStream`, a `UnixStream` or a [`Stream`] of either.
+    pub fn new(stream: impl Into<Stream>, peer_addr: impl Into<PeerAddr>, buffer_size: usize) -> Self {
         Self {
-            stream,
-            peer_addr,
+            stream: stream.into(),
+            peer_addr: peer_addr.into(),
             buffer_size,
         }
     }
 
     pub async fn handle(&mut self) -> Result<()> {
-        let mut buf = vec![0; self.buffer_size];
-
         // convert to async stream
-        let mut stream = tokio::net::TcpStream::from_std(self.stream.try_clone()?)?;
+        match self.stream.try_clone()? {
+            Stream::Tcp(stream) => self.echo(tokio::net::TcpStream::from_std(stream)?).await,
+            Stream::Unix(stream) => self.echo(tokio::net::UnixStream::from_std(stream)?).await,
+        }
+    }
+
+    async fn echo<T: AsyncRead + AsyncWrite + Unpin>(&self, mut stream: T) -> Result<()> {
+        let mut buf = vec![0; self.buffer_size];
 
         loop {
             let n = match stream.read(&mut buf).await {
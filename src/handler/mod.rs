@@ -1,2 +1,4 @@
 pub mod connection;
+pub mod proc_connection;
 pub use connection::ConnectionHandler;
+pub use proc_connection::{BlockingProtocolConnectionHandler, ProtocolConnectionHandler};
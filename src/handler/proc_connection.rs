@@ -1,72 +1,309 @@
-use crate::error::Result;
-use crate::protocol::message::Message;
+use crate::error::{Result, ServerError};
+use crate::protocol::codec::MessageCodec;
+use crate::protocol::message::{Message, OpCode};
 use crate::protocol::handler::ProtocolHandler;
-use crate::storage::KeyValueStore;
-use log::{debug, error};
-use std::net::{SocketAddr, TcpStream};
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-pub struct ProtocolConnectionHandler {
-    stream: TcpStream,
-    peer_addr: SocketAddr,
-    handler: ProtocolHandler,
+use crate::pubsub::Inbox;
+use crate::server::ShutdownHandle;
+use crate::storage::{KeyValueStore, StorageBackend};
+use crate::utils::PeerAddr;
+use futures::{SinkExt, StreamExt};
+use log::{debug, error, warn};
+use std::io::{BufReader, ErrorKind};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// Serves the protocol on a tokio stream: a `TcpStream` by default, or a
+/// `UnixStream` for Unix domain socket clients.
+pub struct ProtocolConnectionHandler<S: StorageBackend = KeyValueStore, T = TcpStream> {
+    stream: T,
+    peer_addr: PeerAddr,
+    handler: Arc<ProtocolHandler<S>>,
     buffer_size: usize,
+    max_frame_size: usize,
+    max_in_flight: usize,
+    shutdown: ShutdownHandle,
 }
 
-impl ProtocolConnectionHandler {
+impl<S: StorageBackend, T: AsyncRead + AsyncWrite + Unpin> ProtocolConnectionHandler<S, T> {
     pub fn new(
-        stream: TcpStream,
-        peer_addr: SocketAddr,
-        store: Arc<KeyValueStore>,
+        stream: T,
+        peer_addr: impl Into<PeerAddr>,
+        handler: Arc<ProtocolHandler<S>>,
         buffer_size: usize,
+        max_frame_size: usize,
+        max_in_flight: usize,
+        shutdown: ShutdownHandle,
     ) -> Self {
         Self {
             stream,
-            peer_addr,
-            handler: ProtocolHandler::new(store),
+            peer_addr: peer_addr.into(),
+            handler,
             buffer_size,
+            max_frame_size,
+            max_in_flight: max_in_flight.max(1),
+            shutdown,
         }
     }
 
+    /// Reads requests ahead of the responses and processes up to `max_in_flight`
+    /// of them concurrently. Responses are written as soon as they are ready, so
+    /// they may arrive out of order; clients match them up by `request_id`.
+    ///
+    /// Concurrent requests are applied in no particular order, even when they
+    /// touch the same key: a pipelined STORE followed by a RETRIEVE of that key
+    /// may read the old value. With a `max_in_flight` of 1 requests are applied
+    /// one at a time, in the order they were sent.
+    ///
+    /// On shutdown the handler stops reading, waits for in-flight requests to be
+    /// answered and then sends a going-away notice before closing.
+    ///
+    /// Push frames for the connection's subscriptions are written between
+    /// responses.
     pub async fn handle(&mut self) -> Result<()> {
-        let mut stream = tokio::net::TcpStream::from_std(self.stream.try_clone()?)?;
+        let peer_addr = self.peer_addr;
+        let broker = self.handler.broker().clone();
+        let (outbox, mut inbox) = broker.register();
+        let connection_id = outbox.id();
+        let (reader, writer) = tokio::io::split(&mut self.stream);
+        let codec = MessageCodec::with_max_frame_size(self.max_frame_size);
+        let mut frames = FramedRead::with_capacity(reader, codec, self.buffer_size);
+        let mut sink = FramedWrite::new(writer, codec);
 
-        loop {
-            match Message::read_from(&mut stream) {
-                Ok(message) => {
-                    debug!("Received message from {}: {:?}", self.peer_addr, message);
-
-                    match self.handler.handle_message(message) {
-                        Ok(response) => {
-                            debug!("Sending response to {}: {:?}", self.peer_addr, response);
-                            if let Err(e) = response.write_to(&mut stream) {
-                                error!("Error sending response to {}: {}", self.peer_addr, e);
-                                break;
-                            }
-                        }
+        let in_flight = Arc::new(Semaphore::new(self.max_in_flight));
+        // each response carries its in-flight permit until it has been written
+        let (tx, mut rx) = mpsc::unbounded_channel::<(Message, Option<OwnedSemaphorePermit>)>();
+
+        let handler = self.handler.clone();
+        let shutdown = self.shutdown.clone();
+        let max_in_flight = self.max_in_flight as u32;
+        let read_loop = async move {
+            loop {
+                let frame = tokio::select! {
+                    frame = frames.next() => frame,
+                    _ = shutdown.wait() => {
+                        // every permit is back once all in-flight responses are written
+                        let _drained = in_flight.acquire_many(max_in_flight).await;
+                        debug!("Sending going-away notice to {}", peer_addr);
+                        let _ = tx.send((Message::new_going_away(), None));
+                        return;
+                    }
+                };
+                let Some(frame) = frame else { break };
+
+                let message = match frame {
+                    Ok(message) => message,
+                    Err(e @ ServerError::FrameTooLarge { request_id, .. }) => {
+                        // the oversized payload is never read, so the stream cannot be resynchronised
+                        warn!("Closing connection {}: {}", peer_addr, e);
+                        let _ = tx.send((Message::new_error(request_id, e.to_string()), None));
+                        return;
+                    }
+                    Err(ServerError::Io(e))
+                        if matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::BrokenPipe) =>
+                    {
+                        debug!("Connection closed by peer: {}", peer_addr);
+                        return;
+                    }
+                    Err(e) => {
+                        error!("Error reading from {}: {}", peer_addr, e);
+                        return;
+                    }
+                };
+
+                debug!("Received message from {}: {:?}", peer_addr, message);
+                let permit = match in_flight.clone().acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => return,
+                };
+
+                let handler = handler.clone();
+                let outbox = outbox.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let request_id = message.request_id;
+                    let response = match handler.handle_connection_message(message, &outbox) {
+                        Ok(response) => response,
                         Err(e) => {
-                            error!("Error handling message from {}: {}", self.peer_addr, e);
-                            let error_message = Message::new_error(0, e.to_string());
-                            if let Err(e) = error_message.write_to(&mut stream) {
-                                error!("Error sending error response to {}: {}", self.peer_addr, e);
-                            }
-                            break;
+                            error!("Error handling message from {}: {}", peer_addr, e);
+                            Message::from_error(request_id, &e)
+                        }
+                    };
+                    let _ = tx.send((response, Some(permit)));
+                });
+            }
+
+            debug!("Connection closed by peer: {}", peer_addr);
+        };
+
+        let write_loop = async move {
+            let mut pushes_open = true;
+            loop {
+                let (response, permit) = tokio::select! {
+                    next = rx.recv() => match next {
+                        Some(next) => next,
+                        None => break,
+                    },
+                    push = inbox.recv(), if pushes_open => match push {
+                        Some(push) => (push, None),
+                        None if inbox.is_disconnected() => {
+                            warn!("Disconnecting slow subscriber {}", peer_addr);
+                            return;
+                        }
+                        None => {
+                            pushes_open = false;
+                            continue;
                         }
+                    },
+                };
+                debug!("Sending response to {}: {:?}", peer_addr, response);
+                let mut done = vec![permit];
+                let mut result = sink.feed(response).await;
+
+                // batch whatever else is already finished into the same flush
+                while result.is_ok() {
+                    match rx.try_recv() {
+                        Ok((response, permit)) => {
+                            done.push(permit);
+                            result = sink.feed(response).await;
+                        }
+                        Err(_) => break,
                     }
                 }
+
+                if let Err(e) = result.and(sink.flush().await) {
+                    error!("Error sending response to {}: {}", peer_addr, e);
+                    return;
+                }
+                drop(done);
+            }
+        };
+
+        tokio::pin!(write_loop);
+        tokio::select! {
+            _ = read_loop => {
+                // drain responses for requests that were still in flight
+                write_loop.await;
+            }
+            _ = &mut write_loop => {}
+        }
+
+        broker.unregister(connection_id);
+        Ok(())
+    }
+}
+
+pub struct BlockingProtocolConnectionHandler<S: StorageBackend = KeyValueStore> {
+    stream: std::net::TcpStream,
+    peer_addr: SocketAddr,
+    handler: Arc<ProtocolHandler<S>>,
+    buffer_size: usize,
+    max_frame_size: usize,
+    shutdown: ShutdownHandle,
+}
+
+impl<S: StorageBackend> BlockingProtocolConnectionHandler<S> {
+    pub fn new(
+        stream: std::net::TcpStream,
+        peer_addr: SocketAddr,
+        handler: Arc<ProtocolHandler<S>>,
+        buffer_size: usize,
+        max_frame_size: usize,
+        shutdown: ShutdownHandle,
+    ) -> Self {
+        Self {
+            stream,
+            peer_addr,
+            handler,
+            buffer_size,
+            max_frame_size,
+            shutdown,
+        }
+    }
+
+    /// Serves requests until the peer disconnects. When the server drains, it
+    /// shuts down the read side of the socket; the handler then finishes the
+    /// current request and sends a going-away notice.
+    ///
+    /// The first subscription starts a second thread that writes push frames;
+    /// a lock keeps them from interleaving with responses.
+    pub fn handle(&mut self) -> Result<()> {
+        let mut reader = BufReader::with_capacity(self.buffer_size, self.stream.try_clone()?);
+        let writer = Arc::new(Mutex::new(self.stream.try_clone()?));
+        let broker = self.handler.broker().clone();
+        let (outbox, inbox) = broker.register();
+        let mut inbox = Some(inbox);
+
+        loop {
+            let message = match Message::read_from_limited(&mut reader, self.max_frame_size) {
+                Ok(message) => message,
+                Err(e @ ServerError::FrameTooLarge { request_id, .. }) => {
+                    warn!("Closing connection {}: {}", self.peer_addr, e);
+                    let _ = Message::new_error(request_id, e.to_string()).write_to(&mut *writer.lock().unwrap());
+                    break;
+                }
+                Err(ServerError::Io(e))
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe
+                    ) =>
+                {
+                    debug!("Connection closed by peer: {}", self.peer_addr);
+                    break;
+                }
                 Err(e) => {
-                    if e.to_string().contains("connection reset") ||
-                       e.to_string().contains("broken pipe") {
-                        debug!("Connection closed by peer: {}", self.peer_addr);
-                    } else {
-                        error!("Error reading from {}: {}", self.peer_addr, e);
-                    }
+                    debug!("Error reading from connection {}: {}", self.peer_addr, e);
                     break;
                 }
+            };
+
+            if matches!(message.op_code, OpCode::Subscribe | OpCode::PSubscribe | OpCode::Watch) {
+                if let Some(inbox) = inbox.take() {
+                    Self::spawn_pusher(self.peer_addr, inbox, writer.clone());
+                }
             }
+
+            let request_id = message.request_id;
+            let response = match self.handler.handle_connection_message(message, &outbox) {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("Error handling message from {}: {}", self.peer_addr, e);
+                    Message::from_error(request_id, &e)
+                }
+            };
+
+            if let Err(e) = response.write_to(&mut *writer.lock().unwrap()) {
+                debug!("Error writing to connection {}: {}", self.peer_addr, e);
+                break;
+            }
+        }
+
+        if self.shutdown.is_shutdown() {
+            debug!("Sending going-away notice to {}", self.peer_addr);
+            let _ = Message::new_going_away().write_to(&mut *writer.lock().unwrap());
         }
 
+        // closes the push queue, which ends the pusher thread
+        broker.unregister(outbox.id());
         Ok(())
     }
+
+    fn spawn_pusher(peer_addr: SocketAddr, mut inbox: Inbox, writer: Arc<Mutex<std::net::TcpStream>>) {
+        std::thread::spawn(move || {
+            while let Some(push) = inbox.blocking_recv() {
+                if let Err(e) = push.write_to(&mut *writer.lock().unwrap()) {
+                    debug!("Error writing push to connection {}: {}", peer_addr, e);
+                    return;
+                }
+            }
+            if inbox.is_disconnected() {
+                warn!("Disconnecting slow subscriber {}", peer_addr);
+                // wakes the request loop blocked in read
+                let _ = writer.lock().unwrap().shutdown(Shutdown::Both);
+            }
+        });
+    }
 }
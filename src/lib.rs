@@ -5,6 +5,7 @@ pub mod server;
 pub mod utils;
 pub mod client;
 pub mod protocol;
+pub mod pubsub;
 pub mod storage;
 
 use crate::utils::optimizations::SystemOptimizer;
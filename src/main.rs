@@ -1,9 +1,10 @@
 use log::{error, info};
-use std::env;
 use tcp_server::{
     config::ServerConfig,
-    server::{RawServer, StdServer},
+    error::Result,
+    server::{ServerBuilder, ShutdownHandle, ShutdownSummary},
 };
+use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
 async fn main() {
@@ -17,24 +18,40 @@ async fn main() {
         }
     };
 
-    // Determine which server implementation to use
-    let use_raw = env::var("USE_RAW_SERVER")
-        .map(|v| v.parse().unwrap_or(false))
-        .unwrap_or(false);
+    info!("Starting TCP server in {} mode...", config.mode);
 
-    info!("Starting TCP server...");
-
-    if use_raw {
-        let server = RawServer::new(config);
-        if let Err(e) = server.run() {
-            error!("Server error: {}", e);
-            std::process::exit(1);
-        }
-    } else {
-        let server = StdServer::new(config);
-        if let Err(e) = server.run().await {
+    match run(config).await {
+        Ok(summary) => info!("Shutdown complete: {}", summary),
+        Err(e) => {
             error!("Server error: {}", e);
             std::process::exit(1);
         }
     }
 }
+
+async fn run(config: ServerConfig) -> Result<ShutdownSummary> {
+    let mut server = ServerBuilder::new(config)?.build();
+    server.start()?;
+    shutdown_on_signal(server.shutdown_handle());
+    tokio::task::spawn_blocking(move || server.wait())
+        .await
+        .expect("server thread panicked")
+}
+
+fn shutdown_on_signal(shutdown: ShutdownHandle) {
+    tokio::spawn(async move {
+        let mut sigterm = match signal(SignalKind::terminate()) {
+            Ok(sigterm) => sigterm,
+            Err(e) => {
+                error!("Failed to install SIGTERM handler: {}", e);
+                return;
+            }
+        };
+
+        tokio::select! {
+            _ = sigterm.recv() => info!("Received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        }
+        shutdown.shutdown();
+    });
+}
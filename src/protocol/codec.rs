@@ -0,0 +1,99 @@
+use super::message::{Message, OpCode, DEFAULT_MAX_FRAME_SIZE, HEADER_SIZE};
+use crate::error::ServerError;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+// offsets of the request id and payload length inside the header
+const REQUEST_ID_OFFSET: usize = 1;
+const PAYLOAD_LEN_OFFSET: usize = 6;
+
+#[derive(Debug, Clone, Copy)]
+pub struct MessageCodec {
+    max_frame_size: usize,
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MessageCodec {
+    pub fn new() -> Self {
+        Self::with_max_frame_size(DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+        Self { max_frame_size }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+}
+
+fn read_u32_at(src: &BytesMut, offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&src[offset..offset + 4]);
+    u32::from_be_bytes(bytes)
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = ServerError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, ServerError> {
+        if src.len() < HEADER_SIZE {
+            src.reserve(HEADER_SIZE - src.len());
+            return Ok(None);
+        }
+
+        let payload_len = read_u32_at(src, PAYLOAD_LEN_OFFSET);
+        if payload_len as usize > self.max_frame_size {
+            return Err(ServerError::FrameTooLarge {
+                request_id: read_u32_at(src, REQUEST_ID_OFFSET),
+                payload_len,
+                max_frame_size: self.max_frame_size,
+            });
+        }
+
+        let payload_len = payload_len as usize;
+        let frame_len = HEADER_SIZE + payload_len;
+        if src.len() < frame_len {
+            // wait for the rest of the payload
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+
+        let message_type = src.get_u8();
+        let request_id = src.get_u32();
+        let op_code = src.get_u8();
+        let payload_len = src.get_u32();
+        let payload = src.split_to(payload_len as usize).to_vec();
+
+        Ok(Some(Message {
+            message_type,
+            request_id,
+            op_code: OpCode::try_from(op_code)?,
+            payload_len,
+            payload,
+        }))
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = ServerError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), ServerError> {
+        let payload_len = u32::try_from(message.payload.len())
+            .map_err(|_| ServerError::Protocol("Payload too large".into()))?;
+
+        dst.reserve(HEADER_SIZE + message.payload.len());
+        dst.put_u8(message.message_type);
+        dst.put_u32(message.request_id);
+        dst.put_u8(message.op_code as u8);
+        dst.put_u32(payload_len);
+        dst.extend_from_slice(&message.payload);
+        Ok(())
+    }
+}
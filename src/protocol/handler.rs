@@ -1,9 +1,12 @@
-use super::message::{Message, OpCode};
-use crate::error::Result;
-use crate::storage::KeyValueStore;
+use super::message::{ErrorCode, Message, OpCode};
+use crate::error::{Result, ServerError};
+use crate::pubsub::{Broker, Outbox, WatchTarget};
+use crate::storage::{KeyValueStore, ScanCursor, ScanFilter, StorageBackend, TxOp, WriteCondition};
 use log::debug;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct StoreRequest {
@@ -11,6 +14,98 @@ struct StoreRequest {
     value: Vec<u8>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct StoreExRequest {
+    key: String,
+    value: Vec<u8>,
+    ttl_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExpireRequest {
+    key: String,
+    ttl_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyRequest {
+    key: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ScanRequest {
+    cursor: ScanCursor,
+    count: u32,
+    filter: Option<ScanFilter>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IncrByRequest {
+    key: String,
+    delta: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CasRequest {
+    key: String,
+    value: Vec<u8>,
+    condition: WriteCondition,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TransactionRequest {
+    ops: Vec<TxOp>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SubscribeRequest {
+    // channels, or patterns for PSUBSCRIBE
+    names: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WatchRequest {
+    targets: Vec<WatchTarget>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PublishRequest {
+    channel: String,
+    payload: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MGetRequest {
+    keys: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MSetRequest {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MDeleteRequest {
+    keys: Vec<String>,
+}
+
+/// Outcome for one key of an `MGET`, `MSET` or `MDELETE`, in request order.
+/// A failing key does not stop the rest of the batch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyResult {
+    Found(Vec<u8>),
+    Missing,
+    /// The write was applied.
+    Done,
+    Error(String),
+}
+
+impl KeyResult {
+    pub fn is_error(&self) -> bool {
+        matches!(self, KeyResult::Error(_))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct RetrieveRequest {
     key: String,
@@ -21,13 +116,44 @@ struct DeleteRequest {
     key: String,
 }
 
-pub struct ProtocolHandler {
-    store: Arc<KeyValueStore>,
+pub struct ProtocolHandler<S: StorageBackend = KeyValueStore> {
+    store: Arc<S>,
+    broker: Arc<Broker>,
+    // whether the broker listens to the store's changes yet
+    watching: AtomicBool,
 }
 
-impl ProtocolHandler {
-    pub fn new(store: Arc<KeyValueStore>) -> Self {
-        Self { store }
+impl<S: StorageBackend> ProtocolHandler<S> {
+    /// Handler with a broker of its own; use [`ProtocolHandler::with_broker`]
+    /// to let connections publish to each other.
+    pub fn new(store: Arc<S>) -> Self {
+        Self::with_broker(store, Arc::new(Broker::default()))
+    }
+
+    pub fn with_broker(store: Arc<S>, broker: Arc<Broker>) -> Self {
+        Self {
+            store,
+            broker,
+            watching: AtomicBool::new(false),
+        }
+    }
+
+    pub fn broker(&self) -> &Arc<Broker> {
+        &self.broker
+    }
+
+    /// Like [`ProtocolHandler::handle_message`], for a connection that
+    /// receives push frames through `outbox`, which subscriptions need.
+    pub fn handle_connection_message(&self, message: Message, outbox: &Outbox) -> Result<Message> {
+        if !message.is_request() {
+            return self.handle_message(message);
+        }
+
+        match message.op_code {
+            OpCode::Subscribe | OpCode::PSubscribe | OpCode::Unsubscribe => self.handle_subscribe(message, outbox),
+            OpCode::Watch | OpCode::Unwatch => self.handle_watch(message, outbox),
+            _ => self.handle_message(message),
+        }
     }
 
     pub fn handle_message(&self, message: Message) -> Result<Message> {
@@ -44,6 +170,30 @@ impl ProtocolHandler {
             OpCode::Retrieve => self.handle_retrieve(message),
             OpCode::Delete => self.handle_delete(message),
             OpCode::List => self.handle_list(message),
+            OpCode::StoreEx => self.handle_store_ex(message),
+            OpCode::Expire => self.handle_expire(message),
+            OpCode::Ttl => self.handle_ttl(message),
+            OpCode::Persist => self.handle_persist(message),
+            OpCode::Stats => self.handle_stats(message),
+            OpCode::Snapshot => self.handle_snapshot(message),
+            OpCode::Scan => self.handle_scan(message),
+            OpCode::MGet => self.handle_mget(message),
+            OpCode::MSet => self.handle_mset(message),
+            OpCode::MDelete => self.handle_mdelete(message),
+            OpCode::CompareAndSwap => self.handle_cas(message),
+            OpCode::RetrieveVersion => self.handle_retrieve_version(message),
+            OpCode::Incr => self.handle_incr(message, 1),
+            OpCode::Decr => self.handle_incr(message, -1),
+            OpCode::IncrBy => self.handle_incr_by(message),
+            OpCode::Transaction => self.handle_transaction(message),
+            OpCode::Subscribe
+            | OpCode::PSubscribe
+            | OpCode::Unsubscribe
+            | OpCode::Watch
+            | OpCode::Unwatch => Err(ServerError::Protocol(
+                "Subscriptions need a connection that accepts pushes".into(),
+            )),
+            OpCode::Publish => self.handle_publish(message),
         }
     }
 
@@ -70,8 +220,9 @@ impl ProtocolHandler {
 
         match self.store.get(&request.key)? {
             Some(value) => Ok(Message::new_response(message.request_id, value)),
-            None => Ok(Message::new_error(
+            None => Ok(Message::new_error_with_code(
                 message.request_id,
+                ErrorCode::KeyNotFound,
                 "Key not found".to_string(),
             )),
         }
@@ -96,4 +247,225 @@ impl ProtocolHandler {
 
         Ok(Message::new_response(message.request_id, response))
     }
+
+    fn handle_store_ex(&self, message: Message) -> Result<Message> {
+        debug!("Handling STOREEX request");
+        let request: StoreExRequest = bincode::deserialize(&message.payload)?;
+
+        self.store.set_with_ttl(&request.key, request.value, Duration::from_millis(request.ttl_ms))?;
+
+        Ok(Message::new_response(
+            message.request_id,
+            b"OK".to_vec(),
+        ))
+    }
+
+    fn handle_expire(&self, message: Message) -> Result<Message> {
+        debug!("Handling EXPIRE request");
+        let request: ExpireRequest = bincode::deserialize(&message.payload)?;
+
+        let applied = self.store.expire(&request.key, Duration::from_millis(request.ttl_ms))?;
+        let response = bincode::serialize(&applied)?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_ttl(&self, message: Message) -> Result<Message> {
+        debug!("Handling TTL request");
+        let request: KeyRequest = bincode::deserialize(&message.payload)?;
+
+        let ttl = self.store.ttl(&request.key)?;
+        let response = bincode::serialize(&ttl.to_millis())?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_persist(&self, message: Message) -> Result<Message> {
+        debug!("Handling PERSIST request");
+        let request: KeyRequest = bincode::deserialize(&message.payload)?;
+
+        let applied = self.store.persist(&request.key)?;
+        let response = bincode::serialize(&applied)?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_stats(&self, message: Message) -> Result<Message> {
+        debug!("Handling STATS request");
+        let response = bincode::serialize(&self.store.stats())?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_snapshot(&self, message: Message) -> Result<Message> {
+        debug!("Handling SNAPSHOT request");
+        self.store.request_snapshot()?;
+
+        Ok(Message::new_response(message.request_id, b"OK".to_vec()))
+    }
+
+    fn handle_scan(&self, message: Message) -> Result<Message> {
+        debug!("Handling SCAN request");
+        let request: ScanRequest = bincode::deserialize(&message.payload)?;
+
+        let page = self.store.scan(&request.cursor, request.count as usize, request.filter.as_ref())?;
+        let response = bincode::serialize(&page)?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_mget(&self, message: Message) -> Result<Message> {
+        debug!("Handling MGET request");
+        let request: MGetRequest = bincode::deserialize(&message.payload)?;
+
+        let results: Vec<KeyResult> = request
+            .keys
+            .iter()
+            .map(|key| match self.store.get(key) {
+                Ok(Some(value)) => KeyResult::Found(value),
+                Ok(None) => KeyResult::Missing,
+                Err(e) => KeyResult::Error(e.to_string()),
+            })
+            .collect();
+        let response = bincode::serialize(&results)?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_mset(&self, message: Message) -> Result<Message> {
+        debug!("Handling MSET request");
+        let request: MSetRequest = bincode::deserialize(&message.payload)?;
+
+        let results: Vec<KeyResult> = request
+            .entries
+            .into_iter()
+            .map(|(key, value)| write_result(self.store.set(&key, value)))
+            .collect();
+        let response = bincode::serialize(&results)?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_mdelete(&self, message: Message) -> Result<Message> {
+        debug!("Handling MDELETE request");
+        let request: MDeleteRequest = bincode::deserialize(&message.payload)?;
+
+        let results: Vec<KeyResult> = request
+            .keys
+            .iter()
+            .map(|key| write_result(self.store.delete(key)))
+            .collect();
+        let response = bincode::serialize(&results)?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_cas(&self, message: Message) -> Result<Message> {
+        debug!("Handling CAS request");
+        let request: CasRequest = bincode::deserialize(&message.payload)?;
+
+        let version = self.store.set_if(&request.key, request.value, &request.condition)?;
+        let response = bincode::serialize(&version)?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_retrieve_version(&self, message: Message) -> Result<Message> {
+        debug!("Handling RETRIEVEVERSION request");
+        let request: KeyRequest = bincode::deserialize(&message.payload)?;
+
+        let versioned = self.store.get_versioned(&request.key)?;
+        let response = bincode::serialize(&versioned)?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_incr(&self, message: Message, delta: i64) -> Result<Message> {
+        debug!("Handling {} request", if delta < 0 { "DECR" } else { "INCR" });
+        let request: KeyRequest = bincode::deserialize(&message.payload)?;
+
+        let value = self.store.incr_by(&request.key, delta)?;
+        let response = bincode::serialize(&value)?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_incr_by(&self, message: Message) -> Result<Message> {
+        debug!("Handling INCRBY request");
+        let request: IncrByRequest = bincode::deserialize(&message.payload)?;
+
+        let value = self.store.incr_by(&request.key, request.delta)?;
+        let response = bincode::serialize(&value)?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_transaction(&self, message: Message) -> Result<Message> {
+        debug!("Handling TRANSACTION request");
+        let request: TransactionRequest = bincode::deserialize(&message.payload)?;
+
+        let outcome = self.store.transact(request.ops)?;
+        let response = bincode::serialize(&outcome)?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_subscribe(&self, message: Message, outbox: &Outbox) -> Result<Message> {
+        let request: SubscribeRequest = bincode::deserialize(&message.payload)?;
+
+        let subscriptions = match message.op_code {
+            OpCode::Subscribe => {
+                debug!("Handling SUBSCRIBE request");
+                self.broker.subscribe(outbox, &request.names)
+            }
+            OpCode::PSubscribe => {
+                debug!("Handling PSUBSCRIBE request");
+                self.broker.psubscribe(outbox, &request.names)
+            }
+            _ => {
+                debug!("Handling UNSUBSCRIBE request");
+                self.broker.unsubscribe(outbox.id(), &request.names)
+            }
+        };
+        let response = bincode::serialize(&(subscriptions as u32))?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_watch(&self, message: Message, outbox: &Outbox) -> Result<Message> {
+        let request: WatchRequest = bincode::deserialize(&message.payload)?;
+
+        let watches = if message.op_code == OpCode::Watch {
+            debug!("Handling WATCH request");
+            // the store only pays for notifications once someone watches
+            if !self.watching.load(Ordering::Acquire) {
+                self.store.set_key_listener(self.broker.clone())?;
+                self.watching.store(true, Ordering::Release);
+            }
+            self.broker.watch(outbox, &request.targets)
+        } else {
+            debug!("Handling UNWATCH request");
+            self.broker.unwatch(outbox.id(), &request.targets)
+        };
+        let response = bincode::serialize(&(watches as u32))?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+
+    fn handle_publish(&self, message: Message) -> Result<Message> {
+        debug!("Handling PUBLISH request");
+        let request: PublishRequest = bincode::deserialize(&message.payload)?;
+
+        let receivers = self.broker.publish(&request.channel, &request.payload)?;
+        let response = bincode::serialize(&(receivers as u64))?;
+
+        Ok(Message::new_response(message.request_id, response))
+    }
+}
+
+fn write_result(result: Result<()>) -> KeyResult {
+    match result {
+        Ok(()) => KeyResult::Done,
+        Err(e) => KeyResult::Error(e.to_string()),
+    }
 }
@@ -7,6 +7,14 @@ use crate::error::{Result, ServerError};
 const MESSAGE_TYPE_REQUEST: u8 = 1;
 const MESSAGE_TYPE_RESPONSE: u8 = 2;
 const MESSAGE_TYPE_ERROR: u8 = 3;
+const MESSAGE_TYPE_GOING_AWAY: u8 = 4;
+const MESSAGE_TYPE_PUSH: u8 = 5;
+
+// type (1) + request id (4) + op code (1) + payload length (4)
+pub const HEADER_SIZE: usize = 10;
+
+// largest payload accepted from the wire unless configured otherwise
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
 
 // Operation codes
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
@@ -17,6 +25,28 @@ pub enum OpCode {
     Retrieve = 3,
     Delete = 4,
     List = 5,
+    StoreEx = 6,
+    Expire = 7,
+    Ttl = 8,
+    Persist = 9,
+    Stats = 10,
+    Snapshot = 11,
+    Scan = 12,
+    MGet = 13,
+    MSet = 14,
+    MDelete = 15,
+    CompareAndSwap = 16,
+    RetrieveVersion = 17,
+    Incr = 18,
+    Decr = 19,
+    IncrBy = 20,
+    Transaction = 21,
+    Subscribe = 22,
+    Unsubscribe = 23,
+    PSubscribe = 24,
+    Publish = 25,
+    Watch = 26,
+    Unwatch = 27,
 }
 
 impl TryFrom<u8> for OpCode {
@@ -29,12 +59,66 @@ impl TryFrom<u8> for OpCode {
             3 => Ok(OpCode::Retrieve),
             4 => Ok(OpCode::Delete),
             5 => Ok(OpCode::List),
+            6 => Ok(OpCode::StoreEx),
+            7 => Ok(OpCode::Expire),
+            8 => Ok(OpCode::Ttl),
+            9 => Ok(OpCode::Persist),
+            10 => Ok(OpCode::Stats),
+            11 => Ok(OpCode::Snapshot),
+            12 => Ok(OpCode::Scan),
+            13 => Ok(OpCode::MGet),
+            14 => Ok(OpCode::MSet),
+            15 => Ok(OpCode::MDelete),
+            16 => Ok(OpCode::CompareAndSwap),
+            17 => Ok(OpCode::RetrieveVersion),
+            18 => Ok(OpCode::Incr),
+            19 => Ok(OpCode::Decr),
+            20 => Ok(OpCode::IncrBy),
+            21 => Ok(OpCode::Transaction),
+            22 => Ok(OpCode::Subscribe),
+            23 => Ok(OpCode::Unsubscribe),
+            24 => Ok(OpCode::PSubscribe),
+            25 => Ok(OpCode::Publish),
+            26 => Ok(OpCode::Watch),
+            27 => Ok(OpCode::Unwatch),
             _ => Err(ServerError::Protocol(format!("Invalid opcode: {}", value))),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Why a request failed. Error frames carry it in the first payload byte,
+/// ahead of the message text, so clients can tell failures apart without
+/// parsing the text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum ErrorCode {
+    /// Any failure without a code of its own.
+    Other = 1,
+    KeyNotFound = 2,
+    /// INCR/DECR/INCRBY on a value that is not an integer, or an overflow.
+    NotAnInteger = 3,
+}
+
+impl From<&ServerError> for ErrorCode {
+    fn from(error: &ServerError) -> Self {
+        match error {
+            ServerError::NotAnInteger => ErrorCode::NotAnInteger,
+            _ => ErrorCode::Other,
+        }
+    }
+}
+
+impl From<u8> for ErrorCode {
+    fn from(value: u8) -> Self {
+        match value {
+            2 => ErrorCode::KeyNotFound,
+            3 => ErrorCode::NotAnInteger,
+            _ => ErrorCode::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Message {
     pub message_type: u8,
     pub request_id: u32,
@@ -65,21 +149,72 @@ impl Message {
     }
 
     pub fn new_error(request_id: u32, error_message: String) -> Self {
+        Self::new_error_with_code(request_id, ErrorCode::Other, error_message)
+    }
+
+    /// An error frame reporting `error`, with the code that matches it.
+    pub fn from_error(request_id: u32, error: &ServerError) -> Self {
+        Self::new_error_with_code(request_id, ErrorCode::from(error), error.to_string())
+    }
+
+    pub fn new_error_with_code(request_id: u32, code: ErrorCode, error_message: String) -> Self {
+        let mut payload = Vec::with_capacity(1 + error_message.len());
+        payload.push(code as u8);
+        payload.extend_from_slice(error_message.as_bytes());
         Self {
             message_type: MESSAGE_TYPE_ERROR,
             request_id,
             op_code: OpCode::Ping, // Not relevant for errors
-            payload_len: error_message.len() as u32,
-            payload: error_message.into_bytes(),
+            payload_len: payload.len() as u32,
+            payload,
+        }
+    }
+
+    /// Unsolicited notice that the server is shutting down and will close the
+    /// connection once outstanding responses have been sent.
+    pub fn new_going_away() -> Self {
+        let reason = b"Server shutting down".to_vec();
+        Self {
+            message_type: MESSAGE_TYPE_GOING_AWAY,
+            request_id: 0,
+            op_code: OpCode::Ping, // Not relevant for notices
+            payload_len: reason.len() as u32,
+            payload: reason,
+        }
+    }
+
+    /// Unsolicited frame the server sends to a connection that asked for it,
+    /// such as a published message. `op_code` says what kind of push it is.
+    pub fn new_push(op_code: OpCode, payload: Vec<u8>) -> Self {
+        Self {
+            message_type: MESSAGE_TYPE_PUSH,
+            request_id: 0,
+            op_code,
+            payload_len: payload.len() as u32,
+            payload,
         }
     }
 
     pub fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        Self::read_from_limited(reader, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Reads one message, rejecting any payload longer than `max_frame_size`
+    /// before allocating for it. The payload is left unread on rejection.
+    pub fn read_from_limited<R: Read>(reader: &mut R, max_frame_size: usize) -> Result<Self> {
         let message_type = reader.read_u8()?;
         let request_id = reader.read_u32::<BigEndian>()?;
         let op_code = reader.read_u8()?;
         let payload_len = reader.read_u32::<BigEndian>()?;
 
+        if payload_len as usize > max_frame_size {
+            return Err(ServerError::FrameTooLarge {
+                request_id,
+                payload_len,
+                max_frame_size,
+            });
+        }
+
         let mut payload = vec![0u8; payload_len as usize];
         reader.read_exact(&mut payload)?;
 
@@ -113,4 +248,23 @@ impl Message {
     pub fn is_error(&self) -> bool {
         self.message_type == MESSAGE_TYPE_ERROR
     }
+
+    /// The [`ErrorCode`] of an error frame; `None` for any other frame.
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        self.is_error()
+            .then(|| self.payload.first().map_or(ErrorCode::Other, |&code| ErrorCode::from(code)))
+    }
+
+    /// The text of an error frame, without its code.
+    pub fn error_message(&self) -> String {
+        String::from_utf8_lossy(self.payload.get(1..).unwrap_or_default()).into_owned()
+    }
+
+    pub fn is_going_away(&self) -> bool {
+        self.message_type == MESSAGE_TYPE_GOING_AWAY
+    }
+
+    pub fn is_push(&self) -> bool {
+        self.message_type == MESSAGE_TYPE_PUSH
+    }
 }
@@ -1,5 +1,7 @@
+pub mod codec;
 pub mod message;
 pub mod handler;
 
-pub use message::{Message, OpCode};
-pub use handler::ProtocolHandler;
+pub use codec::MessageCodec;
+pub use message::{ErrorCode, Message, OpCode};
+pub use handler::{KeyResult, ProtocolHandler};
@@ -0,0 +1,302 @@
+use super::outbox::{Inbox, Outbox, PushWaker, SlowSubscriberPolicy, DEFAULT_PUSH_BUFFER};
+use crate::config::ServerConfig;
+use crate::error::Result;
+use crate::protocol::message::{Message, OpCode};
+use crate::storage::{glob_match, KeyEvent, KeyListener};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Payload of a `PUBLISH` push frame.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Published {
+    pub channel: String,
+    /// The pattern that matched, for deliveries to `PSUBSCRIBE`rs.
+    pub pattern: Option<String>,
+    pub payload: Vec<u8>,
+}
+
+/// What a `WATCH` follows: a single key, or every key starting with a prefix.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum WatchTarget {
+    Key(String),
+    Prefix(String),
+}
+
+/// Routes published messages to the connections subscribed to a channel or
+/// to a glob pattern matching it, and keyspace changes to the connections
+/// watching the key. One broker is shared by every connection of a server.
+pub struct Broker {
+    state: Mutex<State>,
+    next_id: AtomicU64,
+    capacity: usize,
+    policy: SlowSubscriberPolicy,
+}
+
+#[derive(Default)]
+struct State {
+    channels: HashMap<String, HashMap<u64, Outbox>>,
+    patterns: HashMap<String, HashMap<u64, Outbox>>,
+    keys: HashMap<String, HashMap<u64, Outbox>>,
+    prefixes: HashMap<String, HashMap<u64, Outbox>>,
+    // what each connection is subscribed to, for counts and cleanup
+    connections: HashMap<u64, Subscriptions>,
+}
+
+#[derive(Default)]
+struct Subscriptions {
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    watches: HashSet<WatchTarget>,
+}
+
+impl Default for Broker {
+    fn default() -> Self {
+        Self::new(DEFAULT_PUSH_BUFFER, SlowSubscriberPolicy::default())
+    }
+}
+
+impl Broker {
+    /// `capacity` is the number of push frames queued per connection before
+    /// `policy` applies.
+    pub fn new(capacity: usize, policy: SlowSubscriberPolicy) -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+            next_id: AtomicU64::new(1),
+            capacity,
+            policy,
+        }
+    }
+
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self::new(config.push_buffer, config.slow_subscriber_policy)
+    }
+
+    /// Creates the push queue of a new connection.
+    pub fn register(&self) -> (Outbox, Inbox) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        Outbox::channel(id, self.capacity, self.policy)
+    }
+
+    /// Like [`Broker::register`], calling `waker` whenever a push is queued.
+    pub fn register_with_waker(&self, waker: PushWaker) -> (Outbox, Inbox) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        Outbox::with_waker(id, self.capacity, self.policy, Some(waker))
+    }
+
+    /// Drops every subscription and watch of the connection behind `id`.
+    pub fn unregister(&self, id: u64) {
+        self.unsubscribe(id, &[]);
+        self.unwatch(id, &[]);
+    }
+
+    /// Subscribes the connection to `channels` and returns how many channels
+    /// and patterns it is subscribed to now.
+    pub fn subscribe(&self, outbox: &Outbox, channels: &[String]) -> usize {
+        let mut state = self.state.lock().unwrap();
+        for channel in channels {
+            state.channels.entry(channel.clone()).or_default().insert(outbox.id(), outbox.clone());
+            state.connections.entry(outbox.id()).or_default().channels.insert(channel.clone());
+        }
+        state.count(outbox.id())
+    }
+
+    /// Like [`Broker::subscribe`] for glob patterns (`*`, `?`, `[a-z]`).
+    pub fn psubscribe(&self, outbox: &Outbox, patterns: &[String]) -> usize {
+        let mut state = self.state.lock().unwrap();
+        for pattern in patterns {
+            state.patterns.entry(pattern.clone()).or_default().insert(outbox.id(), outbox.clone());
+            state.connections.entry(outbox.id()).or_default().patterns.insert(pattern.clone());
+        }
+        state.count(outbox.id())
+    }
+
+    /// Removes the channel and pattern subscriptions named in `names`, or all
+    /// of them if `names` is empty. Returns how many remain.
+    pub fn unsubscribe(&self, id: u64, names: &[String]) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            channels,
+            patterns,
+            connections,
+            ..
+        } = &mut *state;
+        let Some(subscriptions) = connections.get_mut(&id) else {
+            return 0;
+        };
+
+        let all = names.is_empty();
+        for (subscribed, routes) in [(&mut subscriptions.channels, channels), (&mut subscriptions.patterns, patterns)] {
+            subscribed.retain(|name| {
+                if !all && !names.contains(name) {
+                    return true;
+                }
+                if let Some(outboxes) = routes.get_mut(name) {
+                    outboxes.remove(&id);
+                    if outboxes.is_empty() {
+                        routes.remove(name);
+                    }
+                }
+                false
+            });
+        }
+
+        let remaining = state.count(id);
+        state.forget_if_idle(id);
+        remaining
+    }
+
+    /// Sends the connection a push for every change to the keys `targets`
+    /// cover, and returns how many targets it watches now.
+    pub fn watch(&self, outbox: &Outbox, targets: &[WatchTarget]) -> usize {
+        let mut state = self.state.lock().unwrap();
+        for target in targets {
+            let routes = match target {
+                WatchTarget::Key(key) => state.keys.entry(key.clone()),
+                WatchTarget::Prefix(prefix) => state.prefixes.entry(prefix.clone()),
+            };
+            routes.or_default().insert(outbox.id(), outbox.clone());
+            state.connections.entry(outbox.id()).or_default().watches.insert(target.clone());
+        }
+        state.watch_count(outbox.id())
+    }
+
+    /// Removes the watches in `targets`, or all of them if `targets` is
+    /// empty. Returns how many remain.
+    pub fn unwatch(&self, id: u64, targets: &[WatchTarget]) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            keys,
+            prefixes,
+            connections,
+            ..
+        } = &mut *state;
+        let Some(subscriptions) = connections.get_mut(&id) else {
+            return 0;
+        };
+
+        subscriptions.watches.retain(|target| {
+            if !targets.is_empty() && !targets.contains(target) {
+                return true;
+            }
+            let (routes, name) = match target {
+                WatchTarget::Key(key) => (&mut *keys, key),
+                WatchTarget::Prefix(prefix) => (&mut *prefixes, prefix),
+            };
+            if let Some(outboxes) = routes.get_mut(name) {
+                outboxes.remove(&id);
+                if outboxes.is_empty() {
+                    routes.remove(name);
+                }
+            }
+            false
+        });
+
+        let remaining = state.watch_count(id);
+        state.forget_if_idle(id);
+        remaining
+    }
+
+    /// Pushes `payload` to every connection subscribed to `channel` or to a
+    /// matching pattern, once per matching subscription. Returns how many
+    /// pushes were queued; pushes dropped for slow subscribers still count.
+    pub fn publish(&self, channel: &str, payload: &[u8]) -> Result<usize> {
+        let mut delivered = 0;
+        let mut gone = Vec::new();
+        {
+            let state = self.state.lock().unwrap();
+            let mut deliver = |outboxes: &HashMap<u64, Outbox>, pattern: Option<&String>| -> Result<()> {
+                let push = Published {
+                    channel: channel.to_string(),
+                    pattern: pattern.cloned(),
+                    payload: payload.to_vec(),
+                };
+                let message = Message::new_push(OpCode::Publish, bincode::serialize(&push)?);
+                for outbox in outboxes.values() {
+                    if outbox.push(message.clone()) {
+                        delivered += 1;
+                    } else {
+                        gone.push(outbox.id());
+                    }
+                }
+                Ok(())
+            };
+
+            if let Some(outboxes) = state.channels.get(channel) {
+                deliver(outboxes, None)?;
+            }
+            for (pattern, outboxes) in &state.patterns {
+                if glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                    deliver(outboxes, Some(pattern))?;
+                }
+            }
+        }
+
+        for id in gone {
+            debug!("Dropping subscriptions of closed connection {}", id);
+            self.unregister(id);
+        }
+        Ok(delivered)
+    }
+}
+
+impl KeyListener for Broker {
+    fn key_changed(&self, event: &KeyEvent) {
+        let mut gone = Vec::new();
+        {
+            let state = self.state.lock().unwrap();
+            if state.keys.is_empty() && state.prefixes.is_empty() {
+                return;
+            }
+
+            // one push per connection, however many of its watches match
+            let mut watchers: HashMap<u64, &Outbox> = HashMap::new();
+            let prefixes = state.prefixes.iter().filter(|(prefix, _)| event.key.starts_with(prefix.as_str()));
+            for (_, outboxes) in state.keys.get_key_value(&event.key).into_iter().chain(prefixes) {
+                watchers.extend(outboxes.iter().map(|(&id, outbox)| (id, outbox)));
+            }
+            if watchers.is_empty() {
+                return;
+            }
+
+            let payload = match bincode::serialize(event) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    error!("Failed to encode change of '{}': {}", event.key, e);
+                    return;
+                }
+            };
+            let message = Message::new_push(OpCode::Watch, payload);
+            for (id, outbox) in watchers {
+                if !outbox.push(message.clone()) {
+                    gone.push(id);
+                }
+            }
+        }
+
+        for id in gone {
+            debug!("Dropping watches of closed connection {}", id);
+            self.unregister(id);
+        }
+    }
+}
+
+impl State {
+    fn count(&self, id: u64) -> usize {
+        self.connections
+            .get(&id)
+            .map_or(0, |subscriptions| subscriptions.channels.len() + subscriptions.patterns.len())
+    }
+
+    fn watch_count(&self, id: u64) -> usize {
+        self.connections.get(&id).map_or(0, |subscriptions| subscriptions.watches.len())
+    }
+
+    fn forget_if_idle(&mut self, id: u64) {
+        if self.count(id) + self.watch_count(id) == 0 {
+            self.connections.remove(&id);
+        }
+    }
+}
@@ -0,0 +1,5 @@
+mod broker;
+mod outbox;
+
+pub use broker::{Broker, Published, WatchTarget};
+pub use outbox::{Inbox, Outbox, PushWaker, SlowSubscriberPolicy, DEFAULT_PUSH_BUFFER};
@@ -0,0 +1,177 @@
+use crate::protocol::message::Message;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+/// Push frames queued per connection unless configured otherwise.
+pub const DEFAULT_PUSH_BUFFER: usize = 1024;
+
+/// Called after a push is queued or the connection is disconnected, for
+/// writers that poll their [`Inbox`] instead of awaiting it.
+pub type PushWaker = Arc<dyn Fn() + Send + Sync>;
+
+/// What happens to a connection whose push queue is full because it reads
+/// slower than messages are published to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SlowSubscriberPolicy {
+    /// Drop the new push for that connection and keep it subscribed.
+    #[default]
+    Drop,
+    /// Close the connection.
+    Disconnect,
+}
+
+impl std::str::FromStr for SlowSubscriberPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "drop" => Ok(SlowSubscriberPolicy::Drop),
+            "disconnect" => Ok(SlowSubscriberPolicy::Disconnect),
+            other => Err(format!("unknown slow subscriber policy '{}'", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for SlowSubscriberPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SlowSubscriberPolicy::Drop => "drop",
+            SlowSubscriberPolicy::Disconnect => "disconnect",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Sending side of a connection's bounded queue of push frames. Clones share
+/// the queue; it closes once every clone is dropped.
+#[derive(Clone)]
+pub struct Outbox {
+    id: u64,
+    inner: Arc<OutboxInner>,
+}
+
+struct OutboxInner {
+    // taken on disconnect, which closes the queue even while clones remain
+    sender: RwLock<Option<mpsc::Sender<Message>>>,
+    disconnected: Arc<AtomicBool>,
+    dropped: AtomicU64,
+    policy: SlowSubscriberPolicy,
+    waker: Option<PushWaker>,
+}
+
+/// Receiving side of an [`Outbox`], drained by the connection's writer.
+pub struct Inbox {
+    receiver: mpsc::Receiver<Message>,
+    disconnected: Arc<AtomicBool>,
+}
+
+impl Outbox {
+    pub fn channel(id: u64, capacity: usize, policy: SlowSubscriberPolicy) -> (Outbox, Inbox) {
+        Self::with_waker(id, capacity, policy, None)
+    }
+
+    pub fn with_waker(
+        id: u64,
+        capacity: usize,
+        policy: SlowSubscriberPolicy,
+        waker: Option<PushWaker>,
+    ) -> (Outbox, Inbox) {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let disconnected = Arc::new(AtomicBool::new(false));
+        let outbox = Outbox {
+            id,
+            inner: Arc::new(OutboxInner {
+                sender: RwLock::new(Some(sender)),
+                disconnected: disconnected.clone(),
+                dropped: AtomicU64::new(0),
+                policy,
+                waker,
+            }),
+        };
+        (outbox, Inbox { receiver, disconnected })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Queues `message` without blocking. Returns `false` once the connection
+    /// is gone or has been disconnected for falling behind, after which the
+    /// caller should forget this outbox.
+    pub fn push(&self, message: Message) -> bool {
+        let guard = self.inner.sender.read().unwrap();
+        let Some(sender) = guard.as_ref() else {
+            return false;
+        };
+
+        match sender.try_send(message) {
+            Ok(()) => {
+                self.wake();
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+            Err(TrySendError::Full(_)) => match self.inner.policy {
+                SlowSubscriberPolicy::Drop => {
+                    let dropped = self.inner.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                    debug!("Push queue of connection {} is full, dropped {} push(es)", self.id, dropped);
+                    true
+                }
+                SlowSubscriberPolicy::Disconnect => {
+                    drop(guard);
+                    warn!("Push queue of connection {} is full, disconnecting it", self.id);
+                    self.disconnect();
+                    false
+                }
+            },
+        }
+    }
+
+    /// Pushes dropped so far under [`SlowSubscriberPolicy::Drop`].
+    pub fn dropped(&self) -> u64 {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+
+    fn disconnect(&self) {
+        self.inner.disconnected.store(true, Ordering::SeqCst);
+        self.inner.sender.write().unwrap().take();
+        self.wake();
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = &self.inner.waker {
+            waker();
+        }
+    }
+}
+
+impl Inbox {
+    /// Waits for the next push. `None` means the queue closed or the
+    /// connection was disconnected for falling behind.
+    pub async fn recv(&mut self) -> Option<Message> {
+        let message = self.receiver.recv().await?;
+        (!self.is_disconnected()).then_some(message)
+    }
+
+    /// Blocking [`Inbox::recv`], for threads outside the tokio runtime.
+    pub fn blocking_recv(&mut self) -> Option<Message> {
+        let message = self.receiver.blocking_recv()?;
+        (!self.is_disconnected()).then_some(message)
+    }
+
+    /// Takes the next push if one is queued, without waiting.
+    pub fn try_recv(&mut self) -> Option<Message> {
+        let message = self.receiver.try_recv().ok()?;
+        (!self.is_disconnected()).then_some(message)
+    }
+
+    /// Whether the connection was disconnected under
+    /// [`SlowSubscriberPolicy::Disconnect`].
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,111 @@
+use crate::config::ServerConfig;
+use crate::error::Result;
+use crate::server::{IoUringServer, RawServer, Server, StdServer};
+use crate::storage::{KeyValueStore, SnapshotConfig, StorageBackend};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Which server implementation serves the protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServerMode {
+    /// `StdServer`: tokio tasks, one per connection.
+    #[default]
+    Tokio,
+    /// `RawServer`: edge-triggered epoll event loops on worker threads. Also
+    /// accepted as `raw`, and as the deprecated `raw-threads`, which named the
+    /// thread-per-connection server the event loops replaced.
+    RawEpoll,
+    /// `IoUringServer`: one io_uring event loop.
+    IoUring,
+}
+
+impl std::str::FromStr for ServerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tokio" => Ok(ServerMode::Tokio),
+            "raw-epoll" | "raw" => Ok(ServerMode::RawEpoll),
+            "raw-threads" => {
+                warn!("Server mode 'raw-threads' is deprecated, using 'raw-epoll'");
+                Ok(ServerMode::RawEpoll)
+            }
+            "io-uring" | "io_uring" => Ok(ServerMode::IoUring),
+            other => Err(format!("unknown server mode '{}'", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for ServerMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ServerMode::Tokio => "tokio",
+            ServerMode::RawEpoll => "raw-epoll",
+            ServerMode::IoUring => "io-uring",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Builds the server a [`ServerMode`] selects, so embedding applications
+/// can pick the implementation at runtime.
+///
+/// ```no_run
+/// use tcp_server::config::ServerConfig;
+/// use tcp_server::server::{ServerBuilder, ServerMode};
+///
+/// let mut server = ServerBuilder::new(ServerConfig::default())?
+///     .mode(ServerMode::RawEpoll)
+///     .build();
+/// server.start()?;
+/// println!("listening on {:?}", server.local_addr());
+/// server.shutdown_handle().shutdown();
+/// server.wait()?;
+/// # Ok::<(), tcp_server::error::ServerError>(())
+/// ```
+pub struct ServerBuilder<S: StorageBackend = KeyValueStore> {
+    config: ServerConfig,
+    store: Arc<S>,
+}
+
+impl ServerBuilder {
+    /// Serves a `KeyValueStore` set up from `config`, with its expiry
+    /// sweeper and, if enabled, snapshots running.
+    pub fn new(config: ServerConfig) -> Result<Self> {
+        let store = configured_store(&config)?;
+        Ok(Self::with_store(config, store))
+    }
+}
+
+impl<S: StorageBackend> ServerBuilder<S> {
+    pub fn with_store(config: ServerConfig, store: Arc<S>) -> Self {
+        Self { config, store }
+    }
+
+    /// Overrides `config.mode`.
+    pub fn mode(mut self, mode: ServerMode) -> Self {
+        self.config.mode = mode;
+        self
+    }
+
+    pub fn build(self) -> Box<dyn Server> {
+        match self.config.mode {
+            ServerMode::Tokio => Box::new(StdServer::with_store(self.config, self.store)),
+            ServerMode::RawEpoll => Box::new(RawServer::with_store(self.config, self.store)),
+            ServerMode::IoUring => Box::new(IoUringServer::with_store(self.config, self.store)),
+        }
+    }
+}
+
+/// The store every server's `new` starts from.
+pub(crate) fn configured_store(config: &ServerConfig) -> Result<Arc<KeyValueStore>> {
+    let store = Arc::new(KeyValueStore::from_config(config)?);
+    KeyValueStore::spawn_sweeper(&store, Duration::from_millis(config.expiry_sweep_interval_ms));
+    if config.snapshot_enabled {
+        KeyValueStore::spawn_snapshotter(&store, SnapshotConfig::from_server_config(config));
+    }
+    Ok(store)
+}
@@ -0,0 +1,728 @@
+use crate::config::ServerConfig;
+use crate::error::{Result, ServerError};
+use crate::protocol::codec::MessageCodec;
+use crate::protocol::ProtocolHandler;
+use crate::pubsub::Broker;
+use crate::server::builder::configured_store;
+use crate::server::reactor::Mailbox;
+use crate::server::session::{Session, WRITE_HIGH_WATER};
+use crate::server::traits::{Background, ConnectionStats};
+use crate::server::{Server, ServerMode, ServerStats, ShutdownHandle, ShutdownSummary};
+use crate::storage::{KeyValueStore, StorageBackend};
+use crate::utils::{SocketUtils, Stream};
+use bytes::{Buf, BytesMut};
+use io_uring::types::{BufRingEntry, Fd, Timespec};
+use io_uring::{cqueue, opcode, squeue, IoUring, Probe};
+use log::{debug, error, info, warn};
+use std::alloc::{self, Layout};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{Shutdown, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const RING_ENTRIES: u32 = 1024;
+// provided receive buffers; the kernel wants a power of two
+const RING_BUFFERS: u16 = 1024;
+const BUFFER_GROUP: u16 = 0;
+// how often the loop checks for shutdown while idle
+const TICK_MS: u64 = 100;
+
+// what a completion is for, in the low bits of its user_data; the rest is
+// the token of the connection it belongs to
+const ACCEPT: u64 = 0;
+const WAKE: u64 = 1;
+const TICK: u64 = 2;
+const RECV: u64 = 3;
+const SEND: u64 = 4;
+const CANCEL: u64 = 5;
+const KIND_BITS: u32 = 3;
+
+fn user_data(kind: u64, token: u64) -> u64 {
+    token << KIND_BITS | kind
+}
+
+/// Server driven by one io_uring instance on the thread calling `run`:
+/// accepts, receives and sends are submitted to the kernel and complete
+/// asynchronously. Uses multishot accept and a provided buffer ring for
+/// receives on kernels that have them (5.19+), plain accepts and
+/// per-connection buffers otherwise.
+pub struct IoUringServer<S: StorageBackend = KeyValueStore> {
+    config: ServerConfig,
+    store: Arc<S>,
+    broker: Arc<Broker>,
+    shutdown: ShutdownHandle,
+    stats: Arc<ConnectionStats>,
+    background: Option<Background>,
+}
+
+impl IoUringServer {
+    pub fn new(config: ServerConfig) -> Result<Self> {
+        let store = configured_store(&config)?;
+        Ok(Self::with_store(config, store))
+    }
+}
+
+impl<S: StorageBackend> IoUringServer<S> {
+    pub fn with_store(config: ServerConfig, store: Arc<S>) -> Self {
+        let broker = Arc::new(Broker::from_config(&config));
+        Self {
+            config,
+            store,
+            broker,
+            shutdown: ShutdownHandle::new(),
+            stats: Arc::new(ConnectionStats::default()),
+            background: None,
+        }
+    }
+
+    pub fn store(&self) -> Arc<S> {
+        self.store.clone()
+    }
+
+    /// The broker shared by this server's connections, for publishing from
+    /// the embedding application.
+    pub fn broker(&self) -> Arc<Broker> {
+        self.broker.clone()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves connections until shutdown is requested, then drains them for
+    /// up to `drain_timeout_ms` before forcibly closing whatever is left.
+    /// Fails with [`ServerError::IoUringUnavailable`] before binding if the
+    /// kernel can't run it.
+    pub fn run(&self) -> Result<ShutdownSummary> {
+        let ring = Self::setup_ring()?;
+        let listener = SocketUtils::listen(&self.config)?;
+        self.serve(ring, listener)
+    }
+
+    fn serve(&self, ring: IoUring, listener: OwnedFd) -> Result<ShutdownSummary> {
+        info!("io_uring TCP server listening on {}", SocketUtils::bound_addr(&listener)?);
+        let handler = Arc::new(ProtocolHandler::with_broker(self.store.clone(), self.broker.clone()));
+        let (shutdown, stats) = (self.shutdown.clone(), self.stats.clone());
+        let summary = EventLoop::new(ring, listener, handler, &self.config, shutdown, stats)?.run();
+        SocketUtils::remove_unix_socket(&self.config);
+        summary
+    }
+
+    // the part of the server its event loop thread needs
+    fn detach(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            store: self.store.clone(),
+            broker: self.broker.clone(),
+            shutdown: self.shutdown.clone(),
+            stats: self.stats.clone(),
+            background: None,
+        }
+    }
+
+    fn setup_ring() -> Result<IoUring> {
+        let ring = IoUring::new(RING_ENTRIES)
+            .map_err(|e| ServerError::IoUringUnavailable(format!("io_uring_setup failed: {}", e)))?;
+
+        let mut probe = Probe::new();
+        ring.submitter()
+            .register_probe(&mut probe)
+            .map_err(|e| ServerError::IoUringUnavailable(format!("cannot probe supported operations: {}", e)))?;
+        let required = [
+            (opcode::Accept::CODE, "ACCEPT"),
+            (opcode::Recv::CODE, "RECV"),
+            (opcode::Send::CODE, "SEND"),
+            (opcode::Read::CODE, "READ"),
+            (opcode::Timeout::CODE, "TIMEOUT"),
+            (opcode::AsyncCancel::CODE, "ASYNC_CANCEL"),
+        ];
+        for (code, name) in required {
+            if !probe.is_supported(code) {
+                return Err(ServerError::IoUringUnavailable(format!("kernel lacks IORING_OP_{}", name)));
+            }
+        }
+        Ok(ring)
+    }
+}
+
+impl<S: StorageBackend> Server for IoUringServer<S> {
+    /// Also fails with [`ServerError::IoUringUnavailable`] if the kernel
+    /// can't run this server.
+    fn start(&mut self) -> Result<()> {
+        Background::check_unstarted(&self.background)?;
+        let ring = Self::setup_ring()?;
+        let listener = SocketUtils::listen(&self.config)?;
+        let bound = SocketUtils::bound_addr(&listener)?;
+        let server = self.detach();
+        self.background = Some(Background::spawn("io-uring-loop", bound, move || server.serve(ring, listener))?);
+        Ok(())
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.background.as_ref().and_then(|background| background.local_addr)
+    }
+
+    fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    fn broker(&self) -> Arc<Broker> {
+        self.broker.clone()
+    }
+
+    fn stats(&self) -> ServerStats {
+        self.stats.snapshot(ServerMode::IoUring)
+    }
+
+    fn wait(&mut self) -> Result<ShutdownSummary> {
+        Background::wait(self.background.as_mut())
+    }
+}
+
+/// Receive buffers registered with the kernel, which picks one as data
+/// arrives; idle connections then hold no buffer of their own.
+struct BufferRing {
+    entries: *mut BufRingEntry,
+    layout: Layout,
+    buffers: Vec<u8>,
+    size: usize,
+    tail: u16,
+}
+
+impl BufferRing {
+    fn register(ring: &IoUring, size: usize) -> io::Result<Self> {
+        // the ring itself must be page aligned
+        let layout = Layout::from_size_align(RING_BUFFERS as usize * std::mem::size_of::<BufRingEntry>(), 4096)
+            .expect("valid buffer ring layout");
+        let entries = unsafe { alloc::alloc_zeroed(layout) } as *mut BufRingEntry;
+        if entries.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        let mut buffers = Self {
+            entries,
+            layout,
+            buffers: vec![0; RING_BUFFERS as usize * size],
+            size,
+            tail: 0,
+        };
+
+        // safety: the ring memory outlives the registration, which `EventLoop` ends before dropping it
+        unsafe { ring.submitter().register_buf_ring_with_flags(entries as u64, RING_BUFFERS, BUFFER_GROUP, 0)? };
+        for bid in 0..RING_BUFFERS {
+            buffers.recycle(bid);
+        }
+        Ok(buffers)
+    }
+
+    fn get(&self, bid: u16, len: usize) -> &[u8] {
+        &self.buffers[bid as usize * self.size..][..len]
+    }
+
+    /// Hands buffer `bid` back to the kernel.
+    fn recycle(&mut self, bid: u16) {
+        unsafe {
+            let entry = &mut *self.entries.add((self.tail & (RING_BUFFERS - 1)) as usize);
+            entry.set_addr(self.buffers.as_mut_ptr().add(bid as usize * self.size) as u64);
+            entry.set_len(self.size as u32);
+            entry.set_bid(bid);
+        }
+        self.tail = self.tail.wrapping_add(1);
+        let tail = unsafe { &*(BufRingEntry::tail(self.entries) as *const AtomicU16) };
+        tail.store(self.tail, Ordering::Release);
+    }
+}
+
+impl Drop for BufferRing {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.entries as *mut u8, self.layout) };
+    }
+}
+
+struct Connection {
+    stream: Stream,
+    session: Session,
+    // receive buffer when there is no buffer ring; stays put while a receive is in flight
+    recv_buf: Vec<u8>,
+    // output handed to the kernel; stays put until its send completes
+    sending: BytesMut,
+    send_in_flight: bool,
+    receiving: bool,
+    // a cancel of the in-flight receive was submitted
+    cancelling: bool,
+    // drop output instead of sending it; the peer is gone or too slow
+    discard: bool,
+}
+
+impl Connection {
+    fn fail(&mut self) {
+        self.session.closing = true;
+        self.session.write_buf.clear();
+        self.discard = true;
+    }
+
+    fn pending_output(&self) -> usize {
+        self.sending.len() + self.session.write_buf.len()
+    }
+}
+
+struct EventLoop<S: StorageBackend> {
+    // dropped first, so the kernel lets go of every buffer below
+    ring: IoUring,
+    buffers: Option<BufferRing>,
+    listener: OwnedFd,
+    multishot_accept: bool,
+    accepting: bool,
+    // accepted while at max_connections, served as slots free up
+    parked: VecDeque<OwnedFd>,
+    connections: HashMap<u64, Connection>,
+    next_token: u64,
+    // receives that found the buffer ring empty, retried after the batch
+    starved: Vec<u64>,
+    mailbox: Arc<Mailbox>,
+    wake_buf: Box<[u8; 8]>,
+    tick: Box<Timespec>,
+    handler: Arc<ProtocolHandler<S>>,
+    codec: MessageCodec,
+    config: ServerConfig,
+    shutdown: ShutdownHandle,
+    stats: Arc<ConnectionStats>,
+    draining: bool,
+}
+
+impl<S: StorageBackend> EventLoop<S> {
+    fn new(
+        ring: IoUring,
+        listener: OwnedFd,
+        handler: Arc<ProtocolHandler<S>>,
+        config: &ServerConfig,
+        shutdown: ShutdownHandle,
+        stats: Arc<ConnectionStats>,
+    ) -> Result<Self> {
+        let buffers = match BufferRing::register(&ring, config.buffer_size) {
+            Ok(buffers) => Some(buffers),
+            Err(e) => {
+                info!("Provided buffer rings unavailable ({}), using per-connection buffers", e);
+                None
+            }
+        };
+
+        Ok(Self {
+            ring,
+            buffers,
+            listener,
+            multishot_accept: true,
+            accepting: false,
+            parked: VecDeque::new(),
+            connections: HashMap::new(),
+            next_token: 1,
+            starved: Vec::new(),
+            mailbox: Arc::new(Mailbox::new()?),
+            wake_buf: Box::new([0; 8]),
+            tick: Box::new(Timespec::from(Duration::from_millis(TICK_MS))),
+            handler,
+            codec: MessageCodec::with_max_frame_size(config.max_frame_size),
+            config: config.clone(),
+            shutdown,
+            stats,
+            draining: false,
+        })
+    }
+
+    fn run(mut self) -> Result<ShutdownSummary> {
+        self.arm_accept();
+        self.arm_wake();
+        self.arm_tick();
+
+        let mut result = Ok(());
+        while !self.shutdown.is_shutdown() {
+            result = self.turn();
+            if let Err(e) = &result {
+                error!("Event loop failed, shutting down: {}", e);
+                self.shutdown.shutdown();
+            }
+        }
+
+        let summary = self.drain();
+        result.map(|_| summary)
+    }
+
+    /// Submits what is queued, waits for at least one completion and handles
+    /// every completion that is ready.
+    fn turn(&mut self) -> Result<()> {
+        match self.ring.submit_and_wait(1) {
+            Ok(_) => {}
+            // interrupted, or completions must be reaped first
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EINTR) | Some(libc::EBUSY)) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let completions: Vec<_> = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result(), cqe.flags()))
+            .collect();
+        for (data, result, flags) in completions {
+            let token = data >> KIND_BITS;
+            match data & ((1 << KIND_BITS) - 1) {
+                ACCEPT => self.on_accept(result, flags),
+                WAKE => self.on_wake(result),
+                TICK => self.on_tick(),
+                RECV => self.on_recv(token, result, flags),
+                SEND => self.on_send(token, result),
+                _ => {}
+            }
+        }
+
+        for token in std::mem::take(&mut self.starved) {
+            self.service(token);
+        }
+        Ok(())
+    }
+
+    fn submit(&mut self, entry: squeue::Entry) {
+        // safety: every buffer an entry points to is boxed, or owned by a
+        // connection that is only dropped once none of its operations are in flight
+        while unsafe { self.ring.submission().push(&entry) }.is_err() {
+            // the queue is full; hand it to the kernel to make room
+            if let Err(e) = self.ring.submit() {
+                if !matches!(e.raw_os_error(), Some(libc::EINTR) | Some(libc::EBUSY)) {
+                    error!("Failed to submit to io_uring: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn arm_accept(&mut self) {
+        if self.accepting || self.draining || self.connections.len() >= self.config.max_connections.max(1) {
+            return;
+        }
+        let listener = Fd(self.listener.as_raw_fd());
+        let flags = libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+        let entry = if self.multishot_accept {
+            opcode::AcceptMulti::new(listener).flags(flags).build()
+        } else {
+            opcode::Accept::new(listener, ptr::null_mut(), ptr::null_mut()).flags(flags).build()
+        };
+        self.submit(entry.user_data(user_data(ACCEPT, 0)));
+        self.accepting = true;
+    }
+
+    fn cancel_accept(&mut self) {
+        if self.accepting {
+            let cancel = opcode::AsyncCancel::new(user_data(ACCEPT, 0)).build();
+            self.submit(cancel.user_data(user_data(CANCEL, 0)));
+        }
+    }
+
+    fn arm_wake(&mut self) {
+        let eventfd = Fd(self.mailbox.eventfd().as_raw_fd());
+        let entry = opcode::Read::new(eventfd, self.wake_buf.as_mut_ptr(), 8).build();
+        self.submit(entry.user_data(user_data(WAKE, 0)));
+    }
+
+    fn arm_tick(&mut self) {
+        let entry = opcode::Timeout::new(&*self.tick as *const Timespec).build();
+        self.submit(entry.user_data(user_data(TICK, 0)));
+    }
+
+    fn on_accept(&mut self, result: i32, flags: u32) {
+        if !cqueue::more(flags) {
+            self.accepting = false;
+        }
+
+        if result >= 0 {
+            let fd = unsafe { OwnedFd::from_raw_fd(result) };
+            // accepted as the accept was being cancelled for shutdown
+            if !self.draining {
+                self.admit(fd);
+            }
+        } else {
+            match -result {
+                libc::EINVAL if self.multishot_accept => {
+                    info!("Multishot accept unavailable, falling back to single accepts");
+                    self.multishot_accept = false;
+                }
+                // at max_connections, or shutting down
+                libc::ECANCELED => {}
+                libc::EMFILE | libc::ENFILE => {
+                    // retried on the next tick
+                    error!("Out of file descriptors, pausing accept: {}", io::Error::from_raw_os_error(-result));
+                    return;
+                }
+                errno => error!("Error accepting connection: {}", io::Error::from_raw_os_error(errno)),
+            }
+        }
+        self.arm_accept();
+    }
+
+    fn on_wake(&mut self, result: i32) {
+        if result < 0 {
+            warn!("Error reading wake-ups: {}", io::Error::from_raw_os_error(-result));
+        }
+        for token in self.mailbox.take_ready() {
+            self.service(token);
+        }
+        self.arm_wake();
+    }
+
+    fn on_tick(&mut self) {
+        self.arm_accept();
+        self.arm_tick();
+    }
+
+    fn admit(&mut self, fd: OwnedFd) {
+        let max_connections = self.config.max_connections.max(1);
+        if self.connections.len() >= max_connections {
+            // accepted before the multishot accept was cancelled
+            self.parked.push_back(fd);
+            self.cancel_accept();
+            return;
+        }
+
+        let (stream, peer_addr) = match Stream::accepted(fd) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Error reading peer address: {}", e);
+                return;
+            }
+        };
+        info!("Accepted connection from {}", peer_addr);
+
+        let token = self.next_token;
+        self.next_token += 1;
+        let mailbox = self.mailbox.clone();
+        let (outbox, inbox) = self
+            .handler
+            .broker()
+            .register_with_waker(Arc::new(move || mailbox.push_ready(token)));
+        let recv_buf = match self.buffers {
+            Some(_) => Vec::new(),
+            None => vec![0; self.config.buffer_size],
+        };
+        self.connections.insert(
+            token,
+            Connection {
+                stream,
+                session: Session::new(peer_addr, self.config.buffer_size, outbox, inbox),
+                recv_buf,
+                sending: BytesMut::new(),
+                send_in_flight: false,
+                receiving: false,
+                cancelling: false,
+                discard: false,
+            },
+        );
+        self.stats.opened();
+
+        if self.connections.len() >= max_connections {
+            self.cancel_accept();
+        }
+        self.service(token);
+    }
+
+    fn on_recv(&mut self, token: u64, result: i32, flags: u32) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        connection.receiving = false;
+        connection.cancelling = false;
+        let session = &mut connection.session;
+
+        if result > 0 {
+            let len = result as usize;
+            match (cqueue::buffer_select(flags), &mut self.buffers) {
+                (Some(bid), Some(buffers)) => {
+                    session.read_buf.extend_from_slice(buffers.get(bid, len));
+                    buffers.recycle(bid);
+                }
+                _ => session.read_buf.extend_from_slice(&connection.recv_buf[..len]),
+            }
+            if !session.closing {
+                session.dispatch(&self.handler, self.codec, &self.config);
+            }
+        } else if result == 0 {
+            debug!("Connection closed by peer: {}", session.peer_addr);
+            // answer what the peer sent before closing its side
+            if !session.closing {
+                session.dispatch(&self.handler, self.codec, &self.config);
+            }
+            session.closing = true;
+        } else {
+            match -result {
+                libc::ENOBUFS => self.starved.push(token),
+                libc::ECANCELED | libc::EINTR => {}
+                errno => {
+                    debug!(
+                        "Error reading from connection {}: {}",
+                        session.peer_addr,
+                        io::Error::from_raw_os_error(errno)
+                    );
+                    connection.fail();
+                }
+            }
+        }
+        self.service(token);
+    }
+
+    fn on_send(&mut self, token: u64, result: i32) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        connection.send_in_flight = false;
+
+        if result < 0 {
+            if result != -libc::EINTR {
+                debug!(
+                    "Error writing to connection {}: {}",
+                    connection.session.peer_addr,
+                    io::Error::from_raw_os_error(-result)
+                );
+                connection.fail();
+            }
+        } else {
+            connection.sending.advance(result as usize);
+        }
+        if connection.discard {
+            connection.sending.clear();
+        }
+        self.service(token);
+    }
+
+    /// Moves a connection along: forwards pushes, keeps a send and a receive
+    /// in flight while there is work for them, and closes the connection
+    /// once it is finished and nothing is in flight.
+    fn service(&mut self, token: u64) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        let mut entries = Vec::with_capacity(2);
+        let fd = Fd(connection.stream.as_raw_fd());
+
+        if !connection.discard && !connection.session.forward_pushes(self.codec) {
+            connection.fail();
+        }
+
+        if !connection.send_in_flight && !connection.discard {
+            if connection.sending.is_empty() {
+                std::mem::swap(&mut connection.sending, &mut connection.session.write_buf);
+            }
+            if !connection.sending.is_empty() {
+                let len = connection.sending.len().min(u32::MAX as usize) as u32;
+                entries.push(opcode::Send::new(fd, connection.sending.as_ptr(), len).build().user_data(user_data(SEND, token)));
+                connection.send_in_flight = true;
+            }
+        }
+
+        if connection.session.closing {
+            if connection.receiving && !connection.cancelling {
+                let cancel = opcode::AsyncCancel::new(user_data(RECV, token)).build();
+                entries.push(cancel.user_data(user_data(CANCEL, token)));
+                connection.cancelling = true;
+            }
+        } else if !connection.receiving && connection.pending_output() < WRITE_HIGH_WATER {
+            let recv = match &self.buffers {
+                Some(buffers) => opcode::Recv::new(fd, ptr::null_mut(), buffers.size as u32)
+                    .buf_group(BUFFER_GROUP)
+                    .build()
+                    .flags(squeue::Flags::BUFFER_SELECT),
+                None => opcode::Recv::new(fd, connection.recv_buf.as_mut_ptr(), connection.recv_buf.len() as u32).build(),
+            };
+            entries.push(recv.user_data(user_data(RECV, token)));
+            connection.receiving = true;
+        }
+
+        let finished = connection.session.closing
+            && !connection.receiving
+            && !connection.send_in_flight
+            && connection.pending_output() == 0;
+        for entry in entries {
+            self.submit(entry);
+        }
+        if finished {
+            self.close(token);
+        }
+    }
+
+    fn close(&mut self, token: u64) {
+        let Some(connection) = self.connections.remove(&token) else {
+            return;
+        };
+        debug!("Closing connection {}", connection.session.peer_addr);
+        self.handler.broker().unregister(connection.session.outbox.id());
+        drop(connection);
+        self.stats.closed();
+
+        match self.parked.pop_front() {
+            Some(fd) => self.admit(fd),
+            None => self.arm_accept(),
+        }
+    }
+
+    /// Answers the requests each connection already sent, follows them with
+    /// a going-away notice and waits up to `drain_timeout_ms` for the output
+    /// to be flushed before closing whatever is left.
+    fn drain(mut self) -> ShutdownSummary {
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(self.config.drain_timeout_ms);
+        info!("Shutting down, draining {} connection(s)", self.connections.len());
+
+        self.draining = true;
+        self.cancel_accept();
+        // connections accepted as shutdown began are closed unserved
+        self.parked.clear();
+
+        let tokens: Vec<u64> = self.connections.keys().copied().collect();
+        let total = tokens.len();
+        for token in tokens {
+            if let Some(connection) = self.connections.get_mut(&token) {
+                connection.session.finish(&self.handler, self.codec, &self.config);
+            }
+            self.service(token);
+        }
+        while !self.connections.is_empty() && Instant::now() < deadline {
+            if let Err(e) = self.turn() {
+                error!("Event loop failed while draining: {}", e);
+                break;
+            }
+        }
+
+        let forced = self.connections.len();
+        if forced > 0 {
+            warn!("Drain deadline reached, closed {} connection(s)", forced);
+            let tokens: Vec<u64> = self.connections.keys().copied().collect();
+            for token in tokens {
+                if let Some(connection) = self.connections.get_mut(&token) {
+                    let _ = connection.stream.shutdown(Shutdown::Both);
+                    connection.fail();
+                }
+                self.service(token);
+            }
+            // the kernel may still write into their buffers until their operations complete
+            while !self.connections.is_empty() {
+                if let Err(e) = self.turn() {
+                    error!("Event loop failed while closing connections: {}", e);
+                    break;
+                }
+            }
+        }
+
+        if self.buffers.is_some() {
+            if let Err(e) = self.ring.submitter().unregister_buf_ring(BUFFER_GROUP) {
+                warn!("Failed to unregister receive buffers: {}", e);
+            }
+        }
+
+        let summary = ShutdownSummary {
+            drained_connections: total - forced,
+            forced_connections: forced,
+            drain_time: started.elapsed(),
+        };
+        info!("Server stopped: {}", summary);
+        summary
+    }
+}
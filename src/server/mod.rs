@@ -1,5 +1,15 @@
+mod builder;
+mod io_uring_server;
 mod raw_server;
+mod reactor;
+mod session;
+mod shutdown;
 mod std_server;
+mod traits;
 
+pub use builder::{ServerBuilder, ServerMode};
+pub use io_uring_server::IoUringServer;
 pub use raw_server::RawServer;
+pub use shutdown::{ShutdownHandle, ShutdownSummary};
 pub use std_server::StdServer;
+pub use traits::{Server, ServerStats};
@@ -1,129 +1,238 @@
 use crate::{config::ServerConfig, error::Result};
-use crate::handler::ConnectionHandler;
-use log::{error, info};
-use nix::sys::socket::{
-    accept,
-    bind,
-    getpeername,
-    listen,
-    socket,
-    setsockopt,
-    sockopt,
-    AddressFamily, SockFlag, SockType, SockaddrIn,
-};
-use nix::sys::time::TimeVal;
-use std::os::unix::io::{FromRawFd, AsRawFd};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::protocol::ProtocolHandler;
+use crate::pubsub::Broker;
+use crate::server::builder::configured_store;
+use crate::server::reactor::{ConnectionLimit, Worker, WorkerHandle};
+use crate::server::traits::{Background, ConnectionStats};
+use crate::server::{Server, ServerMode, ServerStats, ShutdownHandle, ShutdownSummary};
+use crate::storage::{KeyValueStore, StorageBackend};
+use crate::utils::{SocketUtils, Stream};
+use log::{error, info, warn};
+use nix::errno::Errno;
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags};
+use nix::sys::socket::{accept4, SockFlag};
+use std::net::SocketAddr;
+use std::os::fd::OwnedFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::sync::Arc;
-use std::os::fd::{AsFd, BorrowedFd};
-use std::net::SocketAddrV4;
-use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
 
-pub struct RawServer {
+// how often the accept loop wakes up to check for shutdown
+const ACCEPT_POLL_MS: u64 = 100;
+
+/// Server built directly on syscalls: an acceptor thread hands connections
+/// round-robin to `workers` event loop threads, each running its own
+/// edge-triggered epoll instance over non-blocking sockets.
+pub struct RawServer<S: StorageBackend = KeyValueStore> {
     config: ServerConfig,
-    active_connections: Arc<AtomicUsize>,
+    store: Arc<S>,
+    broker: Arc<Broker>,
+    shutdown: ShutdownHandle,
+    limit: Arc<ConnectionLimit>,
+    stats: Arc<ConnectionStats>,
+    background: Option<Background>,
 }
 
 impl RawServer {
-    pub fn new(config: ServerConfig) -> Self {
+    pub fn new(config: ServerConfig) -> Result<Self> {
+        let store = configured_store(&config)?;
+        Ok(Self::with_store(config, store))
+    }
+}
+
+impl<S: StorageBackend> RawServer<S> {
+    pub fn with_store(config: ServerConfig, store: Arc<S>) -> Self {
+        let broker = Arc::new(Broker::from_config(&config));
+        let limit = Arc::new(ConnectionLimit::new(config.max_connections));
         Self {
             config,
-            active_connections: Arc::new(AtomicUsize::new(0)),
+            store,
+            broker,
+            shutdown: ShutdownHandle::new(),
+            limit,
+            stats: Arc::new(ConnectionStats::default()),
+            background: None,
         }
     }
 
-    pub fn run(&self) -> Result<()> {
-        let sock_fd = socket(AddressFamily::Inet, SockType::Stream, SockFlag::empty(), None)?;
+    pub fn store(&self) -> Arc<S> {
+        self.store.clone()
+    }
 
-        // Convert IP address to SocketAddrV4
-        let addr = match self.config.host {
-            std::net::IpAddr::V4(ipv4) => SocketAddrV4::new(ipv4, self.config.port),
-            std::net::IpAddr::V6(_) => {
-                return Err(Error::new(ErrorKind::Unsupported, "IPv6 not supported").into())
-            }
-        };
-        let sock_addr = SockaddrIn::from(addr);
-        let sock_borrowed = unsafe { BorrowedFd::borrow_raw(sock_fd.as_raw_fd()) };
+    /// The broker shared by this server's connections, for publishing from
+    /// the embedding application.
+    pub fn broker(&self) -> Arc<Broker> {
+        self.broker.clone()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
 
-        bind(sock_fd.as_raw_fd(), &sock_addr)?;
-        listen(&sock_fd, self.config.backlog as usize)?;
+    /// Accepts connections until shutdown is requested, then drains them for up
+    /// to `drain_timeout_ms` before forcibly closing whatever is left.
+    pub fn run(&self) -> Result<ShutdownSummary> {
+        let listener = SocketUtils::listen(&self.config)?;
+        self.serve(listener)
+    }
 
+    fn serve(&self, listener: OwnedFd) -> Result<ShutdownSummary> {
         info!(
-            "Raw syscalls TCP server listening on {}:{}",
-            self.config.host, self.config.port
+            "Raw syscalls TCP server listening on {} with {} worker(s)",
+            SocketUtils::bound_addr(&listener)?,
+            self.worker_count()
         );
 
-        self.set_socket_options(sock_borrowed)?;
-        loop {
-            if self.active_connections.load(Ordering::SeqCst) >= self.config.max_connections {
-                std::thread::sleep(std::time::Duration::from_millis(100));
-                continue;
+        let handler = Arc::new(ProtocolHandler::with_broker(self.store.clone(), self.broker.clone()));
+        let workers = (0..self.worker_count())
+            .map(|index| {
+                let (limit, stats) = (self.limit.clone(), self.stats.clone());
+                Worker::spawn(index, handler.clone(), &self.config, limit, stats, self.shutdown.clone())
+            })
+            .collect::<Result<Vec<_>>>();
+        let workers = match workers {
+            Ok(workers) => workers,
+            Err(e) => {
+                // stops the workers that did start
+                self.shutdown.shutdown();
+                return Err(e);
             }
+        };
+
+        let accepted = self.accept_loop(&listener, &workers);
+        drop(listener);
+        SocketUtils::remove_unix_socket(&self.config);
+        if let Err(e) = &accepted {
+            error!("Accept loop failed, shutting down: {}", e);
+            self.shutdown.shutdown();
+        }
+
+        let summary = self.drain(workers);
+        accepted.map(|_| summary)
+    }
+
+    // the part of the server its acceptor thread needs
+    fn detach(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            store: self.store.clone(),
+            broker: self.broker.clone(),
+            shutdown: self.shutdown.clone(),
+            limit: self.limit.clone(),
+            stats: self.stats.clone(),
+            background: None,
+        }
+    }
 
-            match accept(sock_fd.as_raw_fd()) {
-                Ok(client_fd) => {
-                    self.active_connections.fetch_add(1, Ordering::SeqCst);
+    fn worker_count(&self) -> usize {
+        match self.config.workers {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            workers => workers,
+        }
+    }
 
-                    let client_addr = getpeername(client_fd)?;
-                    let config = self.config.clone();
-                    let active_connections = self.active_connections.clone();
+    fn accept_loop(&self, listener: &OwnedFd, workers: &[WorkerHandle]) -> Result<()> {
+        let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
+        epoll.add(listener, EpollEvent::new(EpollFlags::EPOLLIN | EpollFlags::EPOLLET, 0))?;
+        let mut events = [EpollEvent::empty()];
+        let mut next_worker = 0;
 
-                    std::thread::spawn(move || {
-                        if let Err(e) = Self::handle_connection(client_fd, client_addr, config) {
-                            error!("Error handling connection: {}", e);
+        while !self.shutdown.is_shutdown() {
+            // at max_connections, wait for a connection to close
+            if !self.limit.acquire(Duration::from_millis(ACCEPT_POLL_MS)) {
+                continue;
+            }
+
+            let client_fd = match accept4(listener.as_raw_fd(), SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC) {
+                Ok(client_fd) => client_fd,
+                Err(e) => {
+                    self.limit.release();
+                    match e {
+                        // edge-triggered: only wait once the backlog is empty
+                        Errno::EAGAIN => match epoll.wait(&mut events, ACCEPT_POLL_MS as isize) {
+                            Ok(_) | Err(Errno::EINTR) => {}
+                            Err(e) => return Err(e.into()),
+                        },
+                        Errno::EINTR | Errno::ECONNABORTED => {}
+                        Errno::EMFILE | Errno::ENFILE => {
+                            error!("Out of file descriptors, pausing accept: {}", e);
+                            std::thread::sleep(Duration::from_millis(ACCEPT_POLL_MS));
                         }
-                        active_connections.fetch_sub(1, Ordering::SeqCst);
-                    });
-                }
-                Err(nix::errno::Errno::EAGAIN) => {
-                    // no conn available
+                        e => error!("Error accepting connection: {}", e),
+                    }
                     continue;
                 }
+            };
+
+            let (socket, peer_addr) = match Stream::accepted(unsafe { OwnedFd::from_raw_fd(client_fd) }) {
+                Ok(accepted) => accepted,
                 Err(e) => {
-                    error!("Error accepting connection: {}", e);
+                    error!("Error reading peer address: {}", e);
+                    self.limit.release();
                     continue;
                 }
-            }
+            };
+
+            info!("Accepted connection from {}", peer_addr);
+            self.stats.opened();
+            workers[next_worker % workers.len()].adopt(socket, peer_addr);
+            next_worker += 1;
         }
+        Ok(())
     }
 
-    fn set_socket_options(&self, sock_fd: BorrowedFd) -> Result<()> {
-        let read_timeout = TimeVal::new(
-            (self.config.read_timeout_ms / 1000) as i64,
-            ((self.config.read_timeout_ms % 1000) * 1000) as i32,
-        );
-        setsockopt(&sock_fd.as_fd(), sockopt::ReceiveTimeout, &read_timeout)?;
+    fn drain(&self, workers: Vec<WorkerHandle>) -> ShutdownSummary {
+        let started = Instant::now();
+        info!("Shutting down, draining {} connection(s)", self.limit.active());
 
-        let write_timeout = TimeVal::new(
-            (self.config.write_timeout_ms / 1000) as i64,
-            ((self.config.write_timeout_ms % 1000) * 1000) as i32,
-        );
-        setsockopt(&sock_fd.as_fd(), sockopt::SendTimeout, &write_timeout)?;
-        setsockopt(&sock_fd.as_fd(), sockopt::ReuseAddr, &true)?;
-        setsockopt(&sock_fd.as_fd(), sockopt::TcpNoDelay, &true)?;
+        for worker in &workers {
+            worker.wake();
+        }
+        let (drained, forced) = workers
+            .into_iter()
+            .map(WorkerHandle::join)
+            .fold((0, 0), |(drained, forced), (d, f)| (drained + d, forced + f));
+        if forced > 0 {
+            warn!("Drain deadline reached, closed {} connection(s)", forced);
+        }
 
-        Ok(())
+        let summary = ShutdownSummary {
+            drained_connections: drained,
+            forced_connections: forced,
+            drain_time: started.elapsed(),
+        };
+        info!("Server stopped: {}", summary);
+        summary
     }
+}
 
-    fn handle_connection(client_fd: i32, _client_addr: nix::sys::socket::SockaddrStorage, config: ServerConfig) -> Result<()> {
-        // convert the raw file descriptor to a TcpStream
-        let socket = unsafe { std::net::TcpStream::from_raw_fd(client_fd) };
+impl<S: StorageBackend> Server for RawServer<S> {
+    fn start(&mut self) -> Result<()> {
+        Background::check_unstarted(&self.background)?;
+        let listener = SocketUtils::listen(&self.config)?;
+        let bound = SocketUtils::bound_addr(&listener)?;
+        let server = self.detach();
+        self.background = Some(Background::spawn("raw-acceptor", bound, move || server.serve(listener))?);
+        Ok(())
+    }
 
-        socket.set_nodelay(true)?;
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.background.as_ref().and_then(|background| background.local_addr)
+    }
 
-        let peer_addr = socket.peer_addr()?;
-        let mut handler = ConnectionHandler::new(socket, peer_addr, config.buffer_size);
+    fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
 
-        handler.handle_blocking()?;
+    fn broker(&self) -> Arc<Broker> {
+        self.broker.clone()
+    }
 
-        Ok(())
+    fn stats(&self) -> ServerStats {
+        self.stats.snapshot(ServerMode::RawEpoll)
     }
-}
 
-impl Drop for RawServer {
-    fn drop(&mut self) {
-        while self.active_connections.load(Ordering::SeqCst) > 0 {
-            std::thread::sleep(std::time::Duration::from_millis(100));
-        }
+    fn wait(&mut self) -> Result<ShutdownSummary> {
+        Background::wait(self.background.as_mut())
     }
 }
@@ -0,0 +1,447 @@
+use crate::config::ServerConfig;
+use crate::error::Result;
+use crate::protocol::codec::MessageCodec;
+use crate::protocol::ProtocolHandler;
+use crate::server::session::{Session, WRITE_HIGH_WATER};
+use crate::server::traits::ConnectionStats;
+use crate::server::ShutdownHandle;
+use crate::storage::StorageBackend;
+use crate::utils::{PeerAddr, Stream};
+use bytes::Buf;
+use log::{debug, error};
+use nix::errno::Errno;
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags};
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// epoll token of a worker's eventfd; connections count up from 1
+const WAKER: u64 = 0;
+const EVENTS_PER_WAIT: usize = 1024;
+// how often a worker checks for shutdown while idle
+const POLL_MS: u64 = 100;
+
+/// Caps the connections open across all workers. The acceptor waits on it
+/// for a slot instead of polling.
+pub(crate) struct ConnectionLimit {
+    active: Mutex<usize>,
+    freed: Condvar,
+    max: usize,
+}
+
+impl ConnectionLimit {
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            active: Mutex::new(0),
+            freed: Condvar::new(),
+            max: max.max(1),
+        }
+    }
+
+    /// Takes a slot, waiting up to `timeout` for one to free up.
+    pub(crate) fn acquire(&self, timeout: Duration) -> bool {
+        let active = self.active.lock().unwrap();
+        let (mut active, _) = self
+            .freed
+            .wait_timeout_while(active, timeout, |active| *active >= self.max)
+            .unwrap();
+        if *active >= self.max {
+            return false;
+        }
+        *active += 1;
+        true
+    }
+
+    pub(crate) fn release(&self) {
+        *self.active.lock().unwrap() -= 1;
+        self.freed.notify_one();
+    }
+
+    pub(crate) fn active(&self) -> usize {
+        *self.active.lock().unwrap()
+    }
+}
+
+/// What other threads hand a worker: accepted connections, and the tokens of
+/// connections with pushes waiting. Both wake the worker through an eventfd
+/// in its epoll set.
+pub(crate) struct Mailbox {
+    eventfd: OwnedFd,
+    incoming: Mutex<Vec<(Stream, PeerAddr)>>,
+    ready: Mutex<Vec<u64>>,
+}
+
+impl Mailbox {
+    pub(crate) fn new() -> Result<Self> {
+        Ok(Self {
+            eventfd: eventfd(0, EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC)?,
+            incoming: Mutex::new(Vec::new()),
+            ready: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub(crate) fn eventfd(&self) -> &OwnedFd {
+        &self.eventfd
+    }
+
+    fn wake(&self) {
+        // only fails if the counter would overflow, and then it is non-zero anyway
+        let _ = nix::unistd::write(self.eventfd.as_raw_fd(), &1u64.to_ne_bytes());
+    }
+
+    pub(crate) fn push_ready(&self, token: u64) {
+        let mut ready = self.ready.lock().unwrap();
+        // a non-empty list means a wake-up is already pending
+        let wake = ready.is_empty();
+        ready.push(token);
+        drop(ready);
+        if wake {
+            self.wake();
+        }
+    }
+
+    pub(crate) fn take_ready(&self) -> Vec<u64> {
+        std::mem::take(&mut *self.ready.lock().unwrap())
+    }
+
+    // called before taking the lists, so nothing pushed after it is missed
+    fn reset(&self) {
+        let mut counter = [0u8; 8];
+        let _ = nix::unistd::read(self.eventfd.as_raw_fd(), &mut counter);
+    }
+}
+
+/// The acceptor's side of a worker thread.
+pub(crate) struct WorkerHandle {
+    mailbox: Arc<Mailbox>,
+    thread: JoinHandle<(usize, usize)>,
+}
+
+impl WorkerHandle {
+    pub(crate) fn adopt(&self, stream: Stream, peer_addr: PeerAddr) {
+        self.mailbox.incoming.lock().unwrap().push((stream, peer_addr));
+        self.mailbox.wake();
+    }
+
+    pub(crate) fn wake(&self) {
+        self.mailbox.wake();
+    }
+
+    /// Waits for the worker to finish draining and returns how many of its
+    /// connections closed in time and how many it had to force closed.
+    pub(crate) fn join(self) -> (usize, usize) {
+        self.thread.join().unwrap_or_else(|_| {
+            error!("Worker thread panicked");
+            (0, 0)
+        })
+    }
+}
+
+struct Connection {
+    stream: Stream,
+    session: Session,
+    // stopped reading because `write_buf` passed the high-water mark
+    read_paused: bool,
+}
+
+enum Progress {
+    // the socket has nothing more to read right now
+    Blocked,
+    Paused,
+    Failed,
+}
+
+/// One event loop thread: an epoll instance with the non-blocking,
+/// edge-triggered sockets of the connections it owns.
+pub(crate) struct Worker<S: StorageBackend> {
+    index: usize,
+    epoll: Epoll,
+    mailbox: Arc<Mailbox>,
+    connections: HashMap<u64, Connection>,
+    next_token: u64,
+    handler: Arc<ProtocolHandler<S>>,
+    codec: MessageCodec,
+    config: ServerConfig,
+    limit: Arc<ConnectionLimit>,
+    stats: Arc<ConnectionStats>,
+    shutdown: ShutdownHandle,
+}
+
+impl<S: StorageBackend> Worker<S> {
+    pub(crate) fn spawn(
+        index: usize,
+        handler: Arc<ProtocolHandler<S>>,
+        config: &ServerConfig,
+        limit: Arc<ConnectionLimit>,
+        stats: Arc<ConnectionStats>,
+        shutdown: ShutdownHandle,
+    ) -> Result<WorkerHandle> {
+        let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
+        let mailbox = Arc::new(Mailbox::new()?);
+        epoll.add(&mailbox.eventfd, EpollEvent::new(EpollFlags::EPOLLIN | EpollFlags::EPOLLET, WAKER))?;
+
+        let worker = Self {
+            index,
+            epoll,
+            mailbox: mailbox.clone(),
+            connections: HashMap::new(),
+            next_token: WAKER + 1,
+            handler,
+            codec: MessageCodec::with_max_frame_size(config.max_frame_size),
+            config: config.clone(),
+            limit,
+            stats,
+            shutdown,
+        };
+        let thread = std::thread::Builder::new()
+            .name(format!("raw-worker-{}", index))
+            .spawn(move || worker.run())?;
+        Ok(WorkerHandle { mailbox, thread })
+    }
+
+    fn run(mut self) -> (usize, usize) {
+        let mut events = vec![EpollEvent::empty(); EVENTS_PER_WAIT];
+        while !self.shutdown.is_shutdown() {
+            let ready = match self.epoll.wait(&mut events, POLL_MS as isize) {
+                Ok(ready) => ready,
+                Err(Errno::EINTR) => continue,
+                Err(e) => {
+                    error!("Worker {} failed waiting for events: {}", self.index, e);
+                    break;
+                }
+            };
+
+            for event in &events[..ready] {
+                match event.data() {
+                    WAKER => self.on_wake(),
+                    token => {
+                        let readable = event.events().intersects(
+                            EpollFlags::EPOLLIN | EpollFlags::EPOLLRDHUP | EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR,
+                        );
+                        self.on_ready(token, readable);
+                    }
+                }
+            }
+        }
+
+        let timeout = Duration::from_millis(self.config.drain_timeout_ms);
+        self.drain(timeout)
+    }
+
+    fn on_wake(&mut self) {
+        self.mailbox.reset();
+        let incoming = std::mem::take(&mut *self.mailbox.incoming.lock().unwrap());
+        for (stream, peer_addr) in incoming {
+            self.adopt(stream, peer_addr);
+        }
+        for token in self.mailbox.take_ready() {
+            self.on_ready(token, false);
+        }
+    }
+
+    fn adopt(&mut self, stream: Stream, peer_addr: PeerAddr) {
+        let token = self.next_token;
+        self.next_token += 1;
+
+        let interest = EpollFlags::EPOLLIN | EpollFlags::EPOLLOUT | EpollFlags::EPOLLRDHUP | EpollFlags::EPOLLET;
+        if let Err(e) = self.epoll.add(&stream, EpollEvent::new(interest, token)) {
+            error!("Failed to register connection {}: {}", peer_addr, e);
+            self.limit.release();
+            self.stats.closed();
+            return;
+        }
+
+        let mailbox = self.mailbox.clone();
+        let (outbox, inbox) = self
+            .handler
+            .broker()
+            .register_with_waker(Arc::new(move || mailbox.push_ready(token)));
+        debug!("Worker {} serving {}", self.index, peer_addr);
+        self.connections.insert(
+            token,
+            Connection {
+                stream,
+                session: Session::new(peer_addr, self.config.buffer_size, outbox, inbox),
+                read_paused: false,
+            },
+        );
+        // epoll reports data that arrived before the socket was registered
+    }
+
+    fn on_ready(&mut self, token: u64, readable: bool) {
+        // a push can wake us for a connection that closed in the meantime
+        let Some(mut connection) = self.connections.remove(&token) else {
+            return;
+        };
+        if self.service(&mut connection, readable) {
+            self.connections.insert(token, connection);
+        } else {
+            self.close(connection);
+        }
+    }
+
+    /// Reads and answers requests, forwards pushes and flushes, until the
+    /// socket would block either way. Returns `false` once the connection
+    /// should be closed.
+    fn service(&self, connection: &mut Connection, readable: bool) -> bool {
+        let mut readable = readable || connection.read_paused;
+        loop {
+            let progress = if readable && !connection.session.closing {
+                connection.fill(&self.handler, self.codec, &self.config)
+            } else {
+                Progress::Blocked
+            };
+            if let Progress::Failed = progress {
+                return false;
+            }
+            if !connection.session.forward_pushes(self.codec) {
+                return false;
+            }
+            if let Err(e) = connection.flush() {
+                debug!("Error writing to connection {}: {}", connection.session.peer_addr, e);
+                return false;
+            }
+            if connection.session.closing && connection.session.write_buf.is_empty() {
+                return false;
+            }
+
+            // resume a paused read once the output has drained
+            connection.read_paused = matches!(progress, Progress::Paused);
+            readable = connection.read_paused && connection.session.write_buf.len() < WRITE_HIGH_WATER;
+            if !readable {
+                return true;
+            }
+        }
+    }
+
+    fn close(&self, connection: Connection) {
+        debug!("Closing connection {}", connection.session.peer_addr);
+        let _ = self.epoll.delete(&connection.stream);
+        self.handler.broker().unregister(connection.session.outbox.id());
+        self.limit.release();
+        self.stats.closed();
+    }
+
+    /// Answers the requests each connection already sent, follows them with
+    /// a going-away notice and waits up to `timeout` for the output to be
+    /// flushed before closing whatever is left.
+    fn drain(mut self, timeout: Duration) -> (usize, usize) {
+        let deadline = Instant::now() + timeout;
+        let mut drained = 0;
+
+        // connections handed over as shutdown began are closed unserved
+        for (stream, _) in std::mem::take(&mut *self.mailbox.incoming.lock().unwrap()) {
+            drop(stream);
+            self.limit.release();
+            self.stats.closed();
+        }
+
+        let tokens: Vec<u64> = self.connections.keys().copied().collect();
+        for token in tokens {
+            let mut connection = self.connections.remove(&token).unwrap();
+            connection.session.finish(&self.handler, self.codec, &self.config);
+            if connection.flush().is_err() || connection.session.write_buf.is_empty() {
+                drained += 1;
+                self.close(connection);
+            } else {
+                self.connections.insert(token, connection);
+            }
+        }
+
+        let mut events = vec![EpollEvent::empty(); EVENTS_PER_WAIT];
+        while !self.connections.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let wait = (deadline - now).as_millis().min(POLL_MS as u128) as isize;
+            let ready = match self.epoll.wait(&mut events, wait) {
+                Ok(ready) => ready,
+                Err(Errno::EINTR) => continue,
+                Err(e) => {
+                    error!("Worker {} failed waiting for events: {}", self.index, e);
+                    break;
+                }
+            };
+
+            for event in &events[..ready] {
+                if event.data() == WAKER {
+                    self.mailbox.reset();
+                    continue;
+                }
+                let Some(mut connection) = self.connections.remove(&event.data()) else {
+                    continue;
+                };
+                if connection.flush().is_err() || connection.session.write_buf.is_empty() {
+                    drained += 1;
+                    self.close(connection);
+                } else {
+                    self.connections.insert(event.data(), connection);
+                }
+            }
+        }
+
+        let forced = self.connections.len();
+        for (_, connection) in std::mem::take(&mut self.connections) {
+            self.close(connection);
+        }
+        (drained, forced)
+    }
+}
+
+impl Connection {
+    /// Reads until the socket would block, answering every complete frame.
+    fn fill<S: StorageBackend>(
+        &mut self,
+        handler: &ProtocolHandler<S>,
+        codec: MessageCodec,
+        config: &ServerConfig,
+    ) -> Progress {
+        let session = &mut self.session;
+        while !session.closing {
+            if session.write_buf.len() >= WRITE_HIGH_WATER {
+                return Progress::Paused;
+            }
+
+            let start = session.read_buf.len();
+            session.read_buf.resize(start + config.buffer_size, 0);
+            let read = self.stream.read(&mut session.read_buf[start..]);
+            session.read_buf.truncate(start + read.as_ref().map_or(0, |&n| n));
+
+            match read {
+                Ok(0) => {
+                    debug!("Connection closed by peer: {}", session.peer_addr);
+                    // answer what the peer sent before closing its side
+                    session.dispatch(handler, codec, config);
+                    session.closing = true;
+                }
+                Ok(_) => session.dispatch(handler, codec, config),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Progress::Blocked,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    debug!("Error reading from connection {}: {}", session.peer_addr, e);
+                    return Progress::Failed;
+                }
+            }
+        }
+        Progress::Blocked
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let write_buf = &mut self.session.write_buf;
+        while !write_buf.is_empty() {
+            match self.stream.write(write_buf) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => write_buf.advance(n),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
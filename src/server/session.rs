@@ -0,0 +1,126 @@
+use crate::config::ServerConfig;
+use crate::error::ServerError;
+use crate::protocol::codec::MessageCodec;
+use crate::protocol::message::Message;
+use crate::protocol::ProtocolHandler;
+use crate::pubsub::{Inbox, Outbox};
+use crate::storage::StorageBackend;
+use crate::utils::PeerAddr;
+use bytes::BytesMut;
+use log::{debug, error, warn};
+use tokio_util::codec::{Decoder, Encoder};
+
+// stop reading requests, and forwarding pushes, while this much output is
+// waiting for a connection; a client that never reads can't grow it further
+pub(crate) const WRITE_HIGH_WATER: usize = 1024 * 1024;
+
+/// Protocol side of a connection on the syscall-level servers: bytes read
+/// but not yet decoded, responses and pushes not yet written, and the
+/// connection's push queue. How bytes reach the socket is up to the server.
+pub(crate) struct Session {
+    pub(crate) peer_addr: PeerAddr,
+    pub(crate) read_buf: BytesMut,
+    pub(crate) write_buf: BytesMut,
+    pub(crate) outbox: Outbox,
+    pub(crate) inbox: Inbox,
+    // close once `write_buf` is flushed
+    pub(crate) closing: bool,
+}
+
+impl Session {
+    pub(crate) fn new(peer_addr: PeerAddr, buffer_size: usize, outbox: Outbox, inbox: Inbox) -> Self {
+        Self {
+            peer_addr,
+            read_buf: BytesMut::with_capacity(buffer_size),
+            write_buf: BytesMut::new(),
+            outbox,
+            inbox,
+            closing: false,
+        }
+    }
+
+    /// Answers every complete frame in `read_buf`, or echoes it in echo mode.
+    pub(crate) fn dispatch<S: StorageBackend>(
+        &mut self,
+        handler: &ProtocolHandler<S>,
+        mut codec: MessageCodec,
+        config: &ServerConfig,
+    ) {
+        if config.echo_mode {
+            self.write_buf.extend_from_slice(&self.read_buf);
+            self.read_buf.clear();
+            return;
+        }
+
+        loop {
+            let message = match codec.decode(&mut self.read_buf) {
+                Ok(Some(message)) => message,
+                Ok(None) => return,
+                Err(e @ ServerError::FrameTooLarge { request_id, .. }) => {
+                    // the oversized payload is never read, so the stream cannot be resynchronised
+                    warn!("Closing connection {}: {}", self.peer_addr, e);
+                    self.queue(codec, Message::new_error(request_id, e.to_string()));
+                    self.closing = true;
+                    return;
+                }
+                Err(e) => {
+                    error!("Error reading from {}: {}", self.peer_addr, e);
+                    self.closing = true;
+                    return;
+                }
+            };
+
+            debug!("Received message from {}: {:?}", self.peer_addr, message);
+            let request_id = message.request_id;
+            let response = match handler.handle_connection_message(message, &self.outbox) {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("Error handling message from {}: {}", self.peer_addr, e);
+                    Message::from_error(request_id, &e)
+                }
+            };
+            self.queue(codec, response);
+        }
+    }
+
+    /// Answers the requests already received, follows them with a going-away
+    /// notice and marks the connection for closing.
+    pub(crate) fn finish<S: StorageBackend>(
+        &mut self,
+        handler: &ProtocolHandler<S>,
+        codec: MessageCodec,
+        config: &ServerConfig,
+    ) {
+        if self.closing {
+            return;
+        }
+        self.dispatch(handler, codec, config);
+        if !config.echo_mode {
+            debug!("Sending going-away notice to {}", self.peer_addr);
+            self.queue(codec, Message::new_going_away());
+        }
+        self.closing = true;
+    }
+
+    /// Moves queued pushes into the write buffer. Returns `false` if the
+    /// connection was disconnected for falling behind.
+    pub(crate) fn forward_pushes(&mut self, codec: MessageCodec) -> bool {
+        while self.write_buf.len() < WRITE_HIGH_WATER {
+            match self.inbox.try_recv() {
+                Some(push) => self.queue(codec, push),
+                None => break,
+            }
+        }
+        if self.inbox.is_disconnected() {
+            warn!("Disconnecting slow subscriber {}", self.peer_addr);
+            return false;
+        }
+        true
+    }
+
+    pub(crate) fn queue(&mut self, mut codec: MessageCodec, message: Message) {
+        if let Err(e) = codec.encode(message, &mut self.write_buf) {
+            error!("Error encoding response for {}: {}", self.peer_addr, e);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+#[derive(Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    notify: Notify,
+}
+
+/// Cloneable trigger that asks a running server to stop accepting, drain its
+/// connections and return from `run()`. Usable from both async and blocking code.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn shutdown(&self) {
+        self.state.requested.store(true, Ordering::SeqCst);
+        self.state.notify.notify_waiters();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.state.requested.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once `shutdown` has been called.
+    pub async fn wait(&self) {
+        loop {
+            let notified = self.state.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.is_shutdown() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ShutdownSummary {
+    /// Connections that finished on their own within the drain deadline.
+    pub drained_connections: usize,
+    /// Connections still open at the deadline and closed forcibly.
+    pub forced_connections: usize,
+    pub drain_time: Duration,
+}
+
+impl std::fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "drained {} connection(s), forcibly closed {} in {:?}",
+            self.drained_connections, self.forced_connections, self.drain_time
+        )
+    }
+}
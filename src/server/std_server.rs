@@ -1,36 +1,98 @@
 use crate::{config::ServerConfig, error::Result};
-use crate::handler::ConnectionHandler;
+use crate::handler::{ConnectionHandler, ProtocolConnectionHandler};
+use crate::protocol::ProtocolHandler;
+use crate::pubsub::Broker;
+use crate::server::builder::configured_store;
+use crate::server::traits::{Background, ConnectionStats};
+use crate::server::{Server, ServerMode, ServerStats, ShutdownHandle, ShutdownSummary};
+use crate::storage::{KeyValueStore, StorageBackend};
+use crate::utils::{PeerAddr, SocketUtils, Stream};
 
-use log::{error, info};
+use log::{error, info, warn};
 use std::net::SocketAddr;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Semaphore;
+use std::os::fd::OwnedFd;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
 use std::sync::Arc;
 
-pub struct StdServer {
+pub struct StdServer<S: StorageBackend = KeyValueStore> {
     config: ServerConfig,
     connection_limit: Arc<Semaphore>,
+    store: Arc<S>,
+    broker: Arc<Broker>,
+    shutdown: ShutdownHandle,
+    stats: Arc<ConnectionStats>,
+    background: Option<Background>,
 }
 
 impl StdServer {
-    pub fn new(config: ServerConfig) -> Self {
+    pub fn new(config: ServerConfig) -> Result<Self> {
+        let store = configured_store(&config)?;
+        Ok(Self::with_store(config, store))
+    }
+}
+
+impl<S: StorageBackend> StdServer<S> {
+    pub fn with_store(config: ServerConfig, store: Arc<S>) -> Self {
+        let broker = Arc::new(Broker::from_config(&config));
         let connection_limit = Arc::new(Semaphore::new(config.max_connections));
         Self {
             config,
             connection_limit,
+            store,
+            broker,
+            shutdown: ShutdownHandle::new(),
+            stats: Arc::new(ConnectionStats::default()),
+            background: None,
         }
     }
 
-    pub async fn run(&self) -> Result<()> {
-        let addr = SocketAddr::new(self.config.host, self.config.port);
-        let listener = TcpListener::bind(addr).await?;
+    pub fn store(&self) -> Arc<S> {
+        self.store.clone()
+    }
+
+    /// The broker shared by this server's connections, for publishing from
+    /// the embedding application.
+    pub fn broker(&self) -> Arc<Broker> {
+        self.broker.clone()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Accepts connections until shutdown is requested, then drains them for up
+    /// to `drain_timeout_ms` before aborting whatever is left.
+    pub async fn run(&self) -> Result<ShutdownSummary> {
+        self.serve(SocketUtils::listen(&self.config)?).await
+    }
+
+    async fn serve(&self, listener: OwnedFd) -> Result<ShutdownSummary> {
+        let addr = SocketUtils::bound_addr(&listener)?;
+        let listener = Listener::from_fd(listener, &self.config)?;
+        if self.config.echo_mode {
+            info!("TCP server listening on {} (echo mode)", addr);
+        } else {
+            info!("TCP server listening on {}", addr);
+        }
 
-        info!("TCP server listening on {}", addr);
+        let handler = Arc::new(ProtocolHandler::with_broker(self.store.clone(), self.broker.clone()));
+        let mut connections = JoinSet::new();
 
         loop {
-            let permit = self.connection_limit.clone().acquire_owned().await.unwrap();
-            let (socket, peer_addr) = match listener.accept().await {
-                Ok((socket, peer_addr)) => (socket, peer_addr),
+            // reap finished connections so the set does not grow unbounded
+            while connections.try_join_next().is_some() {}
+
+            let accepted = tokio::select! {
+                _ = self.shutdown.wait() => break,
+                accepted = Self::accept(&listener, &self.connection_limit) => accepted,
+            };
+
+            let (socket, peer_addr, permit) = match accepted {
+                Ok(accepted) => accepted,
                 Err(e) => {
                     error!("Failed to accept connection: {}", e);
                     continue;
@@ -38,30 +100,209 @@ impl StdServer {
             };
 
             info!("Accepted connection from {}", peer_addr);
+            self.stats.opened();
 
             let config = self.config.clone();
-            tokio::spawn(async move {
-                if let Err(e) = Self::process_connection(socket, peer_addr, config).await {
+            let handler = handler.clone();
+            let shutdown = self.shutdown.clone();
+            let stats = self.stats.clone();
+            connections.spawn(async move {
+                if let Err(e) = Self::process_connection(socket, peer_addr, config, handler, shutdown).await {
                     error!("Error processing connection from {}: {}", peer_addr, e);
                 }
+                stats.closed();
                 drop(permit);
             });
         }
+
+        drop(listener);
+        SocketUtils::remove_unix_socket(&self.config);
+        Ok(Self::drain(connections, Duration::from_millis(self.config.drain_timeout_ms)).await)
+    }
+
+    // the part of the server its accept task needs
+    fn detach(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            connection_limit: self.connection_limit.clone(),
+            store: self.store.clone(),
+            broker: self.broker.clone(),
+            shutdown: self.shutdown.clone(),
+            stats: self.stats.clone(),
+            background: None,
+        }
+    }
+
+    async fn accept(
+        listener: &Listener,
+        connection_limit: &Arc<Semaphore>,
+    ) -> std::io::Result<(Accepted, PeerAddr, OwnedSemaphorePermit)> {
+        let permit = connection_limit.clone().acquire_owned().await.unwrap();
+        let (socket, peer_addr) = listener.accept().await?;
+        Ok((socket, peer_addr, permit))
+    }
+
+    async fn drain(mut connections: JoinSet<()>, timeout: Duration) -> ShutdownSummary {
+        let started = Instant::now();
+        let open = connections.len();
+        info!("Shutting down, draining {} connection(s)", open);
+
+        let drained = tokio::time::timeout(timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+
+        let forced = connections.len();
+        if drained.is_err() {
+            warn!("Drain deadline reached, closing {} connection(s)", forced);
+            connections.shutdown().await;
+        }
+
+        let summary = ShutdownSummary {
+            drained_connections: open - forced,
+            forced_connections: forced,
+            drain_time: started.elapsed(),
+        };
+        info!("Server stopped: {}", summary);
+        summary
     }
 
     async fn process_connection(
-        socket: TcpStream,
-        peer_addr: SocketAddr,
+        socket: Accepted,
+        peer_addr: PeerAddr,
         config: ServerConfig,
+        handler: Arc<ProtocolHandler<S>>,
+        shutdown: ShutdownHandle,
+    ) -> Result<()> {
+        if config.echo_mode {
+            let std_stream = socket.into_std()?;
+
+            std_stream.set_read_timeout(Some(std::time::Duration::from_millis(config.read_timeout_ms)))?;
+            std_stream.set_write_timeout(Some(std::time::Duration::from_millis(config.write_timeout_ms)))?;
+
+            let mut handler = ConnectionHandler::new(std_stream, peer_addr, config.buffer_size);
+            tokio::select! {
+                result = handler.handle() => result?,
+                _ = shutdown.wait() => {}
+            }
+        } else {
+            match socket {
+                Accepted::Tcp(socket) => Self::serve_protocol(socket, peer_addr, &config, handler, shutdown).await?,
+                Accepted::Unix(socket) => Self::serve_protocol(socket, peer_addr, &config, handler, shutdown).await?,
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn serve_protocol<T: AsyncRead + AsyncWrite + Unpin>(
+        socket: T,
+        peer_addr: PeerAddr,
+        config: &ServerConfig,
+        handler: Arc<ProtocolHandler<S>>,
+        shutdown: ShutdownHandle,
     ) -> Result<()> {
-        let std_stream = socket.into_std()?;
+        let mut handler = ProtocolConnectionHandler::new(
+            socket,
+            peer_addr,
+            handler,
+            config.buffer_size,
+            config.max_frame_size,
+            config.max_in_flight,
+            shutdown,
+        );
+        handler.handle().await
+    }
+}
+
+// a `SocketUtils::listen` socket on the tokio runtime
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn from_fd(fd: OwnedFd, config: &ServerConfig) -> std::io::Result<Self> {
+        match config.unix_socket {
+            Some(_) => UnixListener::from_std(fd.into()).map(Listener::Unix),
+            None => TcpListener::from_std(fd.into()).map(Listener::Tcp),
+        }
+    }
 
-        std_stream.set_read_timeout(Some(std::time::Duration::from_millis(config.read_timeout_ms)))?;
-        std_stream.set_write_timeout(Some(std::time::Duration::from_millis(config.write_timeout_ms)))?;
+    async fn accept(&self) -> std::io::Result<(Accepted, PeerAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, peer_addr) = listener.accept().await?;
+                socket.set_nodelay(true)?;
+                Ok((Accepted::Tcp(socket), peer_addr.into()))
+            }
+            Listener::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                let pid = socket.peer_cred().ok().and_then(|cred| cred.pid());
+                Ok((Accepted::Unix(socket), PeerAddr::Unix { pid }))
+            }
+        }
+    }
+}
 
-        let mut handler = ConnectionHandler::new(std_stream, peer_addr, config.buffer_size);
-        handler.handle().await?;
+enum Accepted {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
 
+impl Accepted {
+    fn into_std(self) -> std::io::Result<Stream> {
+        match self {
+            Accepted::Tcp(socket) => socket.into_std().map(Stream::Tcp),
+            Accepted::Unix(socket) => socket.into_std().map(Stream::Unix),
+        }
+    }
+}
+
+impl<S: StorageBackend> Server for StdServer<S> {
+    /// Serves on the current tokio runtime if there is one, otherwise on a
+    /// runtime of its own.
+    fn start(&mut self) -> Result<()> {
+        Background::check_unstarted(&self.background)?;
+        let listener = SocketUtils::listen(&self.config)?;
+        let bound = SocketUtils::bound_addr(&listener)?;
+
+        let server = self.detach();
+        let serve = async move { server.serve(listener).await };
+        let background = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                let (background, finished) = Background::new(bound);
+                runtime.spawn(async move {
+                    let _ = finished.send(serve.await);
+                });
+                background
+            }
+            Err(_) => {
+                let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+                Background::spawn("tokio-server", bound, move || runtime.block_on(serve))?
+            }
+        };
+        self.background = Some(background);
         Ok(())
     }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.background.as_ref().and_then(|background| background.local_addr)
+    }
+
+    fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    fn broker(&self) -> Arc<Broker> {
+        self.broker.clone()
+    }
+
+    fn stats(&self) -> ServerStats {
+        self.stats.snapshot(ServerMode::Tokio)
+    }
+
+    fn wait(&mut self) -> Result<ShutdownSummary> {
+        Background::wait(self.background.as_mut())
+    }
 }
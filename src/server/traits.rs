@@ -0,0 +1,125 @@
+use crate::error::{Result, ServerError};
+use crate::pubsub::Broker;
+use crate::server::{ServerMode, ShutdownHandle, ShutdownSummary};
+use crate::utils::ListenAddr;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+
+/// What every server implementation offers an embedding application,
+/// whichever way it does its I/O. [`crate::server::ServerBuilder`] builds
+/// the one `ServerConfig::mode` selects.
+pub trait Server: Send {
+    /// Binds the listener and serves from background threads until shutdown
+    /// is requested. Returns once the listener is bound, so clients can
+    /// connect right away.
+    fn start(&mut self) -> Result<()>;
+
+    /// The address the listener is bound to, once started; with port `0`
+    /// this is where the OS-assigned port shows up. `None` for a Unix
+    /// domain socket listener.
+    fn local_addr(&self) -> Option<SocketAddr>;
+
+    fn shutdown_handle(&self) -> ShutdownHandle;
+
+    /// The broker shared by the server's connections, for publishing from
+    /// the embedding application.
+    fn broker(&self) -> Arc<Broker>;
+
+    fn stats(&self) -> ServerStats;
+
+    /// Blocks until the started server has stopped and drained its
+    /// connections. Call it from a blocking context, not an async task.
+    fn wait(&mut self) -> Result<ShutdownSummary>;
+}
+
+/// Connection counts of a running server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerStats {
+    pub mode: ServerMode,
+    /// Connections being served right now.
+    pub active_connections: usize,
+    /// Connections served since the server started.
+    pub accepted_connections: u64,
+}
+
+/// Counters behind [`ServerStats`], shared by a server and its connections.
+#[derive(Default)]
+pub(crate) struct ConnectionStats {
+    active: AtomicUsize,
+    accepted: AtomicU64,
+}
+
+impl ConnectionStats {
+    pub(crate) fn opened(&self) {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn closed(&self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self, mode: ServerMode) -> ServerStats {
+        ServerStats {
+            mode,
+            active_connections: self.active.load(Ordering::Relaxed),
+            accepted_connections: self.accepted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A server running in the background: where it listens, and the result
+/// of its `run` once it returns.
+pub(crate) struct Background {
+    pub(crate) local_addr: Option<SocketAddr>,
+    // behind a mutex only so servers stay `Sync`
+    done: Mutex<mpsc::Receiver<Result<ShutdownSummary>>>,
+}
+
+impl Background {
+    pub(crate) fn new(bound: ListenAddr) -> (Self, mpsc::Sender<Result<ShutdownSummary>>) {
+        let (finished, done) = mpsc::channel();
+        let local_addr = match bound {
+            ListenAddr::Tcp(addr) => Some(addr),
+            ListenAddr::Unix(_) => None,
+        };
+        (
+            Self {
+                local_addr,
+                done: Mutex::new(done),
+            },
+            finished,
+        )
+    }
+
+    /// Runs `run` on a thread named `name`.
+    pub(crate) fn spawn<F>(name: &str, bound: ListenAddr, run: F) -> Result<Self>
+    where
+        F: FnOnce() -> Result<ShutdownSummary> + Send + 'static,
+    {
+        let (background, finished) = Self::new(bound);
+        std::thread::Builder::new().name(name.to_string()).spawn(move || {
+            let _ = finished.send(run());
+        })?;
+        Ok(background)
+    }
+
+    /// Fails if a server was started already; servers start once.
+    pub(crate) fn check_unstarted(background: &Option<Self>) -> Result<()> {
+        match background {
+            Some(_) => Err(Error::new(ErrorKind::AlreadyExists, "Server was already started").into()),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn wait(background: Option<&mut Self>) -> Result<ShutdownSummary> {
+        let Some(background) = background else {
+            return Err(Error::new(ErrorKind::NotConnected, "Server was not started").into());
+        };
+        // the sender is gone once the result was taken, or if the server panicked
+        let done = background.done.lock().unwrap().recv();
+        done.unwrap_or_else(|_| Err(ServerError::Connection("Server has already stopped".into())))
+    }
+}
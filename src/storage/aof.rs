@@ -0,0 +1,307 @@
+use crate::error::{Result, ServerError};
+use log::{debug, error, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// payload length and CRC32 of the payload, both little-endian
+pub(super) const RECORD_HEADER_SIZE: usize = 8;
+const EVERYSEC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When the append-only log is flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// fsync after every record, before the write is acknowledged.
+    Always,
+    /// fsync once a second; a machine crash loses at most the last second.
+    #[default]
+    EverySec,
+    /// Leave flushing to the OS.
+    Never,
+}
+
+impl std::str::FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "never" => Ok(FsyncPolicy::Never),
+            other => Err(format!("unknown fsync policy '{}'", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            FsyncPolicy::Always => "always",
+            FsyncPolicy::EverySec => "everysec",
+            FsyncPolicy::Never => "never",
+        };
+        f.write_str(name)
+    }
+}
+
+/// One mutation in the log. Expiry times are absolute so replay after a
+/// restart does not extend them.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum AofRecord<'a> {
+    Set {
+        key: Cow<'a, str>,
+        value: Cow<'a, [u8]>,
+        expires_at_ms: Option<u64>,
+    },
+    Delete {
+        key: Cow<'a, str>,
+    },
+    Expire {
+        key: Cow<'a, str>,
+        expires_at_ms: u64,
+    },
+    Persist {
+        key: Cow<'a, str>,
+    },
+    /// The writes of one transaction, in a single record so a crash never
+    /// leaves half of them in the log.
+    Batch {
+        records: Vec<AofRecord<'a>>,
+    },
+    /// First record of a compacted log: the snapshot that holds what the
+    /// dropped records wrote.
+    Base {
+        snapshot: Cow<'a, Path>,
+    },
+}
+
+/// Write-ahead log of store mutations, one checksummed record per mutation.
+pub struct AppendOnlyLog {
+    file: Mutex<File>,
+    path: PathBuf,
+    fsync: FsyncPolicy,
+    dirty: AtomicBool,
+}
+
+impl AppendOnlyLog {
+    /// Opens or creates the log at `path` and returns the records it holds.
+    /// A torn final record, as left by a crash mid-write, is truncated away;
+    /// a damaged record anywhere else is an error.
+    pub(super) fn open(path: &Path, fsync: FsyncPolicy) -> Result<(Self, Vec<AofRecord<'static>>)> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let (frames, valid_len) = parse_frames::<AofRecord>(path, &data)?;
+        let records = frames.into_iter().map(|frame| frame.record).collect();
+        if valid_len < data.len() {
+            warn!(
+                "Truncating torn record at offset {} in {} ({} byte(s) dropped)",
+                valid_len,
+                path.display(),
+                data.len() - valid_len
+            );
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+
+        let log = Self {
+            file: Mutex::new(file),
+            path: path.to_path_buf(),
+            fsync,
+            dirty: AtomicBool::new(false),
+        };
+        Ok((log, records))
+    }
+
+    /// Appends `record` with a single write, fsyncing first if the policy is
+    /// `always`.
+    pub(super) fn append(&self, record: &AofRecord) -> Result<()> {
+        let buf = frame(&bincode::serialize(record)?);
+
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&buf)?;
+        match self.fsync {
+            FsyncPolicy::Always => file.sync_data()?,
+            FsyncPolicy::EverySec => self.dirty.store(true, Ordering::Release),
+            FsyncPolicy::Never => {}
+        }
+        Ok(())
+    }
+
+    /// Flushes appended records to disk if any were written since the last sync.
+    pub fn sync(&self) -> Result<()> {
+        if self.dirty.swap(false, Ordering::AcqRel) {
+            self.file.lock().unwrap().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Current length of the log. Every record before this offset has been
+    /// applied to the store.
+    pub(super) fn mark(&self) -> Result<u64> {
+        Ok(self.file.lock().unwrap().metadata()?.len())
+    }
+
+    /// Drops the records before `mark`, once the snapshot at `base` covering
+    /// them is on disk. A `Base` record naming the snapshot and the tail are
+    /// copied to a temporary file that replaces the log.
+    pub(super) fn compact(&self, mark: u64, base: &Path) -> Result<u64> {
+        let mut file = self.file.lock().unwrap();
+        let tmp_path = self.path.with_extension("aof.tmp");
+
+        let base = AofRecord::Base {
+            snapshot: Cow::Borrowed(base),
+        };
+        let mut compacted = frame(&bincode::serialize(&base)?);
+        file.seek(SeekFrom::Start(mark))?;
+        file.read_to_end(&mut compacted)?;
+
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&compacted)?;
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        sync_parent_dir(&self.path)?;
+
+        *file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        self.dirty.store(false, Ordering::Release);
+        Ok(mark)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        self.fsync
+    }
+
+    /// Starts the thread behind the `everysec` policy. It exits once the log
+    /// is dropped.
+    pub(super) fn spawn_syncer(log: &Arc<Self>) -> JoinHandle<()> {
+        let log: Weak<Self> = Arc::downgrade(log);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(EVERYSEC_INTERVAL);
+            let Some(log) = log.upgrade() else { break };
+            if let Err(e) = log.sync() {
+                error!("Failed to fsync {}: {}", log.path.display(), e);
+            }
+        })
+    }
+}
+
+impl Drop for AppendOnlyLog {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            error!("Failed to fsync {} on close: {}", self.path.display(), e);
+        } else {
+            debug!("Closed {}", self.path.display());
+        }
+    }
+}
+
+/// Prefixes `payload` with its length and CRC32.
+pub(super) fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// A decoded record and where its frame sits in the file.
+pub(super) struct Frame<T> {
+    pub offset: u64,
+    pub len: usize,
+    pub record: T,
+}
+
+/// Decodes consecutive frames from `data`, returning them along with the
+/// length of the valid prefix. A damaged
+/// final frame ends the valid prefix, as a crash mid-write would leave it; a
+/// damaged frame anywhere else is an error.
+pub(super) fn parse_frames<T: DeserializeOwned>(
+    path: &Path,
+    data: &[u8],
+) -> Result<(Vec<Frame<T>>, usize)> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let rest = &data[offset..];
+        if rest.len() < RECORD_HEADER_SIZE {
+            break;
+        }
+
+        let len = u32::from_le_bytes(rest[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+        let Some(payload) = rest.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len) else {
+            break;
+        };
+
+        let record = if crc32fast::hash(payload) == crc {
+            bincode::deserialize::<T>(payload).ok()
+        } else {
+            None
+        };
+
+        let end = offset + RECORD_HEADER_SIZE + len;
+        match record {
+            Some(record) => records.push(Frame {
+                offset: offset as u64,
+                len: end - offset,
+                record,
+            }),
+            // only the last record can have been cut short by a crash
+            None if end == data.len() => break,
+            None => {
+                return Err(ServerError::Storage(format!(
+                    "Corrupt record at offset {} in {}",
+                    offset,
+                    path.display()
+                )))
+            }
+        }
+        offset = end;
+    }
+
+    Ok((records, offset))
+}
+
+/// Makes a rename in the parent directory of `path` durable.
+pub(super) fn sync_parent_dir(path: &Path) -> Result<()> {
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty());
+    File::open(parent.unwrap_or(Path::new(".")))?.sync_all()?;
+    Ok(())
+}
+
+pub(super) fn to_unix_ms(at: Instant) -> u64 {
+    let now = Instant::now();
+    let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let unix_at = unix_now + at.saturating_duration_since(now);
+    unix_at.as_millis() as u64
+}
+
+/// Converts an absolute expiry back to an `Instant`, or `None` if it has passed.
+pub(super) fn from_unix_ms(ms: u64) -> Option<Instant> {
+    let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let remaining = Duration::from_millis(ms).checked_sub(unix_now)?;
+    (!remaining.is_zero()).then(|| Instant::now() + remaining)
+}
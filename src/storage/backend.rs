@@ -0,0 +1,198 @@
+use super::scan::{clamp_count, cursor_key, key_cursor};
+use super::{
+    EvictionPolicy, KeyListener, KeyTtl, KeyValueStore, ScanCursor, ScanFilter, ScanPage, StoreStats, TxOp, TxOutcome, Versioned,
+    WriteCondition,
+};
+use crate::error::{Result, ServerError};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Storage operations `ProtocolHandler` and the servers need from a store.
+///
+/// Only get/set/delete/list/size are required. Expiry, versioning, counters,
+/// transactions, change listeners, stats and snapshots have defaults so simple backends can
+/// leave them out: all but stats fail with a `Storage` error naming the
+/// operation.
+pub trait StorageBackend: Send + Sync + 'static {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    fn set(&self, key: &str, value: Vec<u8>) -> Result<()>;
+
+    fn delete(&self, key: &str) -> Result<()>;
+
+    fn list_keys(&self) -> Result<Vec<String>>;
+
+    /// Bytes currently charged against the backend's capacity.
+    fn current_size(&self) -> u64;
+
+    fn max_size(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn entry_count(&self) -> usize {
+        self.list_keys().map_or(0, |keys| keys.len())
+    }
+
+    /// Returns the page of keys after `cursor`; see [`KeyValueStore::scan`]
+    /// for the guarantees. The default sorts every key on each call and
+    /// resumes after the last key examined, so it is only suitable for small
+    /// backends.
+    fn scan(&self, cursor: &ScanCursor, count: usize, filter: Option<&ScanFilter>) -> Result<ScanPage> {
+        let after = cursor_key(cursor)?;
+        let mut keys: Vec<String> = self
+            .list_keys()?
+            .into_iter()
+            .filter(|key| after.is_none_or(|after| key.as_str() > after))
+            .collect();
+        keys.sort_unstable();
+
+        let count = clamp_count(count);
+        let cursor = match keys.get(count) {
+            Some(_) => key_cursor(&keys[count - 1]),
+            None => ScanCursor::start(),
+        };
+        keys.truncate(count);
+        keys.retain(|key| filter.is_none_or(|filter| filter.matches(key)));
+        Ok(ScanPage { keys, cursor })
+    }
+
+    fn set_with_ttl(&self, _key: &str, _value: Vec<u8>, _ttl: Duration) -> Result<()> {
+        Err(unsupported("STOREEX"))
+    }
+
+    fn expire(&self, _key: &str, _ttl: Duration) -> Result<bool> {
+        Err(unsupported("EXPIRE"))
+    }
+
+    fn persist(&self, _key: &str) -> Result<bool> {
+        Err(unsupported("PERSIST"))
+    }
+
+    fn ttl(&self, _key: &str) -> Result<KeyTtl> {
+        Err(unsupported("TTL"))
+    }
+
+    fn get_versioned(&self, _key: &str) -> Result<Option<Versioned>> {
+        Err(unsupported("RETRIEVEVERSION"))
+    }
+
+    /// Writes `value` only if `condition` holds, atomically. Returns the new
+    /// version, or `None` if nothing was written.
+    fn set_if(&self, _key: &str, _value: Vec<u8>, _condition: &WriteCondition) -> Result<Option<u64>> {
+        Err(unsupported("CAS"))
+    }
+
+    /// Atomically adds `delta` to the integer at `key`; see [`KeyValueStore::incr_by`].
+    fn incr_by(&self, _key: &str, _delta: i64) -> Result<i64> {
+        Err(unsupported("INCR"))
+    }
+
+    /// Applies `ops` all-or-nothing; see [`KeyValueStore::transact`].
+    fn transact(&self, _ops: Vec<TxOp>) -> Result<TxOutcome> {
+        Err(unsupported("TRANSACTION"))
+    }
+
+    /// Installs the listener told about every set and delete, which is what
+    /// `WATCH` runs on; see [`KeyValueStore::set_key_listener`].
+    fn set_key_listener(&self, _listener: Arc<dyn KeyListener>) -> Result<()> {
+        Err(unsupported("WATCH"))
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats {
+            entries: self.entry_count() as u64,
+            size: self.current_size(),
+            max_size: self.max_size(),
+            eviction_policy: EvictionPolicy::NoEviction,
+            evictions: 0,
+            expirations: 0,
+        }
+    }
+
+    fn request_snapshot(&self) -> Result<()> {
+        Err(unsupported("SNAPSHOT"))
+    }
+}
+
+fn unsupported(operation: &str) -> ServerError {
+    ServerError::Storage(format!("{} is not supported by this storage backend", operation))
+}
+
+impl StorageBackend for KeyValueStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        KeyValueStore::get(self, key)
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        KeyValueStore::set(self, key, value)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        KeyValueStore::delete(self, key)
+    }
+
+    fn list_keys(&self) -> Result<Vec<String>> {
+        KeyValueStore::list_keys(self)
+    }
+
+    fn current_size(&self) -> u64 {
+        KeyValueStore::current_size(self)
+    }
+
+    fn max_size(&self) -> u64 {
+        KeyValueStore::max_size(self)
+    }
+
+    fn entry_count(&self) -> usize {
+        KeyValueStore::entry_count(self)
+    }
+
+    fn scan(&self, cursor: &ScanCursor, count: usize, filter: Option<&ScanFilter>) -> Result<ScanPage> {
+        KeyValueStore::scan(self, cursor, count, filter)
+    }
+
+    fn set_with_ttl(&self, key: &str, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        KeyValueStore::set_with_ttl(self, key, value, ttl)
+    }
+
+    fn expire(&self, key: &str, ttl: Duration) -> Result<bool> {
+        KeyValueStore::expire(self, key, ttl)
+    }
+
+    fn persist(&self, key: &str) -> Result<bool> {
+        KeyValueStore::persist(self, key)
+    }
+
+    fn ttl(&self, key: &str) -> Result<KeyTtl> {
+        KeyValueStore::ttl(self, key)
+    }
+
+    fn get_versioned(&self, key: &str) -> Result<Option<Versioned>> {
+        KeyValueStore::get_versioned(self, key)
+    }
+
+    fn set_if(&self, key: &str, value: Vec<u8>, condition: &WriteCondition) -> Result<Option<u64>> {
+        KeyValueStore::set_if(self, key, value, condition)
+    }
+
+    fn incr_by(&self, key: &str, delta: i64) -> Result<i64> {
+        KeyValueStore::incr_by(self, key, delta)
+    }
+
+    fn transact(&self, ops: Vec<TxOp>) -> Result<TxOutcome> {
+        KeyValueStore::transact(self, ops)
+    }
+
+    fn set_key_listener(&self, listener: Arc<dyn KeyListener>) -> Result<()> {
+        KeyValueStore::set_key_listener(self, listener);
+        Ok(())
+    }
+
+    fn stats(&self) -> StoreStats {
+        KeyValueStore::stats(self)
+    }
+
+    fn request_snapshot(&self) -> Result<()> {
+        KeyValueStore::request_snapshot(self)
+    }
+}
@@ -0,0 +1,127 @@
+use super::scan::{clamp_count, cursor_key, key_cursor};
+use super::{entry_size, ScanCursor, ScanFilter, ScanPage, StorageBackend};
+use crate::error::{Result, ServerError};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::RwLock;
+
+/// In-memory backend that keeps keys in order, for range and prefix scans.
+///
+/// A single `RwLock` guards the map, so writes are serialized; size is
+/// accounted with [`entry_size`] like `KeyValueStore`.
+pub struct BTreeStore {
+    inner: RwLock<Inner>,
+    max_size: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    data: BTreeMap<String, Vec<u8>>,
+    size: u64,
+}
+
+impl BTreeStore {
+    pub fn new(max_size: u64) -> Self {
+        Self {
+            inner: RwLock::new(Inner::default()),
+            max_size,
+        }
+    }
+
+    /// Returns the entries whose keys fall between `start` and `end`, in key order.
+    pub fn range(&self, start: Bound<&str>, end: Bound<&str>) -> Vec<(String, Vec<u8>)> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .data
+            .range::<str, _>((start, end))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Returns the entries whose keys start with `prefix`, in key order.
+    pub fn scan_prefix(&self, prefix: &str) -> Vec<(String, Vec<u8>)> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .data
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+}
+
+impl StorageBackend for BTreeStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.inner.read().unwrap().data.get(key).cloned())
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        let old_size = inner.data.get(key).map_or(0, |old| entry_size(key, old));
+        let new_size = inner.size - old_size + entry_size(key, &value);
+        if new_size > self.max_size {
+            return Err(ServerError::Storage("Storage capacity exceeded".into()));
+        }
+
+        inner.size = new_size;
+        inner.data.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        if let Some(value) = inner.data.remove(key) {
+            inner.size -= entry_size(key, &value);
+        }
+        Ok(())
+    }
+
+    fn list_keys(&self) -> Result<Vec<String>> {
+        Ok(self.inner.read().unwrap().data.keys().cloned().collect())
+    }
+
+    fn current_size(&self) -> u64 {
+        self.inner.read().unwrap().size
+    }
+
+    fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Walks keys in order, seeking straight to the filter's literal prefix.
+    fn scan(&self, cursor: &ScanCursor, count: usize, filter: Option<&ScanFilter>) -> Result<ScanPage> {
+        let prefix = filter.map_or("", |filter| filter.literal_prefix());
+        let start = match cursor_key(cursor)? {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+
+        let count = clamp_count(count);
+        let inner = self.inner.read().unwrap();
+        let mut examined = inner
+            .data
+            .range::<str, _>((start, Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .take(count + 1);
+
+        let mut keys = Vec::new();
+        let mut last = None;
+        for key in examined.by_ref().take(count) {
+            last = Some(key);
+            if filter.is_none_or(|filter| filter.matches(key)) {
+                keys.push(key.clone());
+            }
+        }
+
+        let cursor = match (examined.next(), last) {
+            (Some(_), Some(last)) => key_cursor(last),
+            _ => ScanCursor::start(),
+        };
+        Ok(ScanPage { keys, cursor })
+    }
+
+    fn entry_count(&self) -> usize {
+        self.inner.read().unwrap().data.len()
+    }
+}
@@ -0,0 +1,134 @@
+use super::aof::AofRecord;
+use super::{Entry, KeyEventKind, KeyValueStore};
+use dashmap::mapref::entry::Entry as MapEntry;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::hash::BuildHasher;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+// entries inspected per eviction, as in Redis' maxmemory-samples
+const EVICTION_SAMPLES: usize = 5;
+
+/// What `KeyValueStore` does when a write would exceed `max_size`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EvictionPolicy {
+    /// Reject the write with `Storage capacity exceeded`.
+    #[default]
+    NoEviction,
+    /// Evict the least recently accessed of a few sampled keys.
+    Lru,
+    /// Evict the least frequently accessed of a few sampled keys.
+    Lfu,
+    /// Evict a random key.
+    Random,
+}
+
+impl std::str::FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            "random" => Ok(EvictionPolicy::Random),
+            other => Err(format!("unknown eviction policy '{}'", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::Lru => "lru",
+            EvictionPolicy::Lfu => "lfu",
+            EvictionPolicy::Random => "random",
+        };
+        f.write_str(name)
+    }
+}
+
+impl KeyValueStore {
+    /// Evicts one key not in `keep` according to the store's policy.
+    /// Returns `false` if there was nothing to evict.
+    pub(super) fn evict_one(&self, keep: &[&str]) -> bool {
+        let Some(victim) = self.pick_victim(keep) else {
+            return false;
+        };
+
+        if let MapEntry::Occupied(occupied) = self.data.entry(victim) {
+            // replay must not resurrect evicted keys
+            let logged = self.log(|| AofRecord::Delete {
+                key: Cow::Borrowed(occupied.key()),
+            });
+            if let Err(e) = logged {
+                error!("Failed to log eviction of '{}': {}", occupied.key(), e);
+                return false;
+            }
+
+            self.notify(occupied.key(), KeyEventKind::Delete);
+            let (key, entry) = occupied.remove_entry();
+            self.release(entry.size(&key));
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    }
+
+    /// Samples a handful of entries starting at a random position in a random
+    /// shard and returns the best candidate. Expired entries always win.
+    fn pick_victim(&self, keep: &[&str]) -> Option<String> {
+        let now = Instant::now();
+        let shards = self.data.shards();
+        let start = self.random() as usize % shards.len();
+        let mut best: Option<((u64, u64), String)> = None;
+        let mut sampled = 0;
+
+        for i in 0..shards.len() {
+            let shard = shards[(start + i) % shards.len()].read();
+            if shard.is_empty() {
+                continue;
+            }
+
+            let skip = self.random() as usize % shard.len();
+            for (key, value) in shard.iter().skip(skip).chain(shard.iter()).take(shard.len()) {
+                if keep.contains(&key.as_str()) {
+                    continue;
+                }
+
+                let score = self.eviction_score(value.get(), now);
+                if best.as_ref().is_none_or(|(best_score, _)| score < *best_score) {
+                    best = Some((score, key.clone()));
+                }
+
+                sampled += 1;
+                if sampled >= EVICTION_SAMPLES || self.policy == EvictionPolicy::Random {
+                    return best.map(|(_, key)| key);
+                }
+            }
+        }
+
+        best.map(|(_, key)| key)
+    }
+
+    // lower scores are evicted first
+    fn eviction_score(&self, entry: &Entry, now: Instant) -> (u64, u64) {
+        if entry.is_expired(now) {
+            return (0, 0);
+        }
+
+        let last_access = entry.last_access.load(Ordering::Relaxed) + 1;
+        match self.policy {
+            EvictionPolicy::Lfu => (entry.hits.load(Ordering::Relaxed) as u64 + 1, last_access),
+            _ => (last_access, 0),
+        }
+    }
+
+    fn random(&self) -> u64 {
+        let counter = self.random_counter.fetch_add(1, Ordering::Relaxed);
+        self.random_state.hash_one(counter)
+    }
+}
@@ -0,0 +1,171 @@
+use super::{KeyListener, KeyTtl, ScanCursor, ScanFilter, ScanPage, StorageBackend, StoreStats, TxOp, TxOutcome, Versioned, WriteCondition};
+use crate::error::{Result, ServerError};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Test double that wraps another backend and fails operations on demand,
+/// for exercising protocol and server behaviour against a misbehaving store.
+///
+/// Injected failures are `ServerError::Storage("Injected failure: <op>")`.
+/// Size and stats are passed through untouched.
+pub struct FaultyBackend<S> {
+    inner: S,
+    fail_reads: AtomicBool,
+    fail_writes: AtomicBool,
+    // operations left before every call fails; negative disables the countdown
+    fail_after: AtomicI64,
+    injected: AtomicU64,
+}
+
+impl<S: StorageBackend> FaultyBackend<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            fail_reads: AtomicBool::new(false),
+            fail_writes: AtomicBool::new(false),
+            fail_after: AtomicI64::new(-1),
+            injected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Makes get, list, scan and TTL lookups fail.
+    pub fn fail_reads(&self, fail: bool) {
+        self.fail_reads.store(fail, Ordering::SeqCst);
+    }
+
+    /// Makes set, delete and expiry changes fail.
+    pub fn fail_writes(&self, fail: bool) {
+        self.fail_writes.store(fail, Ordering::SeqCst);
+    }
+
+    /// Lets `operations` more calls through, then fails every call.
+    pub fn fail_after(&self, operations: u32) {
+        self.fail_after.store(operations as i64, Ordering::SeqCst);
+    }
+
+    /// Clears every failure mode.
+    pub fn heal(&self) {
+        self.fail_reads(false);
+        self.fail_writes(false);
+        self.fail_after.store(-1, Ordering::SeqCst);
+    }
+
+    /// Number of failures injected so far.
+    pub fn injected_failures(&self) -> u64 {
+        self.injected.load(Ordering::SeqCst)
+    }
+
+    fn check(&self, operation: &str, write: bool) -> Result<()> {
+        let mode = if write { &self.fail_writes } else { &self.fail_reads };
+        let counted_out = match self
+            .fail_after
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| (left > 0).then(|| left - 1))
+        {
+            Ok(_) => false,
+            Err(left) => left == 0,
+        };
+
+        if mode.load(Ordering::SeqCst) || counted_out {
+            self.injected.fetch_add(1, Ordering::SeqCst);
+            return Err(ServerError::Storage(format!("Injected failure: {}", operation)));
+        }
+        Ok(())
+    }
+}
+
+impl<S: StorageBackend> StorageBackend for FaultyBackend<S> {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.check("get", false)?;
+        self.inner.get(key)
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.check("set", true)?;
+        self.inner.set(key, value)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.check("delete", true)?;
+        self.inner.delete(key)
+    }
+
+    fn list_keys(&self) -> Result<Vec<String>> {
+        self.check("list", false)?;
+        self.inner.list_keys()
+    }
+
+    fn current_size(&self) -> u64 {
+        self.inner.current_size()
+    }
+
+    fn max_size(&self) -> u64 {
+        self.inner.max_size()
+    }
+
+    fn entry_count(&self) -> usize {
+        self.inner.entry_count()
+    }
+
+    fn scan(&self, cursor: &ScanCursor, count: usize, filter: Option<&ScanFilter>) -> Result<ScanPage> {
+        self.check("scan", false)?;
+        self.inner.scan(cursor, count, filter)
+    }
+
+    fn set_with_ttl(&self, key: &str, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.check("set_with_ttl", true)?;
+        self.inner.set_with_ttl(key, value, ttl)
+    }
+
+    fn expire(&self, key: &str, ttl: Duration) -> Result<bool> {
+        self.check("expire", true)?;
+        self.inner.expire(key, ttl)
+    }
+
+    fn persist(&self, key: &str) -> Result<bool> {
+        self.check("persist", true)?;
+        self.inner.persist(key)
+    }
+
+    fn ttl(&self, key: &str) -> Result<KeyTtl> {
+        self.check("ttl", false)?;
+        self.inner.ttl(key)
+    }
+
+    fn get_versioned(&self, key: &str) -> Result<Option<Versioned>> {
+        self.check("get_versioned", false)?;
+        self.inner.get_versioned(key)
+    }
+
+    fn set_if(&self, key: &str, value: Vec<u8>, condition: &WriteCondition) -> Result<Option<u64>> {
+        self.check("set_if", true)?;
+        self.inner.set_if(key, value, condition)
+    }
+
+    fn incr_by(&self, key: &str, delta: i64) -> Result<i64> {
+        self.check("incr_by", true)?;
+        self.inner.incr_by(key, delta)
+    }
+
+    fn transact(&self, ops: Vec<TxOp>) -> Result<TxOutcome> {
+        self.check("transact", true)?;
+        self.inner.transact(ops)
+    }
+
+    fn set_key_listener(&self, listener: Arc<dyn KeyListener>) -> Result<()> {
+        self.inner.set_key_listener(listener)
+    }
+
+    fn stats(&self) -> StoreStats {
+        self.inner.stats()
+    }
+
+    fn request_snapshot(&self) -> Result<()> {
+        self.check("snapshot", true)?;
+        self.inner.request_snapshot()
+    }
+}
@@ -0,0 +1,257 @@
+use super::aof::{self, frame, parse_frames, Frame, RECORD_HEADER_SIZE};
+use super::{entry_size, StorageBackend};
+use crate::error::{Result, ServerError};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+// don't bother compacting until at least this much of the file is dead
+const MIN_COMPACTION_GARBAGE: u64 = 1024 * 1024;
+
+/// File-backed backend: every write is appended to a data file and only an
+/// index of record offsets is kept in memory, so values live on disk.
+///
+/// The file is rewritten with just the live records once dead records
+/// outweigh live ones. Writes reach the OS before they are acknowledged;
+/// call [`LogStore::sync`] to force them to disk.
+pub struct LogStore {
+    inner: RwLock<Inner>,
+    path: PathBuf,
+    max_size: u64,
+}
+
+struct Inner {
+    file: File,
+    index: HashMap<String, Location>,
+    end: u64,
+    size: u64,
+    garbage: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Location {
+    offset: u64,
+    len: usize,
+    size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LogRecord<'a> {
+    key: Cow<'a, str>,
+    // `None` is a tombstone
+    value: Option<Cow<'a, [u8]>>,
+}
+
+impl LogStore {
+    /// Opens or creates the data file at `path` and rebuilds the index from it.
+    pub fn open(path: impl AsRef<Path>, max_size: u64) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let (records, valid_len) = parse_frames::<LogRecord>(path, &data)?;
+        if valid_len < data.len() {
+            warn!("Truncating torn record at offset {} in {}", valid_len, path.display());
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+
+        let mut inner = Inner {
+            file,
+            index: HashMap::new(),
+            end: valid_len as u64,
+            size: 0,
+            garbage: 0,
+        };
+        for Frame { offset, len, record } in records {
+            match record.value {
+                Some(value) => {
+                    let size = entry_size(&record.key, &value);
+                    inner.replace(record.key.into_owned(), Some(Location { offset, len, size }));
+                }
+                None => {
+                    inner.replace(record.key.into_owned(), None);
+                    inner.garbage += len as u64;
+                }
+            }
+        }
+
+        info!(
+            "Opened {}: {} key(s), {} live byte(s), {} dead byte(s)",
+            path.display(),
+            inner.index.len(),
+            inner.size,
+            inner.garbage
+        );
+        Ok(Self {
+            inner: RwLock::new(inner),
+            path: path.to_path_buf(),
+            max_size,
+        })
+    }
+
+    /// Forces written records to disk.
+    pub fn sync(&self) -> Result<()> {
+        self.inner.read().unwrap().file.sync_data()?;
+        Ok(())
+    }
+
+    /// Rewrites the data file with only the live records.
+    pub fn compact(&self) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        self.compact_locked(&mut inner)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn compact_locked(&self, inner: &mut Inner) -> Result<()> {
+        let tmp_path = self.path.with_extension("compact");
+        let mut tmp = File::create(&tmp_path)?;
+        let mut index = HashMap::with_capacity(inner.index.len());
+        let mut end = 0u64;
+
+        for (key, location) in &inner.index {
+            let mut record = vec![0u8; location.len];
+            inner.file.read_exact_at(&mut record, location.offset)?;
+            tmp.write_all(&record)?;
+            index.insert(key.clone(), Location { offset: end, ..*location });
+            end += location.len as u64;
+        }
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        aof::sync_parent_dir(&self.path)?;
+
+        debug!("Compacted {}: {} -> {} byte(s)", self.path.display(), inner.end, end);
+        inner.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        inner.index = index;
+        inner.end = end;
+        inner.garbage = 0;
+        Ok(())
+    }
+
+    fn append(&self, inner: &mut Inner, record: &LogRecord) -> Result<(u64, usize)> {
+        let buf = frame(&bincode::serialize(record)?);
+        if let Err(e) = inner.file.write_all(&buf) {
+            // drop a partial record so later offsets stay right
+            let _ = inner.file.set_len(inner.end);
+            return Err(e.into());
+        }
+        let offset = inner.end;
+        inner.end += buf.len() as u64;
+        Ok((offset, buf.len()))
+    }
+
+    fn maybe_compact(&self, inner: &mut Inner) {
+        if inner.garbage >= MIN_COMPACTION_GARBAGE && inner.garbage > inner.end - inner.garbage {
+            if let Err(e) = self.compact_locked(inner) {
+                warn!("Failed to compact {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+impl Inner {
+    // points `key` at `location` (or removes it) and updates size and garbage
+    fn replace(&mut self, key: String, location: Option<Location>) {
+        let old = match location {
+            Some(location) => {
+                self.size += location.size;
+                self.index.insert(key, location)
+            }
+            None => self.index.remove(&key),
+        };
+        if let Some(old) = old {
+            self.size -= old.size;
+            self.garbage += old.len as u64;
+        }
+    }
+}
+
+impl StorageBackend for LogStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let inner = self.inner.read().unwrap();
+        let Some(location) = inner.index.get(key) else {
+            return Ok(None);
+        };
+
+        let mut buf = vec![0u8; location.len];
+        inner.file.read_exact_at(&mut buf, location.offset)?;
+        let record: LogRecord = bincode::deserialize(&buf[RECORD_HEADER_SIZE..])?;
+        match record.value {
+            Some(value) if record.key == key => Ok(Some(value.into_owned())),
+            _ => Err(ServerError::Storage(format!(
+                "Index for '{}' points at the wrong record in {}",
+                key,
+                self.path.display()
+            ))),
+        }
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        let size = entry_size(key, &value);
+        let old_size = inner.index.get(key).map_or(0, |old| old.size);
+        if inner.size - old_size + size > self.max_size {
+            return Err(ServerError::Storage("Storage capacity exceeded".into()));
+        }
+
+        let record = LogRecord {
+            key: Cow::Borrowed(key),
+            value: Some(Cow::Borrowed(&value)),
+        };
+        let (offset, len) = self.append(&mut inner, &record)?;
+        inner.replace(key.to_string(), Some(Location { offset, len, size }));
+        self.maybe_compact(&mut inner);
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        if !inner.index.contains_key(key) {
+            return Ok(());
+        }
+
+        let record = LogRecord {
+            key: Cow::Borrowed(key),
+            value: None,
+        };
+        let (_, len) = self.append(&mut inner, &record)?;
+        inner.replace(key.to_string(), None);
+        inner.garbage += len as u64;
+        self.maybe_compact(&mut inner);
+        Ok(())
+    }
+
+    fn list_keys(&self) -> Result<Vec<String>> {
+        Ok(self.inner.read().unwrap().index.keys().cloned().collect())
+    }
+
+    fn current_size(&self) -> u64 {
+        self.inner.read().unwrap().size
+    }
+
+    fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    fn entry_count(&self) -> usize {
+        self.inner.read().unwrap().index.len()
+    }
+}
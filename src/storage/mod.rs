@@ -1,69 +1,692 @@
+mod aof;
+mod backend;
+mod btree;
+mod eviction;
+mod faulty;
+mod log_store;
+mod scan;
+mod snapshot;
+mod transaction;
+mod watch;
+
+pub use aof::{AppendOnlyLog, FsyncPolicy};
+pub use backend::StorageBackend;
+pub use btree::BTreeStore;
+pub use faulty::FaultyBackend;
+pub use log_store::LogStore;
+pub use scan::{ScanCursor, ScanFilter, ScanPage, DEFAULT_SCAN_COUNT, MAX_SCAN_COUNT};
+pub(crate) use scan::glob_match;
+pub use eviction::EvictionPolicy;
+pub use snapshot::{SnapshotConfig, SnapshotInfo};
+pub use transaction::{TxOp, TxOpResult, TxOutcome};
+pub use watch::{KeyEvent, KeyEventKind, KeyListener};
+
+use dashmap::mapref::entry::Entry as MapEntry;
 use dashmap::DashMap;
+use crate::config::ServerConfig;
 use crate::error::{Result, ServerError};
-use std::sync::atomic::{AtomicU64, Ordering};
+use aof::AofRecord;
+use scan::ScanSnapshots;
+use snapshot::SnapshotTrigger;
+use log::{debug, info, warn};
+use std::borrow::Cow;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Bytes charged per entry on top of its key and value, approximating the
+/// map slot, the key and value headers and the expiry/access metadata.
+pub const ENTRY_OVERHEAD: u64 = std::mem::size_of::<(String, Entry)>() as u64;
+
+/// Bytes an entry counts against `max_size`.
+pub fn entry_size(key: &str, value: &[u8]) -> u64 {
+    key.len() as u64 + value.len() as u64 + ENTRY_OVERHEAD
+}
+
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+    version: u64,
+    // milliseconds since the store was created, for LRU
+    last_access: AtomicU64,
+    // access count, for LFU
+    hits: AtomicU32,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    fn size(&self, key: &str) -> u64 {
+        entry_size(key, &self.value)
+    }
+
+    fn touch(&self, tick: u64) {
+        self.last_access.store(tick, Ordering::Relaxed);
+        let _ = self.hits.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |hits| hits.checked_add(1));
+    }
+}
+
+/// Counters are stored as the ASCII decimal text of an `i64`, e.g. `b"-42"`.
+pub fn encode_integer(n: i64) -> Vec<u8> {
+    n.to_string().into_bytes()
+}
+
+/// Parses a value written by [`encode_integer`]; anything else, including
+/// whitespace or a leading `+`, is [`ServerError::NotAnInteger`].
+pub fn decode_integer(value: &[u8]) -> Result<i64> {
+    std::str::from_utf8(value)
+        .ok()
+        .filter(|text| !text.starts_with('+'))
+        .and_then(|text| text.parse().ok())
+        .ok_or(ServerError::NotAnInteger)
+}
+
+/// Remaining lifetime of a key as reported by [`KeyValueStore::ttl`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyTtl {
+    Missing,
+    NoExpiry,
+    Remaining(Duration),
+}
+
+impl KeyTtl {
+    /// Wire encoding: remaining milliseconds, `-1` for no expiry, `-2` for a missing key.
+    pub fn to_millis(self) -> i64 {
+        match self {
+            KeyTtl::Missing => -2,
+            KeyTtl::NoExpiry => -1,
+            KeyTtl::Remaining(remaining) => remaining.as_millis().min(i64::MAX as u128) as i64,
+        }
+    }
+
+    pub fn from_millis(millis: i64) -> Self {
+        match millis {
+            -1 => KeyTtl::NoExpiry,
+            m if m < 0 => KeyTtl::Missing,
+            m => KeyTtl::Remaining(Duration::from_millis(m as u64)),
+        }
+    }
+}
+
+/// Precondition of a conditional write, checked under the key's lock so no
+/// other write can slip in between the check and the write.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WriteCondition {
+    IfAbsent,
+    IfPresent,
+    /// The key exists with this version.
+    IfVersion(u64),
+    /// The key exists with exactly this value.
+    IfValue(Vec<u8>),
+}
+
+impl WriteCondition {
+    // an expired entry counts as absent
+    fn holds(&self, existing: Option<&Entry>, now: Instant) -> bool {
+        match (self, existing.filter(|entry| !entry.is_expired(now))) {
+            (WriteCondition::IfAbsent, existing) => existing.is_none(),
+            (_, None) => false,
+            (WriteCondition::IfPresent, Some(_)) => true,
+            (WriteCondition::IfVersion(version), Some(entry)) => entry.version == *version,
+            (WriteCondition::IfValue(value), Some(entry)) => entry.value == *value,
+        }
+    }
+}
+
+/// A value together with the version it was written at.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Versioned {
+    pub value: Vec<u8>,
+    pub version: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreStats {
+    pub entries: u64,
+    pub size: u64,
+    pub max_size: u64,
+    pub eviction_policy: EvictionPolicy,
+    pub evictions: u64,
+    pub expirations: u64,
+}
+
+impl std::fmt::Display for StoreStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Store Stats:\n\
+             Entries: {}\n\
+             Size: {} / {} bytes\n\
+             Eviction Policy: {}\n\
+             Evictions: {}\n\
+             Expirations: {}\n",
+            self.entries,
+            self.size,
+            self.max_size,
+            self.eviction_policy,
+            self.evictions,
+            self.expirations
+        )
+    }
+}
 
 pub struct KeyValueStore {
-    data: DashMap<String, Vec<u8>>,
+    data: DashMap<String, Entry>,
     size: AtomicU64,
     max_size: u64,
+    policy: EvictionPolicy,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+    epoch: Instant,
+    random_state: RandomState,
+    random_counter: AtomicU64,
+    next_version: AtomicU64,
+    aof: Option<Arc<AppendOnlyLog>>,
+    // serialises snapshots; holds the timestamp in the newest snapshot's name
+    snapshot_lock: Mutex<u64>,
+    snapshot_trigger: SnapshotTrigger,
+    snapshot_threshold: AtomicU64,
+    writes_since_snapshot: AtomicU64,
+    listener: RwLock<Option<Arc<dyn KeyListener>>>,
+    change_seq: AtomicU64,
+    scans: ScanSnapshots,
 }
 
 impl KeyValueStore {
     pub fn new(max_size: u64) -> Self {
+        Self::with_eviction_policy(max_size, EvictionPolicy::NoEviction)
+    }
+
+    pub fn with_eviction_policy(max_size: u64, policy: EvictionPolicy) -> Self {
         Self {
             data: DashMap::new(),
             size: AtomicU64::new(0),
             max_size,
+            policy,
+            evictions: AtomicU64::new(0),
+            expirations: AtomicU64::new(0),
+            epoch: Instant::now(),
+            random_state: RandomState::new(),
+            random_counter: AtomicU64::new(0),
+            next_version: AtomicU64::new(initial_version()),
+            aof: None,
+            snapshot_lock: Mutex::new(0),
+            snapshot_trigger: SnapshotTrigger::default(),
+            snapshot_threshold: AtomicU64::new(0),
+            writes_since_snapshot: AtomicU64::new(0),
+            listener: RwLock::new(None),
+            change_seq: AtomicU64::new(0),
+            scans: ScanSnapshots::default(),
         }
     }
 
+    /// Opens a store whose mutations are recorded in the append-only log at
+    /// `path`, first replaying whatever the log already holds on top of the
+    /// snapshot it was compacted into, if any.
+    pub fn with_aof(
+        max_size: u64,
+        policy: EvictionPolicy,
+        path: impl AsRef<Path>,
+        fsync: FsyncPolicy,
+    ) -> Result<Self> {
+        let mut store = Self::with_eviction_policy(max_size, policy);
+        store.attach_aof(path.as_ref(), fsync, None)?;
+        Ok(store)
+    }
+
+    /// Builds the store described by `config`. If `aof_enabled` is set, the
+    /// append-only log is replayed on top of the snapshot it was compacted
+    /// into; a log that was never compacted starts from the newest snapshot
+    /// instead when snapshots are enabled, as does a store without a log.
+    pub fn from_config(config: &ServerConfig) -> Result<Self> {
+        let mut store = Self::with_eviction_policy(config.max_storage_size, config.eviction_policy);
+
+        let latest = match config.snapshot_enabled {
+            true => Self::latest_snapshot(&config.snapshot_dir)?,
+            false => None,
+        };
+        if config.aof_enabled {
+            store.attach_aof(&config.aof_path, config.aof_fsync, latest.as_deref())?;
+        } else if let Some(path) = latest {
+            store.load_snapshot(&path)?;
+        }
+        Ok(store)
+    }
+
+    // `fallback` is loaded before replay when the log names no base snapshot
+    fn attach_aof(&mut self, path: &Path, fsync: FsyncPolicy, fallback: Option<&Path>) -> Result<()> {
+        let (log, records) = AppendOnlyLog::open(path, fsync)?;
+
+        let base = match records.first() {
+            Some(AofRecord::Base { snapshot }) => Some(snapshot.to_path_buf()),
+            _ => None,
+        };
+        if let Some(base) = base.as_deref().filter(|base| !base.exists()) {
+            return Err(ServerError::Storage(format!(
+                "{} was compacted into snapshot {}, which is missing",
+                path.display(),
+                base.display()
+            )));
+        }
+        if let Some(snapshot) = base.as_deref().or(fallback) {
+            self.load_snapshot(snapshot)?;
+        }
+
+        let replayed = records.len();
+        for record in records {
+            self.replay(record);
+        }
+        info!(
+            "Replayed {} record(s) from {}: {} key(s), {} byte(s)",
+            replayed,
+            path.display(),
+            self.entry_count(),
+            self.current_size()
+        );
+
+        let log = Arc::new(log);
+        if fsync == FsyncPolicy::EverySec {
+            AppendOnlyLog::spawn_syncer(&log);
+        }
+        self.aof = Some(log);
+        Ok(())
+    }
+
+    pub fn aof(&self) -> Option<&AppendOnlyLog> {
+        self.aof.as_deref()
+    }
+
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    /// Stores `value` without an expiry, clearing any TTL the key had.
     pub fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
-        let value_size = value.len() as u64;
+        self.insert(key, value, None)
+    }
 
-        // check if we're updating an existing key
-        if let Some(existing) = self.data.get(key) {
-            let existing_size = existing.len() as u64;
-            let size_diff = value_size as i64 - existing_size as i64;
+    pub fn set_with_ttl(&self, key: &str, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.insert(key, value, Some(Instant::now() + ttl))
+    }
 
-            if size_diff > 0 &&
-               self.size.load(Ordering::Relaxed) + size_diff as u64 > self.max_size {
-                return Err(ServerError::Storage("Storage capacity exceeded".into()));
+    /// Stores `value` without an expiry if `condition` holds. Returns the new
+    /// version, or `None` if the condition did not hold and nothing changed.
+    pub fn set_if(&self, key: &str, value: Vec<u8>, condition: &WriteCondition) -> Result<Option<u64>> {
+        self.insert_if(key, value, None, Some(condition))
+    }
+
+    /// Returns the value and the version it was written at.
+    pub fn get_versioned(&self, key: &str) -> Result<Option<Versioned>> {
+        let now = Instant::now();
+        match self.data.get(key) {
+            Some(entry) if !entry.is_expired(now) => {
+                entry.touch(self.tick());
+                Ok(Some(Versioned {
+                    value: entry.value.clone(),
+                    version: entry.version,
+                }))
+            }
+            Some(entry) => {
+                drop(entry);
+                self.remove_expired(key, now);
+                Ok(None)
             }
+            None => Ok(None),
+        }
+    }
 
-            self.size.fetch_add(size_diff as u64, Ordering::Relaxed);
-        } else {
-            // new key
-            if self.size.load(Ordering::Relaxed) + value_size > self.max_size {
-                return Err(ServerError::Storage("Storage capacity exceeded".into()));
+    /// Adds `delta` to the integer at `key` and returns the result. A missing
+    /// key counts as `0`; an existing TTL is kept.
+    pub fn incr_by(&self, key: &str, delta: i64) -> Result<i64> {
+        loop {
+            let now = Instant::now();
+            let current = self
+                .data
+                .get(key)
+                .filter(|entry| !entry.is_expired(now))
+                .map(|entry| (decode_integer(&entry.value), entry.version, entry.expires_at));
+            let (n, condition, expires_at) = match current {
+                Some((n, version, expires_at)) => (n?, WriteCondition::IfVersion(version), expires_at),
+                None => (0, WriteCondition::IfAbsent, None),
+            };
+
+            let next = n.checked_add(delta).ok_or(ServerError::NotAnInteger)?;
+            // lost a race with another write to the key; read it again
+            if self.insert_if(key, encode_integer(next), expires_at, Some(&condition))?.is_some() {
+                return Ok(next);
             }
+        }
+    }
 
-            self.size.fetch_add(value_size, Ordering::Relaxed);
+    fn insert(&self, key: &str, value: Vec<u8>, expires_at: Option<Instant>) -> Result<()> {
+        self.insert_if(key, value, expires_at, None).map(|_| ())
+    }
+
+    // Every increase of `size` goes through `try_reserve`, so it never exceeds
+    // `max_size`; the final adjustment happens under the entry's shard lock so
+    // a concurrent overwrite or delete of the same key cannot make it drift.
+    // `condition` is checked under that same lock before any room is made, so
+    // a write whose condition fails never evicts or hits the capacity limit.
+    fn insert_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expires_at: Option<Instant>,
+        condition: Option<&WriteCondition>,
+    ) -> Result<Option<u64>> {
+        let new_size = entry_size(key, &value);
+        let value_len = value.len() as u64;
+        let version = self.next_version.fetch_add(1, Ordering::Relaxed);
+        let entry = Entry {
+            value,
+            expires_at,
+            version,
+            last_access: AtomicU64::new(self.tick()),
+            hits: AtomicU32::new(0),
+        };
+        let now = Instant::now();
+        // bytes reserved by an earlier pass that had to make room first
+        let mut reserved = 0;
+
+        loop {
+            match self.data.entry(key.to_string()) {
+                MapEntry::Occupied(mut occupied) => {
+                    if !condition.is_none_or(|condition| condition.holds(Some(occupied.get()), now)) {
+                        drop(occupied);
+                        self.release(reserved);
+                        return Ok(None);
+                    }
+                    let old_size = occupied.get().size(key);
+                    let growth = new_size.saturating_sub(old_size);
+                    if growth > reserved && !self.try_reserve(growth - reserved) {
+                        // evicting locks other shards, so make room with this one unlocked
+                        drop(occupied);
+                        if let Err(e) = self.reserve(&[key], growth - reserved) {
+                            self.release(reserved);
+                            return Err(e);
+                        }
+                        reserved = growth;
+                        continue;
+                    }
+                    if let Err(e) = self.log_set(key, &entry) {
+                        self.release(reserved.max(growth));
+                        return Err(e);
+                    }
+                    // settle the reservation to exactly new_size - old_size
+                    self.release(reserved.max(growth) + old_size - new_size);
+                    occupied.insert(entry);
+                    self.notify(key, KeyEventKind::Set { value_len });
+                }
+                MapEntry::Vacant(vacant) => {
+                    if !condition.is_none_or(|condition| condition.holds(None, now)) {
+                        drop(vacant);
+                        self.release(reserved);
+                        return Ok(None);
+                    }
+                    if new_size > reserved && !self.try_reserve(new_size - reserved) {
+                        drop(vacant);
+                        if let Err(e) = self.reserve(&[key], new_size - reserved) {
+                            self.release(reserved);
+                            return Err(e);
+                        }
+                        reserved = new_size;
+                        continue;
+                    }
+                    if let Err(e) = self.log_set(key, &entry) {
+                        self.release(reserved.max(new_size));
+                        return Err(e);
+                    }
+                    // the reference `insert` returns keeps the shard locked
+                    let inserted = vacant.insert(entry);
+                    self.notify(key, KeyEventKind::Set { value_len });
+                    drop(inserted);
+                }
+            }
+            self.note_write();
+            return Ok(Some(version));
         }
+    }
 
-        self.data.insert(key.to_string(), value);
+    /// Reserves `additional` bytes, evicting keys other than `keep` if the
+    /// policy allows.
+    fn reserve(&self, keep: &[&str], additional: u64) -> Result<()> {
+        while !self.try_reserve(additional) {
+            if self.policy == EvictionPolicy::NoEviction || !self.evict_one(keep) {
+                return Err(ServerError::Storage("Storage capacity exceeded".into()));
+            }
+        }
         Ok(())
     }
 
+    fn try_reserve(&self, additional: u64) -> bool {
+        self.size
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |size| {
+                size.checked_add(additional).filter(|&total| total <= self.max_size)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: u64) {
+        if bytes > 0 {
+            self.size.fetch_sub(bytes, Ordering::AcqRel);
+        }
+    }
+
+    fn log_set(&self, key: &str, entry: &Entry) -> Result<()> {
+        self.log(|| AofRecord::Set {
+            key: Cow::Borrowed(key),
+            value: Cow::Borrowed(&entry.value),
+            expires_at_ms: entry.expires_at.map(aof::to_unix_ms),
+        })
+    }
+
+    // called with the key's shard locked, so the log order matches the order
+    // mutations of that key are applied in
+    fn log<'a>(&self, record: impl FnOnce() -> AofRecord<'a>) -> Result<()> {
+        match &self.aof {
+            Some(aof) => aof.append(&record()),
+            None => Ok(()),
+        }
+    }
+
+    fn replay(&self, record: AofRecord) {
+        let result = match record {
+            AofRecord::Set { key, value, expires_at_ms } => match expires_at_ms.map(aof::from_unix_ms) {
+                Some(None) => self.delete(&key),
+                Some(expires_at) => self.insert(&key, value.into_owned(), expires_at),
+                None => self.insert(&key, value.into_owned(), None),
+            },
+            AofRecord::Delete { key } => self.delete(&key),
+            AofRecord::Expire { key, expires_at_ms } => match aof::from_unix_ms(expires_at_ms) {
+                Some(at) => self.expire(&key, at.saturating_duration_since(Instant::now())).map(|_| ()),
+                None => self.delete(&key),
+            },
+            AofRecord::Persist { key } => self.persist(&key).map(|_| ()),
+            AofRecord::Batch { records } => {
+                records.into_iter().for_each(|record| self.replay(record));
+                Ok(())
+            }
+            // loaded by `attach_aof` before replay starts
+            AofRecord::Base { .. } => Ok(()),
+        };
+
+        if let Err(e) = result {
+            warn!("Skipping record during replay: {}", e);
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
     pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
-        Ok(self.data.get(key).map(|v| v.clone()))
+        let now = Instant::now();
+        if let Some(entry) = self.data.get(key) {
+            if !entry.is_expired(now) {
+                entry.touch(self.tick());
+                return Ok(Some(entry.value.clone()));
+            }
+        } else {
+            return Ok(None);
+        }
+
+        // expired: reclaim it now rather than waiting for the sweeper
+        self.remove_expired(key, now);
+        Ok(None)
     }
 
     pub fn delete(&self, key: &str) -> Result<()> {
-        if let Some((_, value)) = self.data.remove(key) {
-            self.size.fetch_sub(value.len() as u64, Ordering::Relaxed);
+        if let MapEntry::Occupied(occupied) = self.data.entry(key.to_string()) {
+            self.log(|| AofRecord::Delete { key: Cow::Borrowed(key) })?;
+            self.notify(key, KeyEventKind::Delete);
+            let (key, entry) = occupied.remove_entry();
+            self.release(entry.size(&key));
+            self.note_write();
         }
         Ok(())
     }
 
+    /// Sets a TTL on an existing key. Returns `false` if the key does not exist.
+    pub fn expire(&self, key: &str, ttl: Duration) -> Result<bool> {
+        let now = Instant::now();
+        match self.data.get_mut(key) {
+            Some(mut entry) if !entry.is_expired(now) => {
+                let expires_at = now + ttl;
+                self.log(|| AofRecord::Expire {
+                    key: Cow::Borrowed(key),
+                    expires_at_ms: aof::to_unix_ms(expires_at),
+                })?;
+                entry.expires_at = Some(expires_at);
+                self.note_write();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Removes the TTL from a key. Returns `false` if the key does not exist or
+    /// had no TTL.
+    pub fn persist(&self, key: &str) -> Result<bool> {
+        let now = Instant::now();
+        match self.data.get_mut(key) {
+            Some(mut entry) if !entry.is_expired(now) && entry.expires_at.is_some() => {
+                self.log(|| AofRecord::Persist { key: Cow::Borrowed(key) })?;
+                entry.expires_at = None;
+                self.note_write();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub fn ttl(&self, key: &str) -> Result<KeyTtl> {
+        let now = Instant::now();
+        Ok(match self.data.get(key) {
+            Some(entry) if !entry.is_expired(now) => match entry.expires_at {
+                Some(at) => KeyTtl::Remaining(at - now),
+                None => KeyTtl::NoExpiry,
+            },
+            _ => KeyTtl::Missing,
+        })
+    }
+
     pub fn list_keys(&self) -> Result<Vec<String>> {
-        Ok(self.data.iter().map(|r| r.key().clone()).collect())
+        let now = Instant::now();
+        Ok(self
+            .data
+            .iter()
+            .filter(|r| !r.value().is_expired(now))
+            .map(|r| r.key().clone())
+            .collect())
     }
 
+    /// Bytes charged against `max_size`: [`entry_size`] summed over every entry,
+    /// plus reservations of writes still in progress.
     pub fn current_size(&self) -> u64 {
-        self.size.load(Ordering::Relaxed)
+        self.size.load(Ordering::Acquire)
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
     }
 
     pub fn entry_count(&self) -> usize {
         self.data.len()
     }
+
+    pub fn stats(&self) -> StoreStats {
+        StoreStats {
+            entries: self.data.len() as u64,
+            size: self.current_size(),
+            max_size: self.max_size,
+            eviction_policy: self.policy,
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expirations: self.expirations.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Removes every expired entry and returns how many were reclaimed.
+    pub fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut purged = 0;
+        self.data.retain(|key, entry| {
+            if entry.is_expired(now) {
+                self.notify(key, KeyEventKind::Delete);
+                self.release(entry.size(key));
+                purged += 1;
+                false
+            } else {
+                true
+            }
+        });
+        self.expirations.fetch_add(purged as u64, Ordering::Relaxed);
+        purged
+    }
+
+    /// Starts a thread that purges expired entries every `interval`. The thread
+    /// exits once the store is dropped. A zero `interval` starts nothing, leaving
+    /// expired entries to be reclaimed when they are next accessed.
+    pub fn spawn_sweeper(store: &Arc<Self>, interval: Duration) -> Option<JoinHandle<()>> {
+        if interval.is_zero() {
+            debug!("Expiry sweeper disabled");
+            return None;
+        }
+        let store: Weak<Self> = Arc::downgrade(store);
+        Some(std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(store) = store.upgrade() else { break };
+            let purged = store.purge_expired();
+            if purged > 0 {
+                debug!("Expired {} key(s)", purged);
+            }
+        }))
+    }
+
+    fn remove_expired(&self, key: &str, now: Instant) {
+        let expired = |key: &String, entry: &Entry| {
+            let expired = entry.is_expired(now);
+            if expired {
+                self.notify(key, KeyEventKind::Delete);
+            }
+            expired
+        };
+        if let Some((key, entry)) = self.data.remove_if(key, expired) {
+            self.release(entry.size(&key));
+            self.expirations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Versions only need to be unique per key and to change on every write.
+// Starting from the clock keeps them increasing across restarts, so a version
+// read before a restart can't match a different value written after it.
+fn initial_version() -> u64 {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64);
+    millis << 20
 }
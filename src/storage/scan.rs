@@ -0,0 +1,351 @@
+use super::KeyValueStore;
+use crate::error::{Result, ServerError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bound on the count hint, so a single page stays a reasonable frame.
+pub const MAX_SCAN_COUNT: usize = 10_000;
+pub const DEFAULT_SCAN_COUNT: usize = 10;
+
+// KeyValueStore positions are (shard index << HASH_BITS) | top HASH_BITS of the key hash
+const HASH_BITS: u32 = 48;
+const HASH_MASK: u64 = (1 << HASH_BITS) - 1;
+
+/// Opaque scan position. Start with [`ScanCursor::start`] and pass back the
+/// cursor of each page until it [`is_done`](ScanCursor::is_done).
+///
+/// The encoding is backend specific and only valid for the process that
+/// issued it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanCursor(pub Vec<u8>);
+
+impl ScanCursor {
+    pub fn start() -> Self {
+        Self::default()
+    }
+
+    /// An empty cursor both starts a scan and marks it finished.
+    pub fn is_done(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Restricts the keys a scan returns.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScanFilter {
+    Prefix(String),
+    /// `*`, `?`, `[abc]`, `[a-z]`, `[!abc]` and `\` escapes.
+    Glob(String),
+}
+
+impl ScanFilter {
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            ScanFilter::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            ScanFilter::Glob(pattern) => glob_match(pattern.as_bytes(), key.as_bytes()),
+        }
+    }
+
+    /// Literal prefix every matching key starts with, for backends that can
+    /// seek to it.
+    pub fn literal_prefix(&self) -> &str {
+        match self {
+            ScanFilter::Prefix(prefix) => prefix,
+            ScanFilter::Glob(pattern) => {
+                let end = pattern.find(['*', '?', '[', '\\']).unwrap_or(pattern.len());
+                &pattern[..end]
+            }
+        }
+    }
+}
+
+/// One page of a scan. The count hint bounds how many keys are examined, not
+/// how many match, so a page can be empty while the scan is still running.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanPage {
+    pub keys: Vec<String>,
+    pub cursor: ScanCursor,
+}
+
+pub(super) fn clamp_count(count: usize) -> usize {
+    match count {
+        0 => DEFAULT_SCAN_COUNT,
+        count => count.min(MAX_SCAN_COUNT),
+    }
+}
+
+pub(super) fn invalid_cursor() -> ServerError {
+    ServerError::Storage("Invalid scan cursor".into())
+}
+
+// Cursor for backends that resume after the last key examined. The marker
+// byte keeps the cursor non-empty even when that key is "".
+pub(super) fn key_cursor(key: &str) -> ScanCursor {
+    let mut bytes = Vec::with_capacity(key.len() + 1);
+    bytes.push(b'k');
+    bytes.extend_from_slice(key.as_bytes());
+    ScanCursor(bytes)
+}
+
+pub(super) fn cursor_key(cursor: &ScanCursor) -> Result<Option<&str>> {
+    match cursor.0.split_first() {
+        None => Ok(None),
+        Some((b'k', key)) => std::str::from_utf8(key).map(Some).map_err(|_| invalid_cursor()),
+        Some(_) => Err(invalid_cursor()),
+    }
+}
+
+// scans whose shard snapshot is kept between pages; older ones are rebuilt on demand
+const MAX_SCAN_SNAPSHOTS: usize = 16;
+// snapshots of scans abandoned for this long are dropped
+const SCAN_SNAPSHOT_IDLE: Duration = Duration::from_secs(60);
+
+// the keys of one shard sorted by scan hash
+type SortedShard = Arc<Vec<(u64, String)>>;
+
+struct ShardSnapshot {
+    shard: usize,
+    keys: SortedShard,
+    last_used: Instant,
+}
+
+/// Sorted copies of the shards running scans are walking, so a page is a
+/// binary search into the copy rather than a pass over the shard. Each copy
+/// is taken when its scan reaches the shard; dropping one only costs the next
+/// page a rebuild.
+#[derive(Default)]
+pub(super) struct ScanSnapshots {
+    next_id: AtomicU64,
+    snapshots: Mutex<HashMap<u64, ShardSnapshot>>,
+    keys_visited: AtomicU64,
+}
+
+impl ScanSnapshots {
+    fn take(&self, scan: u64, shard: usize) -> Option<SortedShard> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let snapshot = snapshots.get_mut(&scan).filter(|snapshot| snapshot.shard == shard)?;
+        snapshot.last_used = Instant::now();
+        Some(snapshot.keys.clone())
+    }
+
+    fn keep(&self, scan: u64, shard: usize, keys: SortedShard) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let now = Instant::now();
+        snapshots.retain(|_, snapshot| now.duration_since(snapshot.last_used) < SCAN_SNAPSHOT_IDLE);
+        if snapshots.len() >= MAX_SCAN_SNAPSHOTS && !snapshots.contains_key(&scan) {
+            let oldest = snapshots.iter().min_by_key(|(_, snapshot)| snapshot.last_used).map(|(&id, _)| id);
+            if let Some(oldest) = oldest {
+                snapshots.remove(&oldest);
+            }
+        }
+        snapshots.insert(
+            scan,
+            ShardSnapshot {
+                shard,
+                keys,
+                last_used: now,
+            },
+        );
+    }
+
+    fn forget(&self, scan: u64) {
+        self.snapshots.lock().unwrap().remove(&scan);
+    }
+}
+
+impl KeyValueStore {
+    /// Returns the next page of keys after `cursor`.
+    ///
+    /// Keys are visited shard by shard in order of their hash, which does not
+    /// change while the store runs, so every key present for the whole scan
+    /// is returned at least once no matter what is inserted or removed in
+    /// between. Keys added or removed during the scan may or may not appear.
+    ///
+    /// The first page in each shard sorts a copy of the shard's keys; later
+    /// pages resume from that copy in `O(count + log n)`.
+    pub fn scan(&self, cursor: &ScanCursor, count: usize, filter: Option<&ScanFilter>) -> Result<ScanPage> {
+        let (scan, after) = match cursor.0.as_slice() {
+            [] => (self.scans.next_id.fetch_add(1, Ordering::Relaxed), None),
+            bytes if bytes.len() == 16 => (
+                u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+                Some(u64::from_be_bytes(bytes[8..].try_into().unwrap())),
+            ),
+            _ => return Err(invalid_cursor()),
+        };
+        let count = clamp_count(count);
+        let shards = self.data.shards();
+        let first_shard = after.map_or(0, |position| (position >> HASH_BITS) as usize);
+        if first_shard >= shards.len() {
+            return Err(invalid_cursor());
+        }
+
+        let now = Instant::now();
+        let mut keys = Vec::new();
+        let mut examined = 0;
+
+        for (index, shard) in shards.iter().enumerate().skip(first_shard) {
+            let floor = after
+                .filter(|position| (position >> HASH_BITS) as usize == index)
+                .map(|position| position & HASH_MASK);
+            let sorted = match self.scans.take(scan, index) {
+                Some(sorted) => sorted,
+                None => {
+                    let shard = shard.read();
+                    self.scans.keys_visited.fetch_add(shard.len() as u64, Ordering::Relaxed);
+                    let mut sorted: Vec<(u64, String)> =
+                        shard.keys().map(|key| (self.scan_hash(key), key.clone())).collect();
+                    sorted.sort_unstable();
+                    Arc::new(sorted)
+                }
+            };
+
+            let start = floor.map_or(0, |floor| sorted.partition_point(|&(hash, _)| hash <= floor));
+            let want = count - examined;
+            let mut end = (start + want).min(sorted.len());
+            let full = end - start == want;
+            // the cursor resumes strictly above the last hash, so keys sharing
+            // it must all be returned now
+            if full {
+                while sorted.get(end).is_some_and(|(hash, _)| *hash == sorted[end - 1].0) {
+                    end += 1;
+                }
+            }
+
+            let picked = &sorted[start..end];
+            examined += picked.len();
+            self.scans.keys_visited.fetch_add(picked.len() as u64, Ordering::Relaxed);
+            {
+                let shard = shard.read();
+                keys.extend(
+                    picked
+                        .iter()
+                        .map(|(_, key)| key)
+                        .filter(|key| shard.get(*key).is_some_and(|entry| !entry.get().is_expired(now)))
+                        .filter(|key| filter.is_none_or(|filter| filter.matches(key)))
+                        .cloned(),
+                );
+            }
+
+            if full {
+                let position = ((index as u64) << HASH_BITS) | sorted[end - 1].0;
+                self.scans.keep(scan, index, sorted);
+                let mut cursor = scan.to_be_bytes().to_vec();
+                cursor.extend_from_slice(&position.to_be_bytes());
+                return Ok(ScanPage {
+                    keys,
+                    cursor: ScanCursor(cursor),
+                });
+            }
+        }
+
+        self.scans.forget(scan);
+        Ok(ScanPage {
+            keys,
+            cursor: ScanCursor::start(),
+        })
+    }
+
+    /// Keys examined by scans so far, including those copied when a scan
+    /// enters a shard.
+    pub fn scan_keys_visited(&self) -> u64 {
+        self.scans.keys_visited.load(Ordering::Relaxed)
+    }
+
+    fn scan_hash(&self, key: &str) -> u64 {
+        self.random_state.hash_one(key) >> (64 - HASH_BITS)
+    }
+}
+
+/// Matches `text` against a glob `pattern`, backtracking only on the last `*`.
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    star = Some((p, t));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, text[t]) {
+                        if matched {
+                            p = next;
+                            t += 1;
+                            continue;
+                        }
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() && pattern[p + 1] == text[t] => {
+                    p += 2;
+                    t += 1;
+                    continue;
+                }
+                // an escape that doesn't match must not fall through to a literal `\`
+                b'\\' if p + 1 < pattern.len() => {}
+                c if c == text[t] => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        // mismatch: let the last `*` swallow one more byte
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// Matches `c` against the class starting at `pattern[start] == b'['`. Returns
+// whether it matched and the index after the class, or `None` if unterminated.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negated = matches!(pattern.get(i), Some(b'!') | Some(b'^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let mut lo = *pattern.get(i)?;
+        if lo == b']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+        if lo == b'\\' {
+            i += 1;
+            lo = *pattern.get(i)?;
+        }
+
+        if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|&hi| hi != b']') {
+            let hi = pattern[i + 2];
+            matched |= (lo..=hi).contains(&c);
+            i += 3;
+        } else {
+            matched |= lo == c;
+            i += 1;
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use super::aof::{self, frame};
+use super::KeyValueStore;
+use crate::config::ServerConfig;
+use crate::error::{Result, ServerError};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 8] = b"RTCPSNAP";
+const VERSION: u32 = 1;
+// a length of u32::MAX marks the footer, which holds the entry count
+const FOOTER_MARKER: u32 = u32::MAX;
+const SNAPSHOT_EXTENSION: &str = "snap";
+// how often the snapshot thread checks whether the store is still alive
+const IDLE_POLL: Duration = Duration::from_secs(1);
+
+/// When and where the background snapshot thread writes snapshots.
+#[derive(Debug, Clone)]
+pub struct SnapshotConfig {
+    pub dir: PathBuf,
+    pub retain: usize,
+    /// Take a snapshot this often; `None` disables the timer.
+    pub interval: Option<Duration>,
+    /// Take a snapshot after this many writes; `0` disables the threshold.
+    pub write_threshold: u64,
+}
+
+impl SnapshotConfig {
+    pub fn from_server_config(config: &ServerConfig) -> Self {
+        Self {
+            dir: config.snapshot_dir.clone(),
+            retain: config.snapshot_retain,
+            interval: (config.snapshot_interval_ms > 0)
+                .then(|| Duration::from_millis(config.snapshot_interval_ms)),
+            write_threshold: config.snapshot_write_threshold,
+        }
+    }
+}
+
+/// What a finished snapshot contains.
+#[derive(Debug, Clone)]
+pub struct SnapshotInfo {
+    pub path: PathBuf,
+    pub entries: u64,
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotEntry<'a> {
+    key: Cow<'a, str>,
+    value: Cow<'a, [u8]>,
+    expires_at_ms: Option<u64>,
+}
+
+/// Wakes the snapshot thread on request or once enough writes have piled up.
+#[derive(Default)]
+pub(super) struct SnapshotTrigger {
+    state: Mutex<TriggerState>,
+    wake: Condvar,
+}
+
+#[derive(Default)]
+struct TriggerState {
+    running: bool,
+    requested: bool,
+}
+
+impl SnapshotTrigger {
+    fn request(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.running {
+            state.requested = true;
+            self.wake.notify_one();
+        }
+        state.running
+    }
+}
+
+impl KeyValueStore {
+    /// Writes every live entry to a new snapshot in `dir`, then deletes all but
+    /// the newest `retain` snapshots. Each shard is only read-locked while its
+    /// own entries are written, so writers are never blocked for the whole dump.
+    /// If the store has an append-only log, the records the snapshot covers
+    /// are dropped from it afterwards.
+    pub fn snapshot_to(&self, dir: &Path, retain: usize) -> Result<SnapshotInfo> {
+        let mut last_stamp = self.snapshot_lock.lock().unwrap();
+        let started = Instant::now();
+        std::fs::create_dir_all(dir)?;
+
+        self.writes_since_snapshot.store(0, Ordering::Relaxed);
+        let aof_mark = self.aof.as_ref().map(|aof| aof.mark()).transpose()?;
+
+        // snapshots taken within one millisecond, or named by another store in
+        // `dir`, must not replace each other, so stamps only ever move forward
+        let unix_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let mut stamp = unix_ms.max(*last_stamp + 1);
+        let path = loop {
+            let path = dir.join(format!("snapshot-{:016}.{}", stamp, SNAPSHOT_EXTENSION));
+            if !path.exists() {
+                break path;
+            }
+            stamp += 1;
+        };
+        *last_stamp = stamp;
+        let tmp_path = path.with_extension("tmp");
+
+        let entries = match self.write_snapshot(&tmp_path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        };
+        std::fs::rename(&tmp_path, &path)?;
+        aof::sync_parent_dir(&path)?;
+
+        if let (Some(aof), Some(mark)) = (&self.aof, aof_mark) {
+            let dropped = aof.compact(mark, &path)?;
+            debug!("Compacted {}, dropped {} byte(s)", aof.path().display(), dropped);
+        }
+        prune_snapshots(dir, retain)?;
+
+        let info = SnapshotInfo {
+            bytes: std::fs::metadata(&path)?.len(),
+            path,
+            entries,
+            duration: started.elapsed(),
+        };
+        info!(
+            "Wrote snapshot {} ({} entries, {} bytes) in {:?}",
+            info.path.display(),
+            info.entries,
+            info.bytes,
+            info.duration
+        );
+        Ok(info)
+    }
+
+    fn write_snapshot(&self, path: &Path) -> Result<u64> {
+        let file = File::create(path)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+
+        let now = Instant::now();
+        let mut entries = 0u64;
+        for item in self.data.iter() {
+            let entry = item.value();
+            if entry.is_expired(now) {
+                continue;
+            }
+            let record = SnapshotEntry {
+                key: Cow::Borrowed(item.key()),
+                value: Cow::Borrowed(&entry.value),
+                expires_at_ms: entry.expires_at.map(aof::to_unix_ms),
+            };
+            writer.write_all(&frame(&bincode::serialize(&record)?))?;
+            entries += 1;
+        }
+
+        writer.write_all(&FOOTER_MARKER.to_le_bytes())?;
+        writer.write_all(&entries.to_le_bytes())?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        Ok(entries)
+    }
+
+    /// Loads the entries of the snapshot at `path` into the store. Entries
+    /// that expired since the snapshot was taken are skipped.
+    pub fn load_snapshot(&self, path: &Path) -> Result<u64> {
+        let corrupt = |reason: &str| {
+            ServerError::Storage(format!("Invalid snapshot {}: {}", path.display(), reason))
+        };
+
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header).map_err(|_| corrupt("missing header"))?;
+        if &header[..8] != MAGIC {
+            return Err(corrupt("bad magic"));
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != VERSION {
+            return Err(corrupt(&format!("unsupported version {}", version)));
+        }
+
+        let mut loaded = 0u64;
+        let mut read = 0u64;
+        loop {
+            let mut word = [0u8; 4];
+            reader.read_exact(&mut word).map_err(|_| corrupt("truncated"))?;
+            let len = u32::from_le_bytes(word);
+
+            if len == FOOTER_MARKER {
+                let mut count = [0u8; 8];
+                reader.read_exact(&mut count).map_err(|_| corrupt("truncated footer"))?;
+                if u64::from_le_bytes(count) != read {
+                    return Err(corrupt("entry count mismatch"));
+                }
+                break;
+            }
+
+            reader.read_exact(&mut word).map_err(|_| corrupt("truncated"))?;
+            let crc = u32::from_le_bytes(word);
+            let mut payload = vec![0u8; len as usize];
+            reader.read_exact(&mut payload).map_err(|_| corrupt("truncated"))?;
+            if crc32fast::hash(&payload) != crc {
+                return Err(corrupt("checksum mismatch"));
+            }
+
+            let entry: SnapshotEntry = bincode::deserialize(&payload)?;
+            read += 1;
+            let expires_at = match entry.expires_at_ms.map(aof::from_unix_ms) {
+                Some(None) => continue,
+                Some(at) => at,
+                None => None,
+            };
+            match self.insert(&entry.key, entry.value.into_owned(), expires_at) {
+                Ok(()) => loaded += 1,
+                Err(e) => warn!("Skipping '{}' from snapshot: {}", entry.key, e),
+            }
+        }
+
+        info!("Loaded {} of {} entries from snapshot {}", loaded, read, path.display());
+        Ok(loaded)
+    }
+
+    /// Returns the newest snapshot in `dir`, if any.
+    pub fn latest_snapshot(dir: &Path) -> Result<Option<PathBuf>> {
+        Ok(list_snapshots(dir)?.pop())
+    }
+
+    /// Asks the snapshot thread to take a snapshot now. Fails if no snapshot
+    /// thread is running.
+    pub fn request_snapshot(&self) -> Result<()> {
+        if self.snapshot_trigger.request() {
+            Ok(())
+        } else {
+            Err(ServerError::Storage("Snapshots are not enabled".into()))
+        }
+    }
+
+    pub(super) fn note_write(&self) {
+        let threshold = self.snapshot_threshold.load(Ordering::Relaxed);
+        let writes = self.writes_since_snapshot.fetch_add(1, Ordering::Relaxed) + 1;
+        if threshold > 0 && writes == threshold {
+            self.snapshot_trigger.request();
+        }
+    }
+
+    /// Starts the thread that takes snapshots on request, on a timer and after
+    /// `write_threshold` writes. It exits once the store is dropped.
+    pub fn spawn_snapshotter(store: &Arc<Self>, config: SnapshotConfig) -> JoinHandle<()> {
+        store.snapshot_threshold.store(config.write_threshold, Ordering::Relaxed);
+        store.snapshot_trigger.state.lock().unwrap().running = true;
+
+        let store: Weak<Self> = Arc::downgrade(store);
+        std::thread::spawn(move || {
+            let mut next_timer = config.interval.map(|interval| Instant::now() + interval);
+            loop {
+                let Some(store) = store.upgrade() else { break };
+                let trigger = &store.snapshot_trigger;
+
+                let wait = next_timer
+                    .map_or(IDLE_POLL, |at| at.saturating_duration_since(Instant::now()).min(IDLE_POLL));
+                let requested = {
+                    let state = trigger.state.lock().unwrap();
+                    let (mut state, _) = trigger
+                        .wake
+                        .wait_timeout_while(state, wait, |state| !state.requested)
+                        .unwrap();
+                    std::mem::take(&mut state.requested)
+                };
+
+                let timer_due = next_timer.is_some_and(|at| at <= Instant::now());
+                if !requested && !timer_due {
+                    continue;
+                }
+                if let Some(interval) = config.interval {
+                    next_timer = Some(Instant::now() + interval);
+                }
+
+                if let Err(e) = store.snapshot_to(&config.dir, config.retain) {
+                    error!("Snapshot failed: {}", e);
+                }
+            }
+        })
+    }
+}
+
+fn list_snapshots(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let is_snapshot = path.extension().is_some_and(|ext| ext == SNAPSHOT_EXTENSION)
+            && path.file_name().is_some_and(|name| name.to_string_lossy().starts_with("snapshot-"));
+        if is_snapshot {
+            snapshots.push(path);
+        }
+    }
+    // names embed a zero-padded timestamp, so lexical order is age order
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+fn prune_snapshots(dir: &Path, retain: usize) -> Result<()> {
+    let snapshots = list_snapshots(dir)?;
+    let excess = snapshots.len().saturating_sub(retain.max(1));
+    for old in &snapshots[..excess] {
+        debug!("Removing old snapshot {}", old.display());
+        std::fs::remove_file(old)?;
+    }
+    Ok(())
+}
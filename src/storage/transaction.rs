@@ -0,0 +1,195 @@
+use super::aof::AofRecord;
+use super::{entry_size, Entry, KeyEventKind, KeyValueStore, WriteCondition};
+use crate::error::Result;
+use dashmap::SharedValue;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Instant;
+
+/// One operation of a transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TxOp {
+    /// Stores `value` without an expiry, like `STORE`.
+    Store { key: String, value: Vec<u8> },
+    Delete { key: String },
+    /// Aborts the transaction unless `condition` holds for `key` as it was
+    /// before the transaction.
+    Check { key: String, condition: WriteCondition },
+}
+
+impl TxOp {
+    pub fn key(&self) -> &str {
+        match self {
+            TxOp::Store { key, .. } | TxOp::Delete { key } | TxOp::Check { key, .. } => key,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TxOpResult {
+    Stored { version: u64 },
+    Deleted { existed: bool },
+    Checked,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TxOutcome {
+    /// Every operation was applied; one result per operation, in order.
+    Committed(Vec<TxOpResult>),
+    /// Nothing was applied because the check at index `op` failed.
+    Aborted { op: usize, reason: String },
+}
+
+impl KeyValueStore {
+    /// Applies `ops` all-or-nothing.
+    ///
+    /// Every check is evaluated first; if one fails nothing is written and
+    /// nothing is evicted. The shards of all keys involved stay locked from the
+    /// checks until the last write, so no other operation on those keys can
+    /// interleave, and the writes are charged against `max_size` as one batch
+    /// that never evicts a key of the transaction.
+    pub fn transact(&self, ops: Vec<TxOp>) -> Result<TxOutcome> {
+        if ops.is_empty() {
+            return Ok(TxOutcome::Committed(Vec::new()));
+        }
+
+        // always lock in index order, so two transactions can't deadlock
+        let mut shard_ids: Vec<usize> = ops.iter().map(|op| self.data.determine_map(op.key())).collect();
+        shard_ids.sort_unstable();
+        shard_ids.dedup();
+        let slot = |key: &str| {
+            shard_ids
+                .binary_search(&self.data.determine_map(key))
+                .expect("shard of every key is locked")
+        };
+        let keys: Vec<&str> = ops.iter().map(TxOp::key).collect();
+        // bytes reserved by an earlier pass that had to make room first
+        let mut reserved = 0;
+
+        loop {
+            let mut shards: Vec<_> = shard_ids.iter().map(|&id| self.data.shards()[id].write()).collect();
+            let now = Instant::now();
+
+            for (index, op) in ops.iter().enumerate() {
+                let TxOp::Check { key, condition } = op else { continue };
+                let existing = shards[slot(key)].get(key.as_str()).map(SharedValue::get);
+                if !condition.holds(existing, now) {
+                    drop(shards);
+                    self.release(reserved);
+                    return Ok(TxOutcome::Aborted {
+                        op: index,
+                        reason: format!("Check on '{}' failed: {}", key, describe_failure(condition)),
+                    });
+                }
+            }
+
+            let (added, removed) = net_sizes(&ops, |key| {
+                shards[slot(key)].get(key).map_or(0, |entry| entry.get().size(key))
+            });
+            let growth = added.saturating_sub(removed);
+            if growth > reserved && !self.try_reserve(growth - reserved) {
+                // evicting locks other shards, so make room with these unlocked
+                drop(shards);
+                if let Err(e) = self.reserve(&keys, growth - reserved) {
+                    self.release(reserved);
+                    return Err(e);
+                }
+                reserved = growth;
+                continue;
+            }
+            let charged = reserved.max(growth);
+
+            let logged = self.log(|| AofRecord::Batch {
+                records: ops.iter().filter_map(log_record).collect(),
+            });
+            if let Err(e) = logged {
+                drop(shards);
+                self.release(charged);
+                return Err(e);
+            }
+
+            let mut results = Vec::with_capacity(ops.len());
+            let mut writes = 0;
+            for op in ops {
+                let result = match op {
+                    TxOp::Store { key, value } => {
+                        let version = self.next_version.fetch_add(1, Ordering::Relaxed);
+                        self.notify(&key, KeyEventKind::Set {
+                            value_len: value.len() as u64,
+                        });
+                        let entry = Entry {
+                            value,
+                            expires_at: None,
+                            version,
+                            last_access: AtomicU64::new(self.tick()),
+                            hits: AtomicU32::new(0),
+                        };
+                        shards[slot(&key)].insert(key, SharedValue::new(entry));
+                        writes += 1;
+                        TxOpResult::Stored { version }
+                    }
+                    TxOp::Delete { key } => {
+                        let old = shards[slot(&key)].remove(key.as_str());
+                        if old.is_some() {
+                            self.notify(&key, KeyEventKind::Delete);
+                        }
+                        writes += old.is_some() as usize;
+                        TxOpResult::Deleted {
+                            existed: old.is_some_and(|old| !old.get().is_expired(now)),
+                        }
+                    }
+                    TxOp::Check { .. } => TxOpResult::Checked,
+                };
+                results.push(result);
+            }
+            drop(shards);
+
+            // settle the reservation to exactly added - removed
+            self.release(charged + removed - added);
+            for _ in 0..writes {
+                self.note_write();
+            }
+            return Ok(TxOutcome::Committed(results));
+        }
+    }
+}
+
+// Bytes the transaction adds and removes, counting only the final write to
+// each key; `old_size` is the size a key has before the transaction.
+fn net_sizes(ops: &[TxOp], old_size: impl Fn(&str) -> u64) -> (u64, u64) {
+    let mut last: HashMap<&str, Option<&[u8]>> = HashMap::new();
+    for op in ops {
+        match op {
+            TxOp::Store { key, value } => last.insert(key, Some(value)),
+            TxOp::Delete { key } => last.insert(key, None),
+            TxOp::Check { .. } => continue,
+        };
+    }
+
+    last.into_iter().fold((0, 0), |(added, removed), (key, value)| {
+        (added + value.map_or(0, |value| entry_size(key, value)), removed + old_size(key))
+    })
+}
+
+fn log_record(op: &TxOp) -> Option<AofRecord<'_>> {
+    match op {
+        TxOp::Store { key, value } => Some(AofRecord::Set {
+            key: Cow::Borrowed(key),
+            value: Cow::Borrowed(value),
+            expires_at_ms: None,
+        }),
+        TxOp::Delete { key } => Some(AofRecord::Delete { key: Cow::Borrowed(key) }),
+        TxOp::Check { .. } => None,
+    }
+}
+
+fn describe_failure(condition: &WriteCondition) -> &'static str {
+    match condition {
+        WriteCondition::IfAbsent => "key exists",
+        WriteCondition::IfPresent => "key is missing",
+        WriteCondition::IfVersion(_) => "version does not match",
+        WriteCondition::IfValue(_) => "value does not match",
+    }
+}
@@ -0,0 +1,53 @@
+use super::KeyValueStore;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyEventKind {
+    /// The key was written; `value_len` is the length of the new value.
+    Set { value_len: u64 },
+    /// The key was deleted, evicted or expired.
+    Delete,
+}
+
+/// A change to one key, as seen by a [`KeyListener`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyEvent {
+    /// Unique across the store and increasing for each key: changes to one
+    /// key are numbered in the order they were applied, but changes to
+    /// different keys may be numbered in a different order than they were
+    /// applied in.
+    pub seq: u64,
+    pub key: String,
+    pub kind: KeyEventKind,
+}
+
+/// Receives every change to the keyspace.
+///
+/// Called with the changed key's shard locked, so implementations must be
+/// quick and must not call back into the store.
+pub trait KeyListener: Send + Sync {
+    fn key_changed(&self, event: &KeyEvent);
+}
+
+impl KeyValueStore {
+    /// Installs the listener notified of every set and delete, replacing any
+    /// previous one.
+    pub fn set_key_listener(&self, listener: Arc<dyn KeyListener>) {
+        *self.listener.write().unwrap() = Some(listener);
+    }
+
+    // called with the key's shard locked, like `log`
+    pub(super) fn notify(&self, key: &str, kind: KeyEventKind) {
+        let listener = self.listener.read().unwrap();
+        if let Some(listener) = listener.as_ref() {
+            let seq = self.change_seq.fetch_add(1, Ordering::Relaxed) + 1;
+            listener.key_changed(&KeyEvent {
+                seq,
+                key: key.to_string(),
+                kind,
+            });
+        }
+    }
+}
@@ -1,2 +1,4 @@
 pub mod optimizations;
+pub mod socket;
 pub use optimizations::SystemOptimizer;
+pub use socket::{ListenAddr, PeerAddr, SocketUtils, Stream};
@@ -91,7 +91,7 @@ impl SystemOptimizer {
 
 // thread-local buffer pool
 thread_local! {
-    static BUFFER_POOL: std::cell::RefCell<Vec<Vec<u8>>> = std::cell::RefCell::new(Vec::new());
+    static BUFFER_POOL: std::cell::RefCell<Vec<Vec<u8>>> = const { std::cell::RefCell::new(Vec::new()) };
 }
 
 pub fn get_buffer() -> Vec<u8> {
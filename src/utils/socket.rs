@@ -1,21 +1,218 @@
+use crate::config::ServerConfig;
 use crate::error::{Result, ServerError};
-use nix::sys::socket::{self, setsockopt, sockopt, SockaddrIn};
-use std::net::{IpAddr, SocketAddr};
-use std::os::unix::io::RawFd;
+use log::{info, warn};
+use nix::sys::socket::{
+    self, bind, getpeername, getsockname, getsockopt, listen, setsockopt, sockopt, AddressFamily, SockaddrLike,
+    SockaddrStorage, UnixAddr,
+};
+use std::fmt;
+use std::fs::{self, Permissions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream};
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Duration;
 use nix::sys::time::TimeVal;
 
+const UNIX_SCHEME: &str = "unix:";
+
+/// Where a server listens: `host:port`, or `unix:/path/to.sock` for a Unix
+/// domain stream socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match SocketUtils::unix_path(s) {
+            Some(path) if path.as_os_str().is_empty() => Err(format!("missing socket path in '{}'", s)),
+            Some(path) => Ok(ListenAddr::Unix(path.to_path_buf())),
+            None => s.parse().map(ListenAddr::Tcp).map_err(|e| format!("'{}': {}", s, e)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_SCHEME, path.display()),
+        }
+    }
+}
+
+/// The other end of a connection. Unix domain clients rarely bind a path,
+/// so they are told apart by process id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix { pid: Option<i32> },
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        PeerAddr::Tcp(addr)
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix { pid: Some(pid) } => write!(f, "{}pid={}", UNIX_SCHEME, pid),
+            PeerAddr::Unix { pid: None } => write!(f, "{}unknown", UNIX_SCHEME),
+        }
+    }
+}
+
+/// A blocking connection over TCP or a Unix domain socket.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Connects to `host:port`, or to the Unix domain socket at `unix:/path`.
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        match SocketUtils::unix_path(addr) {
+            Some(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+            None => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_nodelay(true)?;
+                Ok(Stream::Tcp(stream))
+            }
+        }
+    }
+
+    /// Wraps a connection accepted from a [`SocketUtils::listen`] socket,
+    /// turning on `TCP_NODELAY` for TCP peers.
+    pub fn accepted(fd: OwnedFd) -> Result<(Self, PeerAddr)> {
+        let local: SockaddrStorage = getsockname(fd.as_raw_fd())?;
+        if local.family() == Some(AddressFamily::Unix) {
+            // SO_PEERCRED only fails on sockets that are not connected
+            let pid = getsockopt(&fd, sockopt::PeerCredentials).ok().map(|cred| cred.pid());
+            return Ok((Stream::Unix(UnixStream::from(fd)), PeerAddr::Unix { pid }));
+        }
+
+        let stream = TcpStream::from(fd);
+        let peer_addr = PeerAddr::Tcp(SocketUtils::peer_addr(&stream)?);
+        if let Err(e) = stream.set_nodelay(true) {
+            warn!("Failed to set TCP_NODELAY for {}: {}", peer_addr, e);
+        }
+        Ok((Stream::Tcp(stream), peer_addr))
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        match self {
+            Stream::Tcp(stream) => stream.read_timeout(),
+            Stream::Unix(stream) => stream.read_timeout(),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Stream::Tcp(stream)
+    }
+}
+
+impl From<UnixStream> for Stream {
+    fn from(stream: UnixStream) -> Self {
+        Stream::Unix(stream)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl AsFd for Stream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Stream::Tcp(stream) => stream.as_fd(),
+            Stream::Unix(stream) => stream.as_fd(),
+        }
+    }
+}
+
 pub struct SocketUtils;
 
 impl SocketUtils {
-    pub fn create_socket(nonblocking: bool) -> Result<RawFd> {
+    pub fn create_socket(family: AddressFamily, nonblocking: bool) -> Result<OwnedFd> {
         let mut flags = socket::SockFlag::SOCK_CLOEXEC;
         if nonblocking {
             flags |= socket::SockFlag::SOCK_NONBLOCK;
         }
 
         socket::socket(
-            socket::AddressFamily::Inet,
+            family,
             socket::SockType::Stream,
             flags,
             None,
@@ -23,7 +220,7 @@ impl SocketUtils {
         .map_err(ServerError::from)
     }
 
-    pub fn set_socket_opts(fd: RawFd, read_timeout: Duration, write_timeout: Duration) -> Result<()> {
+    pub fn set_socket_opts<F: AsFd>(fd: &F, read_timeout: Duration, write_timeout: Duration) -> Result<()> {
         let read_tv = TimeVal::new(
             read_timeout.as_secs() as i64,
             read_timeout.subsec_micros() as i64,
@@ -42,19 +239,115 @@ impl SocketUtils {
         Ok(())
     }
 
-    pub fn sockaddr_to_std(addr: &SockaddrIn) -> Result<SocketAddr> {
-        let ip = match addr.ip() {
-            Ok(ip) => IpAddr::V4(ip),
-            Err(_) => return Err(ServerError::Connection("Invalid IP address".into())),
+    /// Creates the non-blocking listening socket for `config.host` and
+    /// `config.port`, or for `config.unix_socket` if set. An IPv6 `host`
+    /// also accepts IPv4 clients unless `config.ipv6_only` is set.
+    pub fn listen(config: &ServerConfig) -> Result<OwnedFd> {
+        if let Some(path) = &config.unix_socket {
+            return Self::listen_unix(path, config);
+        }
+
+        let addr = Self::std_to_sockaddr(SocketAddr::new(config.host, config.port))?;
+        let family = addr.family().ok_or_else(|| ServerError::Connection("Unknown address family".into()))?;
+
+        let listener = Self::create_socket(family, true)?;
+        // SO_REUSEADDR only helps rebinding after a restart if set before bind
+        Self::set_socket_opts(
+            &listener,
+            Duration::from_millis(config.read_timeout_ms),
+            Duration::from_millis(config.write_timeout_ms),
+        )?;
+        if family == AddressFamily::Inet6 {
+            // set explicitly, the default follows the net.ipv6.bindv6only sysctl
+            setsockopt(&listener, sockopt::Ipv6V6Only, &config.ipv6_only)?;
+        }
+        bind(listener.as_raw_fd(), &addr)?;
+        listen(&listener, config.backlog as usize)?;
+        Ok(listener)
+    }
+
+    fn listen_unix(path: &Path, config: &ServerConfig) -> Result<OwnedFd> {
+        Self::remove_stale_socket(path)?;
+        let listener = Self::create_socket(AddressFamily::Unix, true)?;
+        bind(listener.as_raw_fd(), &UnixAddr::new(path)?)?;
+        // nobody can connect before listen, so the mode is in place first
+        fs::set_permissions(path, Permissions::from_mode(config.unix_socket_mode))?;
+        listen(&listener, config.backlog as usize)?;
+        Ok(listener)
+    }
+
+    /// Removes a socket file left behind by a server that is gone. Fails if
+    /// a server still accepts on it, or if the path is not a socket.
+    fn remove_stale_socket(path: &Path) -> Result<()> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
         };
-        let port = addr.port();
-        Ok(SocketAddr::new(ip, port))
+        if !metadata.file_type().is_socket() {
+            let message = format!("{} exists and is not a socket", path.display());
+            return Err(io::Error::new(ErrorKind::AlreadyExists, message).into());
+        }
+        match UnixStream::connect(path) {
+            Ok(_) => {
+                let message = format!("{} is in use by another server", path.display());
+                Err(io::Error::new(ErrorKind::AddrInUse, message).into())
+            }
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                info!("Removing stale socket {}", path.display());
+                fs::remove_file(path)?;
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Removes the socket file of a Unix domain listener once it is closed.
+    pub fn remove_unix_socket(config: &ServerConfig) {
+        if let Some(path) = &config.unix_socket {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Failed to remove socket {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// The path of a `unix:/path` address; `None` for anything else.
+    pub fn unix_path(addr: &str) -> Option<&Path> {
+        addr.strip_prefix(UNIX_SCHEME).map(Path::new)
+    }
+
+    /// Where a listening socket is bound, TCP or Unix domain.
+    pub fn bound_addr<F: AsRawFd>(fd: &F) -> Result<ListenAddr> {
+        let addr: SockaddrStorage = getsockname(fd.as_raw_fd())?;
+        match addr.as_unix_addr().and_then(UnixAddr::path) {
+            Some(path) => Ok(ListenAddr::Unix(path.to_path_buf())),
+            None => Self::sockaddr_to_std(&addr).map(ListenAddr::Tcp),
+        }
     }
 
-    pub fn std_to_sockaddr(addr: SocketAddr) -> Result<SockaddrIn> {
-        match addr.ip() {
-            IpAddr::V4(ipv4) => Ok(SockaddrIn::new(ipv4.to_string().as_str(), addr.port())),
-            IpAddr::V6(_) => Err(ServerError::Connection("IPv6 not supported".into())),
+    pub fn peer_addr<F: AsRawFd>(fd: &F) -> Result<SocketAddr> {
+        let addr: SockaddrStorage = getpeername(fd.as_raw_fd())?;
+        Self::sockaddr_to_std(&addr)
+    }
+
+    pub fn local_addr<F: AsRawFd>(fd: &F) -> Result<SocketAddr> {
+        let addr: SockaddrStorage = getsockname(fd.as_raw_fd())?;
+        Self::sockaddr_to_std(&addr)
+    }
+
+    /// Converts an IPv4 or IPv6 socket address; IPv4 clients of a
+    /// dual-stack listener show up as IPv4-mapped IPv6 addresses.
+    pub fn sockaddr_to_std(addr: &SockaddrStorage) -> Result<SocketAddr> {
+        if let Some(addr) = addr.as_sockaddr_in() {
+            Ok(SocketAddr::V4(SocketAddrV4::from(*addr)))
+        } else if let Some(addr) = addr.as_sockaddr_in6() {
+            Ok(SocketAddr::V6(SocketAddrV6::from(*addr)))
+        } else {
+            Err(ServerError::Connection(format!("Unsupported address family: {:?}", addr.family())))
         }
     }
+
+    pub fn std_to_sockaddr(addr: SocketAddr) -> Result<SockaddrStorage> {
+        Ok(SockaddrStorage::from(addr))
+    }
 }
@@ -0,0 +1,124 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use tcp_server::storage::{EvictionPolicy, FsyncPolicy, KeyTtl, KeyValueStore};
+
+fn open(path: &Path) -> KeyValueStore {
+    KeyValueStore::with_aof(u64::MAX, EvictionPolicy::NoEviction, path, FsyncPolicy::Always).unwrap()
+}
+
+#[test]
+fn replay_rebuilds_entries_and_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("appendonly.aof");
+
+    let (size, keys) = {
+        let store = open(&path);
+        store.set("a", b"1".to_vec()).unwrap();
+        store.set("b", b"22".to_vec()).unwrap();
+        store.set("a", b"333".to_vec()).unwrap();
+        store.set("c", b"4".to_vec()).unwrap();
+        store.delete("c").unwrap();
+        store.set_with_ttl("d", b"5".to_vec(), Duration::from_secs(60)).unwrap();
+        store.set_with_ttl("e", b"6".to_vec(), Duration::from_secs(60)).unwrap();
+        store.persist("e").unwrap();
+        (store.current_size(), store.entry_count())
+    };
+
+    let store = open(&path);
+    assert_eq!(store.current_size(), size);
+    assert_eq!(store.entry_count(), keys);
+    assert_eq!(store.get("a").unwrap(), Some(b"333".to_vec()));
+    assert_eq!(store.get("b").unwrap(), Some(b"22".to_vec()));
+    assert_eq!(store.get("c").unwrap(), None);
+    assert!(matches!(store.ttl("d").unwrap(), KeyTtl::Remaining(_)));
+    assert_eq!(store.ttl("e").unwrap(), KeyTtl::NoExpiry);
+}
+
+#[test]
+fn replay_drops_keys_that_expired_while_down() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("appendonly.aof");
+
+    {
+        let store = open(&path);
+        store.set_with_ttl("short", b"x".to_vec(), Duration::from_millis(10)).unwrap();
+        store.set("long", b"y".to_vec()).unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(20));
+
+    let store = open(&path);
+    assert_eq!(store.get("short").unwrap(), None);
+    assert_eq!(store.get("long").unwrap(), Some(b"y".to_vec()));
+    assert_eq!(store.entry_count(), 1);
+}
+
+#[test]
+fn torn_final_record_is_truncated() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("appendonly.aof");
+
+    {
+        let store = open(&path);
+        store.set("kept", b"value".to_vec()).unwrap();
+        store.set("torn", b"value".to_vec()).unwrap();
+    }
+
+    // cut the last record short, as a crash mid-write would
+    let len = std::fs::metadata(&path).unwrap().len();
+    OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+    let intact_len = {
+        let store = open(&path);
+        assert_eq!(store.get("kept").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.get("torn").unwrap(), None);
+        std::fs::metadata(&path).unwrap().len()
+    };
+    assert!(intact_len < len - 3);
+
+    // new records land after the truncation point and replay cleanly
+    open(&path).set("after", b"value".to_vec()).unwrap();
+    let store = open(&path);
+    assert_eq!(store.get("after").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(store.entry_count(), 2);
+}
+
+#[test]
+fn corrupt_record_before_the_tail_is_an_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("appendonly.aof");
+
+    {
+        let store = open(&path);
+        store.set("first", b"value".to_vec()).unwrap();
+        store.set("second", b"value".to_vec()).unwrap();
+    }
+
+    // flip a byte inside the first record's payload
+    let mut data = std::fs::read(&path).unwrap();
+    data[10] ^= 0xff;
+    std::fs::File::create(&path).unwrap().write_all(&data).unwrap();
+
+    assert!(KeyValueStore::with_aof(u64::MAX, EvictionPolicy::NoEviction, &path, FsyncPolicy::Always).is_err());
+}
+
+#[test]
+fn evictions_are_not_replayed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("appendonly.aof");
+    let max_size = 2 * tcp_server::storage::entry_size("k0", b"value");
+
+    {
+        let store = KeyValueStore::with_aof(max_size, EvictionPolicy::Lru, &path, FsyncPolicy::Never).unwrap();
+        for i in 0..5 {
+            store.set(&format!("k{}", i), b"value".to_vec()).unwrap();
+        }
+        assert_eq!(store.entry_count(), 2);
+    }
+
+    let store = KeyValueStore::with_aof(max_size, EvictionPolicy::Lru, &path, FsyncPolicy::Never).unwrap();
+    assert_eq!(store.stats().evictions, 0);
+    assert_eq!(store.entry_count(), 2);
+    assert_eq!(store.get("k4").unwrap(), Some(b"value".to_vec()));
+}
@@ -0,0 +1,103 @@
+use futures::{SinkExt, StreamExt};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tcp_server::client::AsyncClient;
+use tcp_server::config::ServerConfig;
+use tcp_server::error::ServerError;
+use tcp_server::protocol::{ErrorCode, Message, MessageCodec};
+use tcp_server::server::StdServer;
+use tokio::net::TcpListener;
+use tokio_util::codec::Framed;
+
+// runs on the test's runtime until the test ends
+async fn server() -> SocketAddr {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let server = StdServer::new(ServerConfig {
+        port,
+        ..ServerConfig::default()
+    })
+    .unwrap();
+    tokio::spawn(async move { server.run().await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+// a server that answers every request but `request_id` 1, and hangs up after `close_after` requests
+async fn fake_server(close_after: usize) -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut frames = Framed::new(stream, MessageCodec::new());
+        for _ in 0..close_after {
+            let Some(Ok(request)) = frames.next().await else { return };
+            if request.request_id != 1 {
+                let _ = frames.send(Message::new_response(request.request_id, b"PONG".to_vec())).await;
+            }
+        }
+    });
+    addr
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_requests_share_one_connection() {
+    let client = AsyncClient::connect(server().await).await.unwrap();
+
+    let tasks: Vec<_> = (0..100)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let key = format!("key{}", i);
+                client.store(&key, vec![i as u8; 64]).await.unwrap();
+                assert_eq!(client.retrieve(&key).await.unwrap(), Some(vec![i as u8; 64]));
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    assert_eq!(client.retrieve("missing").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn timeouts_apply_per_request() {
+    let client = AsyncClient::connect(fake_server(usize::MAX).await).await.unwrap();
+
+    let started = Instant::now();
+    match client.with_timeout(Duration::from_millis(100)).ping().await {
+        Err(ServerError::Io(e)) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    // the connection stays usable for the requests that do get answers
+    assert_eq!(client.ping().await.unwrap(), "PONG");
+}
+
+#[tokio::test]
+async fn pending_requests_fail_when_the_connection_drops() {
+    // reads request 1 without answering it, then hangs up
+    let client = AsyncClient::connect(fake_server(1).await).await.unwrap();
+
+    let started = Instant::now();
+    match client.with_timeout(Duration::from_secs(10)).ping().await {
+        Err(ServerError::Connection(_)) => {}
+        other => panic!("expected a connection error, got {:?}", other),
+    }
+    assert!(started.elapsed() < Duration::from_secs(5), "waited for the timeout instead");
+    assert!(matches!(client.ping().await, Err(ServerError::Connection(_))));
+}
+
+#[test]
+fn error_frames_carry_their_code() {
+    let missing = Message::new_error_with_code(7, ErrorCode::KeyNotFound, "gone".into());
+    let mut wire = Vec::new();
+    missing.write_to(&mut wire).unwrap();
+    let decoded = Message::read_from(&mut wire.as_slice()).unwrap();
+    assert_eq!(decoded.error_code(), Some(ErrorCode::KeyNotFound));
+    assert_eq!(decoded.error_message(), "gone");
+
+    assert_eq!(Message::new_error(7, "any".into()).error_code(), Some(ErrorCode::Other));
+    assert_eq!(Message::new_response(7, Vec::new()).error_code(), None);
+}
@@ -0,0 +1,204 @@
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+use tcp_server::client::Client;
+use tcp_server::config::ServerConfig;
+use tcp_server::error::ServerError;
+use tcp_server::protocol::{KeyResult, Message, OpCode, ProtocolHandler};
+use tcp_server::server::StdServer;
+use tcp_server::storage::{entry_size, BTreeStore, FaultyBackend, KeyValueStore, LogStore, StorageBackend};
+
+/// Behaviour every backend must share.
+fn exercise(store: &impl StorageBackend) {
+    assert_eq!(store.get("missing").unwrap(), None);
+
+    store.set("a", b"1".to_vec()).unwrap();
+    store.set("b", b"22".to_vec()).unwrap();
+    store.set("a", b"333".to_vec()).unwrap();
+    assert_eq!(store.get("a").unwrap(), Some(b"333".to_vec()));
+    assert_eq!(store.current_size(), entry_size("a", b"333") + entry_size("b", b"22"));
+
+    let mut keys = store.list_keys().unwrap();
+    keys.sort();
+    assert_eq!(keys, ["a", "b"]);
+    assert_eq!(store.entry_count(), 2);
+
+    store.delete("a").unwrap();
+    store.delete("a").unwrap();
+    assert_eq!(store.get("a").unwrap(), None);
+    assert_eq!(store.current_size(), entry_size("b", b"22"));
+}
+
+fn capacity_is_enforced(store: &impl StorageBackend) {
+    store.set("k", vec![0; 10]).unwrap();
+    let err = store.set("other", vec![0; 10]).unwrap_err();
+    assert!(err.to_string().contains("Storage capacity exceeded"));
+    // shrinking an existing key always fits
+    store.set("k", vec![0; 5]).unwrap();
+}
+
+#[test]
+fn key_value_store_conforms() {
+    exercise(&KeyValueStore::new(u64::MAX));
+    capacity_is_enforced(&KeyValueStore::new(entry_size("k", &[0; 10])));
+}
+
+#[test]
+fn btree_store_conforms() {
+    exercise(&BTreeStore::new(u64::MAX));
+    capacity_is_enforced(&BTreeStore::new(entry_size("k", &[0; 10])));
+}
+
+#[test]
+fn log_store_conforms() {
+    let dir = tempfile::tempdir().unwrap();
+    exercise(&LogStore::open(dir.path().join("a.log"), u64::MAX).unwrap());
+    capacity_is_enforced(&LogStore::open(dir.path().join("b.log"), entry_size("k", &[0; 10])).unwrap());
+}
+
+#[test]
+fn btree_store_scans_in_order() {
+    let store = BTreeStore::new(u64::MAX);
+    for key in ["user:3", "order:1", "user:1", "user:2", "vendor:1"] {
+        store.set(key, key.as_bytes().to_vec()).unwrap();
+    }
+
+    let users: Vec<_> = store.scan_prefix("user:").into_iter().map(|(key, _)| key).collect();
+    assert_eq!(users, ["user:1", "user:2", "user:3"]);
+
+    let range: Vec<_> = store
+        .range(Bound::Excluded("order:1"), Bound::Included("user:2"))
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(range, ["user:1", "user:2"]);
+    assert_eq!(store.list_keys().unwrap(), ["order:1", "user:1", "user:2", "user:3", "vendor:1"]);
+}
+
+#[test]
+fn log_store_survives_reopen_and_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.log");
+
+    {
+        let store = LogStore::open(&path, u64::MAX).unwrap();
+        for i in 0..100u8 {
+            store.set("hot", vec![i; 64]).unwrap();
+        }
+        store.set("cold", b"value".to_vec()).unwrap();
+        store.set("gone", b"value".to_vec()).unwrap();
+        store.delete("gone").unwrap();
+        store.sync().unwrap();
+    }
+
+    let store = LogStore::open(&path, u64::MAX).unwrap();
+    assert_eq!(store.get("hot").unwrap(), Some(vec![99; 64]));
+    assert_eq!(store.get("gone").unwrap(), None);
+    let size = store.current_size();
+
+    let before = std::fs::metadata(&path).unwrap().len();
+    store.compact().unwrap();
+    assert!(std::fs::metadata(&path).unwrap().len() < before);
+    assert_eq!(store.get("hot").unwrap(), Some(vec![99; 64]));
+    assert_eq!(store.get("cold").unwrap(), Some(b"value".to_vec()));
+
+    store.set("after", b"compaction".to_vec()).unwrap();
+    drop(store);
+    let store = LogStore::open(&path, u64::MAX).unwrap();
+    assert_eq!(store.current_size(), size + entry_size("after", b"compaction"));
+    assert_eq!(store.entry_count(), 3);
+}
+
+#[test]
+fn protocol_reports_backend_failures_as_errors() {
+    let store = Arc::new(FaultyBackend::new(KeyValueStore::new(u64::MAX)));
+    let handler = ProtocolHandler::new(store.clone());
+    let set = |id| {
+        let payload = bincode::serialize(&("key", b"value".to_vec())).unwrap();
+        handler.handle_message(Message::new_request(id, OpCode::Store, payload))
+    };
+
+    store.fail_writes(true);
+    assert!(matches!(set(1), Err(ServerError::Storage(msg)) if msg == "Injected failure: set"));
+    assert_eq!(store.inner().entry_count(), 0);
+
+    store.heal();
+    assert!(set(2).unwrap().is_response());
+
+    store.fail_after(1);
+    assert!(set(3).is_ok());
+    assert!(set(4).is_err());
+    assert_eq!(store.injected_failures(), 2);
+}
+
+#[test]
+fn batch_operations_report_per_key_results() {
+    let store = Arc::new(FaultyBackend::new(KeyValueStore::new(u64::MAX)));
+    let handler = ProtocolHandler::new(store.clone());
+    let call = |op, payload: Vec<u8>| -> Vec<KeyResult> {
+        let response = handler.handle_message(Message::new_request(1, op, payload)).unwrap();
+        bincode::deserialize(&response.payload).unwrap()
+    };
+
+    let entries = [("a", b"1".to_vec()), ("b", b"2".to_vec()), ("c", b"3".to_vec())];
+    store.fail_after(2);
+    let results = call(OpCode::MSet, bincode::serialize(&entries[..]).unwrap());
+    assert_eq!(results[..2], [KeyResult::Done, KeyResult::Done]);
+    assert_eq!(results[2], KeyResult::Error("Storage error: Injected failure: set".into()));
+
+    store.heal();
+    let results = call(OpCode::MGet, bincode::serialize(&["b", "c", "a"][..]).unwrap());
+    assert_eq!(results, [KeyResult::Found(b"2".to_vec()), KeyResult::Missing, KeyResult::Found(b"1".to_vec())]);
+
+    let results = call(OpCode::MDelete, bincode::serialize(&["a", "b"][..]).unwrap());
+    assert_eq!(results, [KeyResult::Done, KeyResult::Done]);
+    assert_eq!(store.inner().entry_count(), 0);
+}
+
+#[test]
+fn unsupported_operations_fail_cleanly() {
+    let handler = ProtocolHandler::new(Arc::new(BTreeStore::new(u64::MAX)));
+    let payload = bincode::serialize(&("key", b"value".to_vec(), 1000u64)).unwrap();
+    let err = handler
+        .handle_message(Message::new_request(1, OpCode::StoreEx, payload))
+        .unwrap_err();
+    assert!(err.to_string().contains("STOREEX is not supported"));
+}
+
+#[tokio::test]
+async fn server_keeps_connection_open_when_backend_fails() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let config = ServerConfig {
+        port,
+        ..ServerConfig::default()
+    };
+    let store = Arc::new(FaultyBackend::new(BTreeStore::new(u64::MAX)));
+    let server = StdServer::with_store(config, store.clone());
+    let shutdown = server.shutdown_handle();
+    let server = tokio::spawn(async move { server.run().await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let addr = format!("127.0.0.1:{}", port);
+    tokio::task::spawn_blocking(move || {
+        let mut client = Client::connect(&addr).unwrap();
+        client.store("key", b"before".to_vec()).unwrap();
+
+        store.fail_reads(true);
+        let err = client.retrieve("key").unwrap_err();
+        assert!(err.to_string().contains("Injected failure: get"));
+
+        store.heal();
+        assert!(client.retrieve("key").unwrap().is_some());
+
+        let stored = client.store_many(&[("a", b"1".to_vec()), ("b", b"2".to_vec())]).unwrap();
+        assert_eq!(stored, [KeyResult::Done, KeyResult::Done]);
+        let found = client.retrieve_many(&["a", "missing"]).unwrap();
+        assert_eq!(found, [KeyResult::Found(b"1".to_vec()), KeyResult::Missing]);
+        assert_eq!(client.delete_many(&["a", "b"]).unwrap().len(), 2);
+    })
+    .await
+    .unwrap();
+
+    shutdown.shutdown();
+    server.await.unwrap().unwrap();
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tcp_server::protocol::{Message, OpCode, ProtocolHandler};
+use tcp_server::storage::{entry_size, BTreeStore, EvictionPolicy, KeyValueStore, WriteCondition};
+
+#[test]
+fn conditions_are_checked_against_the_current_entry() {
+    let store = KeyValueStore::new(u64::MAX);
+
+    assert_eq!(store.set_if("k", b"a".to_vec(), &WriteCondition::IfPresent).unwrap(), None);
+    let v1 = store.set_if("k", b"a".to_vec(), &WriteCondition::IfAbsent).unwrap().unwrap();
+    assert_eq!(store.set_if("k", b"b".to_vec(), &WriteCondition::IfAbsent).unwrap(), None);
+
+    let current = store.get_versioned("k").unwrap().unwrap();
+    assert_eq!((current.value.as_slice(), current.version), (&b"a"[..], v1));
+
+    let v2 = store.set_if("k", b"b".to_vec(), &WriteCondition::IfVersion(v1)).unwrap().unwrap();
+    assert!(v2 > v1);
+    assert_eq!(store.set_if("k", b"c".to_vec(), &WriteCondition::IfVersion(v1)).unwrap(), None);
+    assert_eq!(store.set_if("k", b"c".to_vec(), &WriteCondition::IfValue(b"a".to_vec())).unwrap(), None);
+    assert!(store.set_if("k", b"c".to_vec(), &WriteCondition::IfValue(b"b".to_vec())).unwrap().is_some());
+    assert!(store.set_if("k", b"d".to_vec(), &WriteCondition::IfPresent).unwrap().is_some());
+    assert_eq!(store.get("k").unwrap(), Some(b"d".to_vec()));
+
+    // a failed condition leaves the size accounting untouched
+    let size = store.current_size();
+    assert_eq!(store.set_if("k", vec![0; 1000], &WriteCondition::IfAbsent).unwrap(), None);
+    assert_eq!(store.current_size(), size);
+}
+
+#[test]
+fn failed_conditions_on_a_full_store_neither_evict_nor_fail() {
+    for policy in [EvictionPolicy::Lru, EvictionPolicy::NoEviction] {
+        let store = KeyValueStore::with_eviction_policy(2 * entry_size("a", &[0; 100]), policy);
+        store.set("a", vec![0; 100]).unwrap();
+        store.set("b", vec![0; 100]).unwrap();
+        let stale = store.get_versioned("a").unwrap().unwrap().version;
+        store.set("a", vec![1; 100]).unwrap();
+
+        let cases = [
+            ("a", WriteCondition::IfVersion(stale)),
+            ("a", WriteCondition::IfAbsent),
+            ("c", WriteCondition::IfPresent),
+        ];
+        for (key, condition) in cases {
+            assert_eq!(store.set_if(key, vec![2; 500], &condition).unwrap(), None, "{} {:?}", policy, condition);
+        }
+        assert_eq!(store.entry_count(), 2);
+        assert_eq!(store.stats().evictions, 0);
+        assert_eq!(store.current_size(), 2 * entry_size("a", &[0; 100]));
+    }
+}
+
+#[test]
+fn recreated_keys_get_new_versions() {
+    let store = KeyValueStore::new(u64::MAX);
+    store.set("k", b"same".to_vec()).unwrap();
+    let before = store.get_versioned("k").unwrap().unwrap().version;
+
+    store.delete("k").unwrap();
+    store.set("k", b"same".to_vec()).unwrap();
+    assert_eq!(store.set_if("k", b"new".to_vec(), &WriteCondition::IfVersion(before)).unwrap(), None);
+}
+
+#[test]
+fn expired_keys_count_as_absent() {
+    let store = KeyValueStore::new(u64::MAX);
+    store.set_with_ttl("k", b"old".to_vec(), Duration::from_millis(10)).unwrap();
+    thread::sleep(Duration::from_millis(20));
+
+    assert_eq!(store.set_if("k", b"x".to_vec(), &WriteCondition::IfPresent).unwrap(), None);
+    assert_eq!(store.get_versioned("k").unwrap(), None);
+    store.set_with_ttl("k", b"old".to_vec(), Duration::from_millis(10)).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    assert!(store.set_if("k", b"new".to_vec(), &WriteCondition::IfAbsent).unwrap().is_some());
+    assert_eq!(store.get("k").unwrap(), Some(b"new".to_vec()));
+}
+
+#[test]
+fn concurrent_compare_and_swap_loses_no_updates() {
+    let store = Arc::new(KeyValueStore::new(u64::MAX));
+    store.set("counter", 0u64.to_be_bytes().to_vec()).unwrap();
+
+    let workers: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..500 {
+                    loop {
+                        let current = store.get_versioned("counter").unwrap().unwrap();
+                        let n = u64::from_be_bytes(current.value.try_into().unwrap());
+                        let next = (n + 1).to_be_bytes().to_vec();
+                        if store.set_if("counter", next, &WriteCondition::IfVersion(current.version)).unwrap().is_some() {
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    assert_eq!(store.get("counter").unwrap(), Some(4000u64.to_be_bytes().to_vec()));
+}
+
+#[test]
+fn cas_over_the_protocol() {
+    let handler = ProtocolHandler::new(Arc::new(KeyValueStore::new(u64::MAX)));
+    let call = |op, payload: Vec<u8>| handler.handle_message(Message::new_request(1, op, payload)).unwrap();
+
+    let absent = bincode::serialize(&("k", b"a".to_vec(), WriteCondition::IfAbsent)).unwrap();
+    let version: Option<u64> = bincode::deserialize(&call(OpCode::CompareAndSwap, absent.clone()).payload).unwrap();
+    let again: Option<u64> = bincode::deserialize(&call(OpCode::CompareAndSwap, absent).payload).unwrap();
+    assert!(version.is_some());
+    assert_eq!(again, None);
+
+    let response = call(OpCode::RetrieveVersion, bincode::serialize(&"k").unwrap());
+    let (value, current): (Vec<u8>, u64) = bincode::deserialize::<Option<_>>(&response.payload).unwrap().unwrap();
+    assert_eq!((value, Some(current)), (b"a".to_vec(), version));
+
+    let btree = ProtocolHandler::new(Arc::new(BTreeStore::new(u64::MAX)));
+    let payload = bincode::serialize(&("k", b"a".to_vec(), WriteCondition::IfAbsent)).unwrap();
+    let err = btree.handle_message(Message::new_request(1, OpCode::CompareAndSwap, payload)).unwrap_err();
+    assert!(err.to_string().contains("CAS is not supported"));
+}
@@ -0,0 +1,109 @@
+use bytes::BytesMut;
+use proptest::prelude::*;
+use tcp_server::error::ServerError;
+use tcp_server::protocol::{Message, MessageCodec, OpCode};
+use tokio_util::codec::{Decoder, Encoder};
+
+fn op_code() -> impl Strategy<Value = OpCode> {
+    prop_oneof![
+        Just(OpCode::Ping),
+        Just(OpCode::Store),
+        Just(OpCode::Retrieve),
+        Just(OpCode::Delete),
+        Just(OpCode::List),
+    ]
+}
+
+fn message() -> impl Strategy<Value = Message> {
+    (
+        any::<u32>(),
+        op_code(),
+        prop::collection::vec(any::<u8>(), 0..512),
+        0u8..3,
+    )
+        .prop_map(|(request_id, op_code, payload, kind)| match kind {
+            0 => Message::new_request(request_id, op_code, payload),
+            1 => Message::new_response(request_id, payload),
+            _ => Message::new_error(request_id, String::from_utf8_lossy(&payload).into_owned()),
+        })
+}
+
+fn encode(messages: &[Message]) -> BytesMut {
+    let mut codec = MessageCodec::new();
+    let mut buf = BytesMut::new();
+    for message in messages {
+        codec.encode(message.clone(), &mut buf).unwrap();
+    }
+    buf
+}
+
+proptest! {
+    #[test]
+    fn encoding_matches_write_to(message in message()) {
+        let mut expected = Vec::new();
+        message.write_to(&mut expected).unwrap();
+        prop_assert_eq!(&encode(&[message])[..], &expected[..]);
+    }
+
+    #[test]
+    fn decodes_frame_split_at_every_boundary(message in message()) {
+        let frame = encode(std::slice::from_ref(&message));
+
+        for split in 0..=frame.len() {
+            let mut codec = MessageCodec::new();
+            let mut buf = BytesMut::from(&frame[..split]);
+
+            if split < frame.len() {
+                prop_assert_eq!(codec.decode(&mut buf).unwrap(), None);
+                prop_assert_eq!(buf.len(), split);
+            }
+
+            buf.extend_from_slice(&frame[split..]);
+            prop_assert_eq!(codec.decode(&mut buf).unwrap(), Some(message.clone()));
+            prop_assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn decodes_stream_fed_in_chunks(
+        messages in prop::collection::vec(message(), 1..8),
+        chunk in 1usize..64,
+    ) {
+        let stream = encode(&messages);
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+
+        for piece in stream.chunks(chunk) {
+            buf.extend_from_slice(piece);
+            while let Some(message) = codec.decode(&mut buf).unwrap() {
+                decoded.push(message);
+            }
+        }
+
+        prop_assert!(buf.is_empty());
+        prop_assert_eq!(decoded, messages);
+    }
+}
+
+#[test]
+fn rejects_unknown_op_code() {
+    let mut buf = BytesMut::from(&[1u8, 0, 0, 0, 7, 0xff, 0, 0, 0, 0][..]);
+    assert!(MessageCodec::new().decode(&mut buf).is_err());
+}
+
+#[test]
+fn rejects_oversized_payload_before_buffering() {
+    let mut codec = MessageCodec::with_max_frame_size(16);
+    let mut buf = BytesMut::from(&[1u8, 0, 0, 0, 42, 2, 0xff, 0xff, 0xff, 0xff][..]);
+
+    match codec.decode(&mut buf) {
+        Err(ServerError::FrameTooLarge { request_id, payload_len, max_frame_size }) => {
+            assert_eq!(request_id, 42);
+            assert_eq!(payload_len, u32::MAX);
+            assert_eq!(max_frame_size, 16);
+        }
+        other => panic!("expected FrameTooLarge, got {:?}", other),
+    }
+    assert!(buf.capacity() < 1024);
+}
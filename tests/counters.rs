@@ -0,0 +1,134 @@
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tcp_server::client::Client;
+use tcp_server::config::ServerConfig;
+use tcp_server::error::ServerError;
+use tcp_server::protocol::{ErrorCode, Message, OpCode};
+use tcp_server::server::{IoUringServer, RawServer, StdServer};
+use tcp_server::storage::{decode_integer, encode_integer, KeyTtl, KeyValueStore};
+
+#[test]
+fn integer_encoding() {
+    for n in [0, 1, -1, 42, i64::MIN, i64::MAX] {
+        assert_eq!(decode_integer(&encode_integer(n)).unwrap(), n);
+    }
+    assert_eq!(encode_integer(-42), b"-42");
+
+    for bad in [&b""[..], b"abc", b" 1", b"1 ", b"+1", b"1.5", b"9223372036854775808", &[0xff]] {
+        assert!(matches!(decode_integer(bad), Err(ServerError::NotAnInteger)), "{:?}", bad);
+    }
+}
+
+#[test]
+fn incr_creates_missing_keys_and_keeps_ttl() {
+    let store = KeyValueStore::new(u64::MAX);
+    assert_eq!(store.incr_by("hits", 1).unwrap(), 1);
+    assert_eq!(store.incr_by("hits", 10).unwrap(), 11);
+    assert_eq!(store.incr_by("hits", -20).unwrap(), -9);
+    assert_eq!(store.get("hits").unwrap(), Some(b"-9".to_vec()));
+
+    store.expire("hits", Duration::from_secs(60)).unwrap();
+    store.incr_by("hits", 1).unwrap();
+    assert!(matches!(store.ttl("hits").unwrap(), KeyTtl::Remaining(_)));
+}
+
+#[test]
+fn incr_rejects_non_integers_and_overflow() {
+    let store = KeyValueStore::new(u64::MAX);
+    store.set("name", b"alice".to_vec()).unwrap();
+    assert!(matches!(store.incr_by("name", 1), Err(ServerError::NotAnInteger)));
+    assert_eq!(store.get("name").unwrap(), Some(b"alice".to_vec()));
+
+    store.set("big", encode_integer(i64::MAX)).unwrap();
+    assert!(matches!(store.incr_by("big", 1), Err(ServerError::NotAnInteger)));
+    assert_eq!(store.incr_by("big", -1).unwrap(), i64::MAX - 1);
+}
+
+#[test]
+fn concurrent_increments_are_not_lost() {
+    let store = Arc::new(KeyValueStore::new(u64::MAX));
+    let workers: Vec<_> = (0..8)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    store.incr_by("counter", if i % 2 == 0 { 3 } else { -1 }).unwrap();
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    assert_eq!(store.get("counter").unwrap(), Some(encode_integer(4 * 3000 - 4 * 1000)));
+}
+
+#[tokio::test]
+async fn client_counters() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let config = ServerConfig {
+        port,
+        ..ServerConfig::default()
+    };
+    let server = StdServer::with_store(config, Arc::new(KeyValueStore::new(u64::MAX)));
+    let shutdown = server.shutdown_handle();
+    let server = tokio::spawn(async move { server.run().await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let addr = format!("127.0.0.1:{}", port);
+    tokio::task::spawn_blocking(move || {
+        let mut client = Client::connect(&addr).unwrap();
+        assert_eq!(client.incr("n").unwrap(), 1);
+        assert_eq!(client.incr_by("n", 41).unwrap(), 42);
+        assert_eq!(client.decr("n").unwrap(), 41);
+
+        client.store("text", b"hello".to_vec()).unwrap();
+        assert!(matches!(client.incr("text"), Err(ServerError::NotAnInteger)));
+        // the connection is still usable afterwards
+        assert_eq!(client.incr("n").unwrap(), 42);
+    })
+    .await
+    .unwrap();
+
+    shutdown.shutdown();
+    server.await.unwrap().unwrap();
+}
+
+#[test]
+fn non_integer_errors_carry_their_code_in_every_server() {
+    let store = Arc::new(KeyValueStore::new(u64::MAX));
+    store.set("text", b"hello".to_vec()).unwrap();
+    let listeners = [(); 3].map(|_| TcpListener::bind("127.0.0.1:0").unwrap());
+    let [tokio_port, raw_port, io_uring_port] = listeners.map(|listener| listener.local_addr().unwrap().port());
+    let config = |port| ServerConfig {
+        port,
+        ..ServerConfig::default()
+    };
+
+    // the servers run until the test process exits
+    let tokio_server = StdServer::with_store(config(tokio_port), store.clone());
+    thread::spawn(move || tokio::runtime::Runtime::new().unwrap().block_on(tokio_server.run()));
+    let raw_server = RawServer::with_store(config(raw_port), store.clone());
+    thread::spawn(move || raw_server.run());
+    let io_uring_server = IoUringServer::with_store(config(io_uring_port), store);
+    let io_uring = thread::spawn(move || io_uring_server.run());
+    thread::sleep(Duration::from_millis(100));
+
+    let mut servers = vec![("tokio", tokio_port), ("raw-epoll", raw_port)];
+    if !io_uring.is_finished() {
+        servers.push(("io-uring", io_uring_port));
+    } else if let Err(e) = io_uring.join().unwrap() {
+        assert!(matches!(e, ServerError::IoUringUnavailable(_)), "{}", e);
+    }
+
+    for (name, port) in servers {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let payload = bincode::serialize(&"text").unwrap();
+        Message::new_request(1, OpCode::Incr, payload).write_to(&mut stream).unwrap();
+        let response = Message::read_from(&mut stream).unwrap();
+        assert_eq!(response.error_code(), Some(ErrorCode::NotAnInteger), "{}", name);
+    }
+}
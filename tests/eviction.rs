@@ -0,0 +1,79 @@
+use std::thread;
+use std::time::Duration;
+use tcp_server::error::ServerError;
+use tcp_server::storage::{EvictionPolicy, KeyValueStore};
+
+const KEYS: [&str; 4] = ["k0", "k1", "k2", "k3"];
+
+// what one of the test entries is charged
+fn entry_size() -> u64 {
+    let store = KeyValueStore::new(u64::MAX);
+    store.set("k0", vec![0; 100]).unwrap();
+    store.current_size()
+}
+
+// a store holding exactly `KEYS`; with fewer keys than the eviction sample,
+// every key is a candidate, so the victim is deterministic
+fn full_store(policy: EvictionPolicy) -> KeyValueStore {
+    let store = KeyValueStore::with_eviction_policy(KEYS.len() as u64 * entry_size(), policy);
+    for key in KEYS {
+        store.set(key, vec![0; 100]).unwrap();
+    }
+    tick();
+    store
+}
+
+// access times have millisecond resolution
+fn tick() {
+    thread::sleep(Duration::from_millis(2));
+}
+
+fn remaining(store: &KeyValueStore) -> Vec<String> {
+    let mut keys = store.list_keys().unwrap();
+    keys.sort();
+    keys
+}
+
+#[test]
+fn lru_evicts_the_least_recently_read_key() {
+    let store = full_store(EvictionPolicy::Lru);
+    for key in ["k0", "k1", "k3"] {
+        store.get(key).unwrap();
+        tick();
+    }
+
+    store.set("k4", vec![0; 100]).unwrap();
+    assert_eq!(remaining(&store), ["k0", "k1", "k3", "k4"]);
+    assert_eq!(store.stats().evictions, 1);
+}
+
+#[test]
+fn lfu_evicts_the_least_frequently_read_key() {
+    let store = full_store(EvictionPolicy::Lfu);
+    for _ in 0..3 {
+        for key in ["k0", "k2", "k3"] {
+            store.get(key).unwrap();
+        }
+    }
+    // the most recent read does not save it
+    tick();
+    store.get("k1").unwrap();
+
+    store.set("k4", vec![0; 100]).unwrap();
+    assert_eq!(remaining(&store), ["k0", "k2", "k3", "k4"]);
+    assert_eq!(store.stats().evictions, 1);
+}
+
+#[test]
+fn no_eviction_rejects_writes_over_capacity() {
+    let store = full_store(EvictionPolicy::NoEviction);
+    let size = store.current_size();
+
+    match store.set("k4", vec![0; 100]) {
+        Err(ServerError::Storage(reason)) => assert!(reason.contains("Storage capacity exceeded")),
+        other => panic!("expected a capacity error, got {:?}", other),
+    }
+    assert_eq!(remaining(&store), KEYS);
+    assert_eq!(store.current_size(), size);
+    assert_eq!(store.stats().evictions, 0);
+}
@@ -0,0 +1,76 @@
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tcp_server::client::Client;
+use tcp_server::config::ServerConfig;
+use tcp_server::server::StdServer;
+use tcp_server::storage::{KeyTtl, KeyValueStore};
+
+const TTL: Duration = Duration::from_millis(50);
+
+#[test]
+fn expired_keys_are_reclaimed_on_access() {
+    let store = KeyValueStore::new(u64::MAX);
+    store.set("kept", b"v".to_vec()).unwrap();
+    let kept_size = store.current_size();
+    store.set_with_ttl("short", b"v".to_vec(), TTL).unwrap();
+    thread::sleep(TTL * 2);
+
+    // nothing sweeps this store, so the entry is still charged
+    assert_eq!(store.entry_count(), 2);
+    assert_eq!(store.get("short").unwrap(), None);
+    assert_eq!(store.entry_count(), 1);
+    assert_eq!(store.current_size(), kept_size);
+}
+
+#[test]
+fn the_sweeper_reclaims_expired_keys_without_access() {
+    let store = Arc::new(KeyValueStore::new(u64::MAX));
+    store.set("kept", b"v".to_vec()).unwrap();
+    let kept_size = store.current_size();
+    for i in 0..10 {
+        store.set_with_ttl(&format!("k{}", i), vec![0; 100], TTL).unwrap();
+    }
+    let sweeper = KeyValueStore::spawn_sweeper(&store, Duration::from_millis(20));
+    assert!(sweeper.is_some());
+    thread::sleep(TTL * 4);
+
+    assert_eq!(store.entry_count(), 1);
+    assert_eq!(store.current_size(), kept_size);
+}
+
+#[test]
+fn a_zero_sweep_interval_disables_the_sweeper() {
+    let store = Arc::new(KeyValueStore::new(u64::MAX));
+    assert!(KeyValueStore::spawn_sweeper(&store, Duration::ZERO).is_none());
+}
+
+#[test]
+fn ttls_are_managed_over_the_protocol() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let config = ServerConfig {
+        port,
+        ..ServerConfig::default()
+    };
+    let server = StdServer::with_store(config, Arc::new(KeyValueStore::new(u64::MAX)));
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || tokio::runtime::Runtime::new().unwrap().block_on(server.run()));
+    thread::sleep(Duration::from_millis(100));
+    let mut client = Client::connect(&format!("127.0.0.1:{}", port)).unwrap();
+
+    client.store_with_ttl("a", b"1".to_vec(), Duration::from_secs(60)).unwrap();
+    assert!(matches!(client.ttl("a").unwrap(), KeyTtl::Remaining(left) if left <= Duration::from_secs(60)));
+    assert!(client.persist("a").unwrap());
+    assert_eq!(client.ttl("a").unwrap(), KeyTtl::NoExpiry);
+    assert!(!client.persist("a").unwrap());
+
+    assert!(client.expire("a", TTL).unwrap());
+    assert!(!client.expire("missing", TTL).unwrap());
+    thread::sleep(TTL * 2);
+    assert_eq!(client.retrieve("a").unwrap(), None);
+    assert_eq!(client.ttl("a").unwrap(), KeyTtl::Missing);
+
+    shutdown.shutdown();
+    running.join().unwrap().unwrap();
+}
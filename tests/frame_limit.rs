@@ -0,0 +1,89 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use tcp_server::config::ServerConfig;
+use tcp_server::protocol::{Message, OpCode};
+use tcp_server::server::{RawServer, StdServer};
+
+// records the largest allocation, to show the oversized payload never gets a buffer
+struct Tracking;
+
+static LARGEST: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Tracking {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LARGEST.fetch_max(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        LARGEST.fetch_max(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Tracking = Tracking;
+
+// a request header announcing a 1 GiB STORE payload that never follows
+fn oversized_header(request_id: u32) -> Vec<u8> {
+    let mut header = vec![1];
+    header.extend_from_slice(&request_id.to_be_bytes());
+    header.push(2);
+    header.extend_from_slice(&(1u32 << 30).to_be_bytes());
+    header
+}
+
+fn config() -> (ServerConfig, SocketAddr) {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let config = ServerConfig {
+        port,
+        ..ServerConfig::default()
+    };
+    (config, SocketAddr::from(([127, 0, 0, 1], port)))
+}
+
+// both servers run until the test process exits
+fn start_std() -> SocketAddr {
+    let (config, addr) = config();
+    let server = StdServer::new(config).unwrap();
+    thread::spawn(move || tokio::runtime::Runtime::new().unwrap().block_on(server.run()));
+    addr
+}
+
+fn start_raw() -> SocketAddr {
+    let (config, addr) = config();
+    let server = RawServer::new(config).unwrap();
+    thread::spawn(move || server.run());
+    addr
+}
+
+#[test]
+fn oversized_frames_get_an_error_and_a_close_without_a_payload_buffer() {
+    for (name, addr) in [("tokio", start_std()), ("raw", start_raw())] {
+        thread::sleep(Duration::from_millis(100));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // the server has set up all its buffers once it answers
+        Message::new_request(1, OpCode::Ping, Vec::new()).write_to(&mut stream).unwrap();
+        Message::read_from(&mut stream).unwrap();
+        LARGEST.store(0, Ordering::Relaxed);
+        stream.write_all(&oversized_header(42)).unwrap();
+
+        let response = Message::read_from(&mut stream).unwrap();
+        assert!(response.is_error(), "{}: {:?}", name, response);
+        assert_eq!(response.request_id, 42);
+        assert!(response.error_message().contains("Frame too large"));
+
+        let mut rest = Vec::new();
+        assert!(matches!(stream.read_to_end(&mut rest), Ok(0) | Err(_)), "{} kept the connection open", name);
+        let largest = LARGEST.load(Ordering::Relaxed);
+        assert!(largest < 1 << 20, "{} allocated {} bytes for the rejected frame", name, largest);
+    }
+}
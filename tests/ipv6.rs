@@ -0,0 +1,91 @@
+use std::net::{Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::Arc;
+use tcp_server::client::Client;
+use tcp_server::config::ServerConfig;
+use tcp_server::error::ServerError;
+use tcp_server::server::{Server, ServerBuilder, ServerMode};
+use tcp_server::storage::KeyValueStore;
+use tcp_server::utils::SocketUtils;
+
+const MODES: [ServerMode; 3] = [ServerMode::Tokio, ServerMode::RawEpoll, ServerMode::IoUring];
+
+fn ipv6_available() -> bool {
+    std::net::TcpListener::bind("[::1]:0").is_ok()
+}
+
+/// Starts a server on `host`, or returns `None` if `mode` can't run here.
+fn start(mode: ServerMode, host: &str, ipv6_only: bool) -> Option<Box<dyn Server>> {
+    let config = ServerConfig {
+        host: host.parse().unwrap(),
+        port: 0,
+        ipv6_only,
+        workers: 1,
+        ..ServerConfig::default()
+    };
+    let mut server = ServerBuilder::with_store(config, Arc::new(KeyValueStore::new(u64::MAX)))
+        .mode(mode)
+        .build();
+    match server.start() {
+        Ok(()) => Some(server),
+        Err(ServerError::IoUringUnavailable(_)) => None,
+        Err(e) => panic!("{} failed to start: {}", mode, e),
+    }
+}
+
+fn stop(mut server: Box<dyn Server>) {
+    server.shutdown_handle().shutdown();
+    server.wait().unwrap();
+}
+
+#[test]
+fn socket_addresses_convert_for_both_families() {
+    for addr in ["127.0.0.1:8080", "[::1]:9090", "[fe80::1%3]:7"] {
+        let addr: SocketAddr = addr.parse().unwrap();
+        let storage = SocketUtils::std_to_sockaddr(addr).unwrap();
+        assert_eq!(SocketUtils::sockaddr_to_std(&storage).unwrap(), addr);
+    }
+}
+
+#[test]
+fn servers_listen_on_ipv6() {
+    if !ipv6_available() {
+        return;
+    }
+    for mode in MODES {
+        let Some(server) = start(mode, "::1", true) else {
+            continue;
+        };
+        let addr = server.local_addr().unwrap();
+        assert_eq!(addr.ip(), Ipv6Addr::LOCALHOST);
+
+        let mut client = Client::connect(&addr.to_string()).unwrap();
+        assert_eq!(client.ping().unwrap(), "PONG");
+        client.store("family", b"v6".to_vec()).unwrap();
+        assert_eq!(client.retrieve("family").unwrap(), Some(b"v6".to_vec()));
+        drop(client);
+        stop(server);
+    }
+}
+
+#[test]
+fn dual_stack_listeners_accept_ipv4_unless_ipv6_only() {
+    if !ipv6_available() {
+        return;
+    }
+    for mode in MODES {
+        let Some(server) = start(mode, "::", false) else {
+            continue;
+        };
+        let port = server.local_addr().unwrap().port();
+        let mut client = Client::connect(&format!("127.0.0.1:{}", port)).unwrap();
+        assert_eq!(client.ping().unwrap(), "PONG");
+        drop(client);
+        stop(server);
+
+        let server = start(mode, "::", true).unwrap();
+        let port = server.local_addr().unwrap().port();
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+        assert!(TcpStream::connect(("::1", port)).is_ok());
+        stop(server);
+    }
+}
@@ -0,0 +1,136 @@
+use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tcp_server::client::Client;
+use tcp_server::config::ServerConfig;
+use tcp_server::error::ServerError;
+use tcp_server::protocol::Message;
+use tcp_server::server::{IoUringServer, RawServer, StdServer};
+
+// the servers run until the test process exits
+fn start_std(config: ServerConfig) -> SocketAddr {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let server = StdServer::new(ServerConfig { port, ..config }).unwrap();
+    thread::spawn(move || tokio::runtime::Runtime::new().unwrap().block_on(server.run()));
+    thread::sleep(Duration::from_millis(100));
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn start_raw(config: ServerConfig) -> SocketAddr {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let server = RawServer::new(ServerConfig { port, ..config }).unwrap();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(100));
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+/// Returns `None` on kernels without io_uring.
+fn start_io_uring(config: ServerConfig) -> Option<SocketAddr> {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let server = IoUringServer::new(ServerConfig { port, ..config }).unwrap();
+    let thread = thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(100));
+    if thread.is_finished() {
+        match thread.join().unwrap() {
+            Err(ServerError::IoUringUnavailable(reason)) => {
+                eprintln!("skipping io-uring, io_uring unavailable: {}", reason);
+                return None;
+            }
+            result => panic!("io-uring server stopped early: {:?}", result),
+        }
+    }
+    Some(SocketAddr::from(([127, 0, 0, 1], port)))
+}
+
+fn start_all(config: ServerConfig) -> Vec<(&'static str, SocketAddr)> {
+    let mut servers = vec![("tokio", start_std(config.clone())), ("raw-epoll", start_raw(config.clone()))];
+    servers.extend(start_io_uring(config).map(|addr| ("io-uring", addr)));
+    servers
+}
+
+fn config(max_in_flight: usize) -> ServerConfig {
+    ServerConfig {
+        max_storage_size: u64::MAX,
+        max_in_flight,
+        ..ServerConfig::default()
+    }
+}
+
+#[test]
+fn responses_are_matched_to_requests_by_request_id() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    // answers a batch of three in reverse order, echoing each request id as the payload
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let requests: Vec<Message> = (0..3).map(|_| Message::read_from(&mut stream).unwrap()).collect();
+        for request in requests.iter().rev() {
+            let payload = request.request_id.to_string().into_bytes();
+            Message::new_response(request.request_id, payload).write_to(&mut stream).unwrap();
+        }
+    });
+
+    let mut client = Client::connect(&addr).unwrap();
+    let mut pipeline = client.pipeline();
+    pipeline.ping().ping().ping();
+    let responses = pipeline.execute().unwrap();
+
+    let ids: Vec<u32> = responses.iter().map(|response| response.request_id).collect();
+    assert_eq!(ids, [1, 2, 3]);
+    for response in responses {
+        assert_eq!(response.payload, response.request_id.to_string().into_bytes());
+    }
+    server.join().unwrap();
+}
+
+#[test]
+fn pipelines_larger_than_the_socket_buffers_complete() {
+    // few permits, so the tokio server stops reading early
+    for (name, addr) in start_all(config(2)) {
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            let mut client = Client::connect(&addr.to_string()).unwrap();
+            let big = vec![7u8; 1024 * 1024];
+            client.store("big", big.clone()).unwrap();
+
+            // 8 MiB of requests answered by 32 MiB of responses
+            let mut pipeline = client.pipeline();
+            for i in 0..32 {
+                pipeline.store(&format!("k{}", i), vec![i as u8; 256 * 1024]).unwrap();
+                pipeline.retrieve("big").unwrap();
+            }
+            let responses = pipeline.execute().unwrap();
+            assert_eq!(responses.len(), 64);
+            for pair in responses.chunks(2) {
+                assert_eq!(pair[0].payload, b"OK");
+                assert_eq!(pair[1].payload, big);
+            }
+            let _ = done.send(());
+        });
+        finished
+            .recv_timeout(Duration::from_secs(30))
+            .unwrap_or_else(|_| panic!("{} pipeline did not complete", name));
+    }
+}
+
+#[test]
+fn one_request_in_flight_applies_pipelines_in_order() {
+    for (name, addr) in start_all(config(1)) {
+        let mut client = Client::connect(&addr.to_string()).unwrap();
+
+        let mut pipeline = client.pipeline();
+        for i in 0..50u8 {
+            pipeline.store("k", vec![i]).unwrap();
+            pipeline.retrieve("k").unwrap();
+        }
+        pipeline.delete("k").unwrap();
+        pipeline.retrieve("k").unwrap();
+        let responses = pipeline.execute().unwrap();
+
+        for (i, pair) in responses[..100].chunks(2).enumerate() {
+            assert_eq!(pair[1].payload, [i as u8], "{}: retrieve {}", name, i);
+        }
+        assert!(responses[101].is_error(), "{}: {:?}", name, responses[101]);
+    }
+}
@@ -0,0 +1,107 @@
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+use tcp_server::client::{ClientPool, PoolConfig};
+use tcp_server::error::ServerError;
+use tcp_server::protocol::Message;
+
+// answers a single request on every connection, then hangs up
+fn one_shot_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            if let Ok(request) = Message::read_from(&mut stream) {
+                let _ = Message::new_response(request.request_id, b"PONG".to_vec()).write_to(&mut stream);
+            }
+        }
+    });
+    addr
+}
+
+fn config() -> PoolConfig {
+    PoolConfig {
+        max_size: 1,
+        validation_interval: Duration::from_secs(60),
+        ..PoolConfig::default()
+    }
+}
+
+#[test]
+fn failed_connects_back_off_until_the_server_is_reachable() {
+    // a port nothing listens on until later
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let pool = ClientPool::new(
+        &addr.to_string(),
+        PoolConfig {
+            checkout_timeout: Duration::from_millis(100),
+            initial_backoff: Duration::from_millis(300),
+            ..config()
+        },
+    );
+
+    assert!(pool.get().is_err());
+    assert_eq!(pool.metrics().failed_connects, 1);
+
+    // still inside the backoff window, so no new connect is attempted
+    match pool.get() {
+        Err(ServerError::Connection(reason)) => assert!(reason.contains("backing off"), "{}", reason),
+        other => panic!("expected a backoff error, got {:?}", other.err()),
+    }
+    assert_eq!(pool.metrics().failed_connects, 1);
+
+    let _listener = TcpListener::bind(addr).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    drop(pool.get().unwrap());
+    let metrics = pool.metrics();
+    assert_eq!((metrics.total_connects, metrics.failed_connects), (1, 1));
+}
+
+#[test]
+fn broken_connections_are_not_returned_to_the_pool() {
+    let pool = ClientPool::new(&one_shot_server(), config());
+
+    assert_eq!(pool.get().unwrap().ping().unwrap(), "PONG");
+    assert_eq!(pool.metrics().idle, 1);
+
+    // the server hung up after the first ping
+    let mut client = pool.get().unwrap();
+    assert!(client.ping().is_err());
+    assert!(client.is_broken());
+    drop(client);
+    let metrics = pool.metrics();
+    assert_eq!((metrics.idle, metrics.in_use), (0, 0));
+
+    assert_eq!(pool.with_client(|client| client.ping()).unwrap(), "PONG");
+    assert_eq!(pool.metrics().total_connects, 2);
+}
+
+#[test]
+fn checkouts_wait_for_a_connection_to_be_returned() {
+    let pool = ClientPool::new(
+        &one_shot_server(),
+        PoolConfig {
+            checkout_timeout: Duration::from_millis(500),
+            ..config()
+        },
+    );
+
+    let held = pool.get().unwrap();
+    let holder = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        drop(held);
+    });
+    let started = Instant::now();
+    let client = pool.get().unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(150));
+    holder.join().unwrap();
+
+    let started = Instant::now();
+    match pool.get() {
+        Err(ServerError::Connection(reason)) => assert!(reason.contains("Timed out"), "{}", reason),
+        other => panic!("expected a checkout timeout, got {:?}", other.err()),
+    }
+    assert!(started.elapsed() >= Duration::from_millis(450));
+    assert_eq!(pool.metrics().total_connects, 1);
+    drop(client);
+}
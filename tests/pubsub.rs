@@ -0,0 +1,206 @@
+use futures::StreamExt;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tcp_server::client::{AsyncClient, AsyncClientConfig, Client};
+use tcp_server::config::ServerConfig;
+use tcp_server::error::ServerError;
+use tcp_server::protocol::Message;
+use tcp_server::pubsub::{Broker, Published, SlowSubscriberPolicy};
+use tcp_server::server::{RawServer, StdServer};
+use tcp_server::storage::KeyValueStore;
+
+fn decode(push: Message) -> Published {
+    assert!(push.is_push());
+    bincode::deserialize(&push.payload).unwrap()
+}
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[test]
+fn broker_delivers_to_channels_and_patterns() {
+    let broker = Broker::default();
+    let (outbox, mut inbox) = broker.register();
+    assert_eq!(broker.subscribe(&outbox, &names(&["news", "sport"])), 2);
+    assert_eq!(broker.psubscribe(&outbox, &names(&["user.*"])), 3);
+
+    assert_eq!(broker.publish("news", b"hello").unwrap(), 1);
+    assert_eq!(broker.publish("user.42", b"login").unwrap(), 1);
+    assert_eq!(broker.publish("weather", b"rain").unwrap(), 0);
+
+    let first = decode(inbox.blocking_recv().unwrap());
+    assert_eq!((first.channel.as_str(), first.pattern, first.payload), ("news", None, b"hello".to_vec()));
+    let second = decode(inbox.blocking_recv().unwrap());
+    assert_eq!(second.pattern.as_deref(), Some("user.*"));
+    assert_eq!(second.channel, "user.42");
+
+    assert_eq!(broker.unsubscribe(outbox.id(), &names(&["news"])), 2);
+    assert_eq!(broker.publish("news", b"again").unwrap(), 0);
+    assert_eq!(broker.unsubscribe(outbox.id(), &[]), 0);
+    assert_eq!(broker.publish("user.1", b"x").unwrap(), 0);
+}
+
+#[test]
+fn slow_subscribers_drop_or_disconnect() {
+    let broker = Broker::new(2, SlowSubscriberPolicy::Drop);
+    let (outbox, mut inbox) = broker.register();
+    broker.subscribe(&outbox, &names(&["c"]));
+    for _ in 0..5 {
+        broker.publish("c", b"x").unwrap();
+    }
+    assert_eq!(outbox.dropped(), 3);
+    assert!(inbox.blocking_recv().is_some());
+    assert!(!inbox.is_disconnected());
+
+    let broker = Broker::new(2, SlowSubscriberPolicy::Disconnect);
+    let (outbox, mut inbox) = broker.register();
+    broker.subscribe(&outbox, &names(&["c"]));
+    for _ in 0..3 {
+        broker.publish("c", b"x").unwrap();
+    }
+    assert!(inbox.is_disconnected());
+    assert!(inbox.blocking_recv().is_none());
+    // the disconnected subscriber no longer counts as a receiver
+    assert_eq!(broker.publish("c", b"x").unwrap(), 0);
+}
+
+fn exchange(addr: String) {
+    let mut subscriber = Client::connect(&addr).unwrap();
+    assert_eq!(subscriber.subscribe(&["chat"]).unwrap(), 1);
+    assert_eq!(subscriber.psubscribe(&["log.*"]).unwrap(), 2);
+
+    let mut publisher = Client::connect(&addr).unwrap();
+    assert_eq!(publisher.publish("chat", b"hi").unwrap(), 1);
+    assert_eq!(publisher.publish("log.error", b"boom").unwrap(), 1);
+    assert_eq!(publisher.publish("other", b"-").unwrap(), 0);
+
+    let message = subscriber.next_message().unwrap();
+    assert_eq!((message.channel.as_str(), message.payload.as_slice()), ("chat", &b"hi"[..]));
+    let message = subscriber.messages().next().unwrap().unwrap();
+    assert_eq!(message.pattern.as_deref(), Some("log.*"));
+
+    // requests still work on a subscribed connection
+    subscriber.store("k", b"v".to_vec()).unwrap();
+    assert_eq!(subscriber.unsubscribe(&[]).unwrap(), 0);
+    assert_eq!(publisher.publish("chat", b"hi").unwrap(), 0);
+}
+
+#[tokio::test]
+async fn publish_and_subscribe_over_std_server() {
+    let port = free_port();
+    let config = ServerConfig {
+        port,
+        ..ServerConfig::default()
+    };
+    let server = StdServer::with_store(config, Arc::new(KeyValueStore::new(u64::MAX)));
+    let shutdown = server.shutdown_handle();
+    let server = tokio::spawn(async move { server.run().await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let addr = format!("127.0.0.1:{}", port);
+    tokio::task::spawn_blocking({
+        let addr = addr.clone();
+        move || exchange(addr)
+    })
+    .await
+    .unwrap();
+
+    let client = AsyncClient::connect(&addr).await.unwrap();
+    let mut messages = client.messages().unwrap();
+    assert!(client.messages().is_none());
+    assert_eq!(client.subscribe(&["events"]).await.unwrap(), 1);
+    assert_eq!(client.publish("events", b"one").await.unwrap(), 1);
+    let message = tokio::time::timeout(Duration::from_secs(5), messages.next()).await.unwrap().unwrap();
+    assert_eq!(message.payload, b"one");
+
+    shutdown.shutdown();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn async_clients_drop_or_disconnect_when_their_buffer_is_full() {
+    let port = free_port();
+    let config = ServerConfig {
+        port,
+        ..ServerConfig::default()
+    };
+    let server = StdServer::with_store(config, Arc::new(KeyValueStore::new(u64::MAX)));
+    let shutdown = server.shutdown_handle();
+    let server = tokio::spawn(async move { server.run().await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let addr = format!("127.0.0.1:{}", port);
+    let publisher = AsyncClient::connect(&addr).await.unwrap();
+    let connect = |slow_subscriber_policy| {
+        let config = AsyncClientConfig {
+            push_buffer: 2,
+            slow_subscriber_policy,
+            ..AsyncClientConfig::default()
+        };
+        AsyncClient::connect_with_config(addr.clone(), config)
+    };
+
+    let dropping = connect(SlowSubscriberPolicy::Drop).await.unwrap();
+    dropping.subscribe(&["c"]).await.unwrap();
+    for _ in 0..5 {
+        assert_eq!(publisher.publish("c", b"x").await.unwrap(), 1);
+    }
+    for _ in 0..50 {
+        if dropping.dropped_pushes() == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(dropping.dropped_pushes(), 3);
+    let mut messages = dropping.messages().unwrap();
+    for _ in 0..2 {
+        tokio::time::timeout(Duration::from_secs(5), messages.next()).await.unwrap().unwrap();
+    }
+    assert_eq!(dropping.ping().await.unwrap(), "PONG");
+    dropping.unsubscribe(&[]).await.unwrap();
+
+    let disconnecting = connect(SlowSubscriberPolicy::Disconnect).await.unwrap();
+    disconnecting.subscribe(&["c"]).await.unwrap();
+    for _ in 0..3 {
+        publisher.publish("c", b"x").await.unwrap();
+    }
+    // the server forgets the subscriber once the connection is closed
+    let mut receivers = 1;
+    for _ in 0..50 {
+        receivers = publisher.publish("c", b"x").await.unwrap();
+        if receivers == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(receivers, 0);
+    assert!(matches!(disconnecting.ping().await, Err(ServerError::Connection(_))));
+    assert_eq!(disconnecting.dropped_pushes(), 0);
+
+    shutdown.shutdown();
+    server.await.unwrap().unwrap();
+}
+
+#[test]
+fn publish_and_subscribe_over_raw_server() {
+    let port = free_port();
+    let config = ServerConfig {
+        port,
+        ..ServerConfig::default()
+    };
+    let server = RawServer::with_store(config, Arc::new(KeyValueStore::new(u64::MAX)));
+    let shutdown = server.shutdown_handle();
+    let server = thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(100));
+
+    exchange(format!("127.0.0.1:{}", port));
+
+    shutdown.shutdown();
+    server.join().unwrap().unwrap();
+}
@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tcp_server::storage::{BTreeStore, KeyValueStore, LogStore, ScanCursor, ScanFilter, StorageBackend};
+
+fn scan_all(store: &impl StorageBackend, count: usize, filter: Option<&ScanFilter>) -> Vec<String> {
+    let mut cursor = ScanCursor::start();
+    let mut keys = Vec::new();
+    loop {
+        let page = store.scan(&cursor, count, filter).unwrap();
+        keys.extend(page.keys);
+        cursor = page.cursor;
+        if cursor.is_done() {
+            return keys;
+        }
+    }
+}
+
+fn fill(store: &impl StorageBackend, n: usize) -> HashSet<String> {
+    (0..n)
+        .map(|i| {
+            let key = format!("{}:{}", if i % 3 == 0 { "user" } else { "order" }, i);
+            store.set(&key, vec![0; 4]).unwrap();
+            key
+        })
+        .collect()
+}
+
+fn returns_every_key_once(store: &impl StorageBackend) {
+    let expected = fill(store, 1000);
+    for count in [1, 7, 100, 5000] {
+        let keys = scan_all(store, count, None);
+        assert_eq!(keys.len(), expected.len(), "count {}", count);
+        assert_eq!(keys.into_iter().collect::<HashSet<_>>(), expected);
+    }
+
+    let users: HashSet<_> = expected.iter().filter(|key| key.starts_with("user:")).cloned().collect();
+    let prefix = ScanFilter::Prefix("user:".into());
+    assert_eq!(scan_all(store, 50, Some(&prefix)).into_iter().collect::<HashSet<_>>(), users);
+
+    let glob = ScanFilter::Glob("user:*0".into());
+    let tens: HashSet<_> = users.iter().filter(|key| key.ends_with('0')).cloned().collect();
+    assert_eq!(scan_all(store, 50, Some(&glob)).into_iter().collect::<HashSet<_>>(), tens);
+}
+
+#[test]
+fn key_value_store_scan_is_complete() {
+    returns_every_key_once(&KeyValueStore::new(u64::MAX));
+}
+
+#[test]
+fn btree_store_scan_is_complete_and_ordered() {
+    let store = BTreeStore::new(u64::MAX);
+    returns_every_key_once(&store);
+
+    let keys = scan_all(&store, 13, None);
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+fn default_scan_is_complete() {
+    let dir = tempfile::tempdir().unwrap();
+    returns_every_key_once(&LogStore::open(dir.path().join("data.log"), u64::MAX).unwrap());
+}
+
+#[test]
+fn scan_sees_stable_keys_despite_concurrent_churn() {
+    let store = Arc::new(KeyValueStore::new(u64::MAX));
+    let stable = fill(store.as_ref(), 2000);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let churn = {
+        let store = store.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut i = 0u64;
+            while !stop.load(Ordering::Relaxed) {
+                // up to 8000 live churn keys, so the shards resize mid-scan
+                store.set(&format!("churn:{}", i), vec![0; 4]).unwrap();
+                if i >= 8000 {
+                    store.delete(&format!("churn:{}", i - 8000)).unwrap();
+                }
+                i += 1;
+            }
+        })
+    };
+
+    for _ in 0..5 {
+        let seen: HashSet<_> = scan_all(store.as_ref(), 64, None).into_iter().collect();
+        let missing: Vec<_> = stable.difference(&seen).collect();
+        assert!(missing.is_empty(), "missed {} stable key(s)", missing.len());
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    churn.join().unwrap();
+}
+
+#[test]
+fn scan_pages_do_not_revisit_the_shard() {
+    let store = KeyValueStore::new(u64::MAX);
+    let expected = fill(&store, 20_000);
+
+    let keys = scan_all(&store, 10, None);
+    assert_eq!(keys.into_iter().collect::<HashSet<_>>(), expected);
+    // each key is copied once when its shard is entered and returned once
+    assert!(
+        store.scan_keys_visited() <= 2 * expected.len() as u64,
+        "visited {} keys",
+        store.scan_keys_visited()
+    );
+}
+
+#[test]
+fn scan_rejects_foreign_cursors() {
+    let store = KeyValueStore::new(u64::MAX);
+    assert!(store.scan(&ScanCursor(vec![1, 2, 3]), 10, None).is_err());
+    assert!(store.scan(&ScanCursor(vec![0xff; 16]), 10, None).is_err());
+    assert!(BTreeStore::new(u64::MAX).scan(&ScanCursor(vec![0]), 10, None).is_err());
+}
+
+#[test]
+fn glob_patterns() {
+    let cases = [
+        ("*", "anything", true),
+        ("*", "", true),
+        ("user:*", "user:42", true),
+        ("user:*", "order:42", false),
+        ("h?llo", "hello", true),
+        ("h?llo", "hllo", false),
+        ("h[ae]llo", "hallo", true),
+        ("h[ae]llo", "hillo", false),
+        ("h[!ae]llo", "hillo", true),
+        ("h[^ae]llo", "hello", false),
+        ("key[0-9]", "key7", true),
+        ("key[0-9]", "keyx", false),
+        ("*a*b*c", "xxaxxbxxc", true),
+        ("*a*b*c", "xxaxxcxxb", false),
+        ("a\\*b", "a*b", true),
+        ("a\\*b", "axb", false),
+        ("[]]", "]", true),
+    ];
+    for (pattern, key, expected) in cases {
+        let filter = ScanFilter::Glob(pattern.into());
+        assert_eq!(filter.matches(key), expected, "{} vs {}", pattern, key);
+    }
+    assert_eq!(ScanFilter::Glob("user:*:name".into()).literal_prefix(), "user:");
+}
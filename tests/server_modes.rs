@@ -0,0 +1,99 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tcp_server::client::{AsyncClient, Client};
+use tcp_server::config::ServerConfig;
+use tcp_server::error::ServerError;
+use tcp_server::server::{Server, ServerBuilder, ServerMode};
+use tcp_server::storage::KeyValueStore;
+
+fn build(mode: ServerMode) -> Box<dyn Server> {
+    let config = ServerConfig {
+        port: 0,
+        workers: 2,
+        ..ServerConfig::default()
+    };
+    ServerBuilder::with_store(config, Arc::new(KeyValueStore::new(u64::MAX)))
+        .mode(mode)
+        .build()
+}
+
+fn wait_for(server: &dyn Server, active: usize) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.stats().active_connections != active {
+        assert!(Instant::now() < deadline, "{:?}", server.stats());
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn modes_parse_from_config_names() {
+    assert_eq!("tokio".parse(), Ok(ServerMode::Tokio));
+    assert_eq!("raw-epoll".parse(), Ok(ServerMode::RawEpoll));
+    assert_eq!("raw".parse(), Ok(ServerMode::RawEpoll));
+    assert_eq!("raw-threads".parse(), Ok(ServerMode::RawEpoll));
+    assert_eq!("IO-URING".parse(), Ok(ServerMode::IoUring));
+    assert!("threads".parse::<ServerMode>().is_err());
+    assert_eq!(ServerMode::RawEpoll.to_string(), "raw-epoll");
+}
+
+#[test]
+fn every_mode_serves_through_the_server_trait() {
+    for mode in [ServerMode::Tokio, ServerMode::RawEpoll, ServerMode::IoUring] {
+        let mut server = build(mode);
+        match server.start() {
+            Ok(()) => {}
+            Err(ServerError::IoUringUnavailable(reason)) => {
+                eprintln!("skipping {}, io_uring unavailable: {}", mode, reason);
+                continue;
+            }
+            Err(e) => panic!("{} failed to start: {}", mode, e),
+        }
+
+        assert_ne!(server.local_addr().unwrap().port(), 0);
+        let addr = server.local_addr().unwrap().to_string();
+        let mut client = Client::connect(&addr).unwrap();
+        assert_eq!(client.ping().unwrap(), "PONG");
+        client.store("mode", mode.to_string().into_bytes()).unwrap();
+        assert_eq!(client.retrieve("mode").unwrap(), Some(mode.to_string().into_bytes()));
+
+        let stats = server.stats();
+        assert_eq!((stats.mode, stats.active_connections, stats.accepted_connections), (mode, 1, 1));
+        drop(client);
+        wait_for(server.as_ref(), 0);
+
+        let _second = Client::connect(&addr).unwrap();
+        wait_for(server.as_ref(), 1);
+        assert_eq!(server.stats().accepted_connections, 2);
+
+        server.shutdown_handle().shutdown();
+        let summary = server.wait().unwrap();
+        assert_eq!(summary.drained_connections + summary.forced_connections, 1);
+    }
+}
+
+#[test]
+fn a_server_starts_once_and_waits_once() {
+    let mut server = build(ServerMode::RawEpoll);
+    assert!(server.wait().is_err());
+
+    server.start().unwrap();
+    assert!(server.start().is_err());
+    server.shutdown_handle().shutdown();
+    server.wait().unwrap();
+    assert!(server.wait().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tokio_mode_runs_on_the_callers_runtime() {
+    let mut server = build(ServerMode::Tokio);
+    server.start().unwrap();
+
+    let client = AsyncClient::connect(server.local_addr().unwrap()).await.unwrap();
+    assert_eq!(client.ping().await.unwrap(), "PONG");
+    assert_eq!(server.broker().publish("nobody", b"listening").unwrap(), 0);
+
+    server.shutdown_handle().shutdown();
+    let summary = tokio::task::spawn_blocking(move || server.wait()).await.unwrap().unwrap();
+    assert_eq!(summary.drained_connections, 1);
+}
@@ -0,0 +1,203 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tcp_server::client::Client;
+use tcp_server::config::ServerConfig;
+use tcp_server::error::ServerError;
+use tcp_server::protocol::{Message, OpCode};
+use tcp_server::server::{Server, ServerBuilder, ServerMode, ShutdownSummary};
+use tcp_server::storage::KeyValueStore;
+
+const MODES: [ServerMode; 3] = [ServerMode::Tokio, ServerMode::RawEpoll, ServerMode::IoUring];
+
+/// Starts a `mode` server on a free port, or returns `None` on kernels
+/// without io_uring.
+fn start(mode: ServerMode, config: ServerConfig) -> Option<Box<dyn Server>> {
+    let config = ServerConfig { port: 0, ..config };
+    let mut server = ServerBuilder::with_store(config, Arc::new(KeyValueStore::new(u64::MAX)))
+        .mode(mode)
+        .build();
+    match server.start() {
+        Ok(()) => Some(server),
+        Err(ServerError::IoUringUnavailable(reason)) => {
+            eprintln!("skipping {}, io_uring unavailable: {}", mode, reason);
+            None
+        }
+        Err(e) => panic!("{} failed to start: {}", mode, e),
+    }
+}
+
+fn addr(server: &dyn Server) -> String {
+    server.local_addr().unwrap().to_string()
+}
+
+fn stop(mut server: Box<dyn Server>) -> ShutdownSummary {
+    server.shutdown_handle().shutdown();
+    server.wait().unwrap()
+}
+
+fn workers(workers: usize) -> ServerConfig {
+    ServerConfig {
+        workers,
+        ..ServerConfig::default()
+    }
+}
+
+#[test]
+fn serves_many_connections_on_few_workers() {
+    for mode in MODES {
+        let Some(server) = start(mode, workers(2)) else { continue };
+
+        // all connections stay open at once, spread over both workers
+        let mut clients: Vec<_> = (0..200).map(|_| Client::connect(&addr(server.as_ref())).unwrap()).collect();
+        for (i, client) in clients.iter_mut().enumerate() {
+            client.store(&format!("key{}", i), i.to_string().into_bytes()).unwrap();
+        }
+        for (i, client) in clients.iter_mut().enumerate().rev() {
+            let key = format!("key{}", (i + 1) % 200);
+            assert_eq!(client.retrieve(&key).unwrap(), Some(((i + 1) % 200).to_string().into_bytes()), "{}", mode);
+        }
+
+        drop(clients);
+        stop(server);
+    }
+}
+
+#[test]
+fn large_and_pipelined_frames_survive_partial_reads_and_writes() {
+    for mode in MODES {
+        let config = ServerConfig {
+            buffer_size: 512,
+            ..workers(1)
+        };
+        let Some(server) = start(mode, config) else { continue };
+        let mut client = Client::connect(&addr(server.as_ref())).unwrap();
+
+        // several times the socket buffers, so writes would block part way
+        let big: Vec<u8> = (0..8 * 1024 * 1024).map(|i| i as u8).collect();
+        client.store("big", big.clone()).unwrap();
+        assert_eq!(client.retrieve("big").unwrap(), Some(big), "{}", mode);
+
+        let mut pipeline = client.pipeline();
+        for i in 0..500 {
+            pipeline.store(&format!("p{}", i), vec![i as u8; 100]).unwrap();
+        }
+        for i in 0..500 {
+            pipeline.retrieve(&format!("p{}", i)).unwrap();
+        }
+        let responses = pipeline.execute().unwrap();
+        assert_eq!(responses.len(), 1000);
+        assert!(responses[500..].iter().enumerate().all(|(i, r)| r.payload == vec![i as u8; 100]), "{}", mode);
+
+        drop(client);
+        stop(server);
+    }
+}
+
+#[test]
+fn delivers_pushes_between_connections() {
+    for mode in MODES {
+        let Some(server) = start(mode, workers(2)) else { continue };
+        let mut subscriber = Client::connect(&addr(server.as_ref())).unwrap();
+        assert_eq!(subscriber.subscribe(&["chat"]).unwrap(), 1);
+
+        let mut publisher = Client::connect(&addr(server.as_ref())).unwrap();
+        for i in 0..50u8 {
+            assert_eq!(publisher.publish("chat", &[i]).unwrap(), 1);
+        }
+        for i in 0..50u8 {
+            assert_eq!(subscriber.next_message().unwrap().payload, vec![i], "{}", mode);
+        }
+
+        drop((subscriber, publisher));
+        stop(server);
+    }
+}
+
+#[test]
+fn waits_for_a_free_slot_at_max_connections() {
+    for mode in MODES {
+        let config = ServerConfig {
+            max_connections: 1,
+            ..workers(1)
+        };
+        let Some(server) = start(mode, config) else { continue };
+        let mut first = Client::connect(&addr(server.as_ref())).unwrap();
+        assert_eq!(first.ping().unwrap(), "PONG");
+
+        // the second connection sits in the backlog until the first closes
+        let second_addr = addr(server.as_ref());
+        let second = thread::spawn(move || {
+            let mut second = Client::connect(&second_addr).unwrap();
+            second.ping().unwrap()
+        });
+        thread::sleep(Duration::from_millis(300));
+        assert!(!second.is_finished(), "{} served past max_connections", mode);
+
+        drop(first);
+        assert_eq!(second.join().unwrap(), "PONG");
+        stop(server);
+    }
+}
+
+#[test]
+fn oversized_frames_get_an_error_and_a_close() {
+    for mode in MODES {
+        let config = ServerConfig {
+            max_frame_size: 1024,
+            ..workers(1)
+        };
+        let Some(server) = start(mode, config) else { continue };
+        let mut stream = TcpStream::connect(addr(server.as_ref())).unwrap();
+        Message::new_request(7, OpCode::Store, vec![0; 4096]).write_to(&mut stream).unwrap();
+
+        let response = Message::read_from(&mut stream).unwrap();
+        assert!(response.is_error(), "{}", mode);
+        assert_eq!(response.request_id, 7);
+        // the unread rest of the frame may turn the close into a reset
+        let mut rest = Vec::new();
+        assert!(matches!(stream.read_to_end(&mut rest), Ok(0) | Err(_)), "{}", mode);
+
+        stop(server);
+    }
+}
+
+#[test]
+fn echo_mode_returns_the_bytes_sent() {
+    for mode in MODES {
+        let config = ServerConfig {
+            echo_mode: true,
+            ..workers(1)
+        };
+        let Some(server) = start(mode, config) else { continue };
+        let mut stream = TcpStream::connect(addr(server.as_ref())).unwrap();
+        stream.write_all(b"hello, reactor").unwrap();
+
+        let mut echoed = [0; 14];
+        stream.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"hello, reactor", "{}", mode);
+
+        drop(stream);
+        stop(server);
+    }
+}
+
+#[test]
+fn shutdown_sends_going_away_to_open_connections() {
+    for mode in MODES {
+        let Some(server) = start(mode, workers(2)) else { continue };
+        let mut streams: Vec<_> = (0..4).map(|_| TcpStream::connect(addr(server.as_ref())).unwrap()).collect();
+        for stream in &mut streams {
+            Message::new_request(1, OpCode::Ping, Vec::new()).write_to(stream).unwrap();
+            assert_eq!(Message::read_from(stream).unwrap().payload, b"PONG");
+        }
+
+        let summary = stop(server);
+        assert_eq!((summary.drained_connections, summary.forced_connections), (4, 0), "{}", mode);
+        for stream in &mut streams {
+            assert!(Message::read_from(stream).unwrap().is_going_away(), "{}", mode);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tcp_server::config::ServerConfig;
+use tcp_server::storage::{EvictionPolicy, FsyncPolicy, KeyTtl, KeyValueStore, SnapshotConfig};
+
+fn snapshots_in(dir: &std::path::Path) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "snap"))
+        .count()
+}
+
+#[test]
+fn snapshot_round_trips_entries() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KeyValueStore::new(u64::MAX);
+    for i in 0..100 {
+        store.set(&format!("key-{}", i), vec![i as u8; i]).unwrap();
+    }
+    store.set_with_ttl("ttl", b"x".to_vec(), Duration::from_secs(60)).unwrap();
+
+    let info = store.snapshot_to(dir.path(), 3).unwrap();
+    assert_eq!(info.entries, 101);
+    assert!(!info.path.with_extension("tmp").exists());
+
+    let restored = KeyValueStore::new(u64::MAX);
+    let path = KeyValueStore::latest_snapshot(dir.path()).unwrap().unwrap();
+    assert_eq!(path, info.path);
+    assert_eq!(restored.load_snapshot(&path).unwrap(), 101);
+    assert_eq!(restored.current_size(), store.current_size());
+    assert_eq!(restored.get("key-42").unwrap(), Some(vec![42; 42]));
+    assert!(matches!(restored.ttl("ttl").unwrap(), KeyTtl::Remaining(_)));
+}
+
+#[test]
+fn old_snapshots_are_pruned() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KeyValueStore::new(u64::MAX);
+    for i in 0..5 {
+        store.set("key", vec![i]).unwrap();
+        store.snapshot_to(dir.path(), 2).unwrap();
+    }
+    assert_eq!(snapshots_in(dir.path()), 2);
+
+    let restored = KeyValueStore::new(u64::MAX);
+    restored.load_snapshot(&KeyValueStore::latest_snapshot(dir.path()).unwrap().unwrap()).unwrap();
+    assert_eq!(restored.get("key").unwrap(), Some(vec![4]));
+}
+
+#[test]
+fn snapshots_never_replace_each_other() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KeyValueStore::new(u64::MAX);
+    let other = KeyValueStore::new(u64::MAX);
+    let mut paths = Vec::new();
+    // far quicker than one per millisecond
+    for _ in 0..5 {
+        paths.push(store.snapshot_to(dir.path(), 10).unwrap().path);
+        paths.push(other.snapshot_to(dir.path(), 10).unwrap().path);
+    }
+    assert_eq!(snapshots_in(dir.path()), 10);
+    let mut sorted = paths.clone();
+    sorted.sort();
+    assert_eq!(sorted, paths);
+}
+
+#[test]
+fn truncated_snapshot_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KeyValueStore::new(u64::MAX);
+    store.set("key", b"value".to_vec()).unwrap();
+    let info = store.snapshot_to(dir.path(), 1).unwrap();
+
+    let len = std::fs::metadata(&info.path).unwrap().len();
+    std::fs::OpenOptions::new().write(true).open(&info.path).unwrap().set_len(len - 1).unwrap();
+
+    assert!(KeyValueStore::new(u64::MAX).load_snapshot(&info.path).is_err());
+}
+
+#[test]
+fn snapshot_compacts_the_append_only_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ServerConfig {
+        aof_enabled: true,
+        aof_path: dir.path().join("appendonly.aof"),
+        aof_fsync: FsyncPolicy::Always,
+        snapshot_enabled: true,
+        snapshot_dir: dir.path().join("snapshots"),
+        ..ServerConfig::default()
+    };
+
+    {
+        let store = KeyValueStore::from_config(&config).unwrap();
+        for i in 0..50 {
+            store.set("counter", i.to_string().into_bytes()).unwrap();
+        }
+        let before = std::fs::metadata(&config.aof_path).unwrap().len();
+        store.snapshot_to(&config.snapshot_dir, 3).unwrap();
+        // only the record naming the snapshot is left
+        assert!(std::fs::metadata(&config.aof_path).unwrap().len() < before / 10);
+
+        // writes after the snapshot only live in the log
+        store.set("after", b"snapshot".to_vec()).unwrap();
+        store.delete("counter").unwrap();
+        store.set("counter", b"final".to_vec()).unwrap();
+    }
+
+    let store = KeyValueStore::from_config(&config).unwrap();
+    assert_eq!(store.get("counter").unwrap(), Some(b"final".to_vec()));
+    assert_eq!(store.get("after").unwrap(), Some(b"snapshot".to_vec()));
+    assert_eq!(store.entry_count(), 2);
+}
+
+#[test]
+fn compacted_log_reopens_without_snapshots_enabled() {
+    let dir = tempfile::tempdir().unwrap();
+    let aof_path = dir.path().join("appendonly.aof");
+    let snapshot_dir = dir.path().join("snapshots");
+    let open = || KeyValueStore::with_aof(u64::MAX, EvictionPolicy::NoEviction, &aof_path, FsyncPolicy::Always);
+
+    {
+        let store = open().unwrap();
+        store.set("before", b"snapshot".to_vec()).unwrap();
+        store.snapshot_to(&snapshot_dir, 3).unwrap();
+        store.set("after", b"snapshot".to_vec()).unwrap();
+    }
+
+    let check = |store: KeyValueStore| {
+        assert_eq!(store.get("before").unwrap(), Some(b"snapshot".to_vec()));
+        assert_eq!(store.get("after").unwrap(), Some(b"snapshot".to_vec()));
+        assert_eq!(store.entry_count(), 2);
+    };
+    check(open().unwrap());
+    let config = ServerConfig {
+        aof_enabled: true,
+        aof_path: aof_path.clone(),
+        snapshot_enabled: false,
+        ..ServerConfig::default()
+    };
+    check(KeyValueStore::from_config(&config).unwrap());
+
+    std::fs::remove_dir_all(&snapshot_dir).unwrap();
+    let err = KeyValueStore::from_config(&config).err().expect("opened without its snapshot");
+    assert!(err.to_string().contains("which is missing"), "{}", err);
+}
+
+#[test]
+fn snapshotter_runs_on_request_and_write_threshold() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = Arc::new(KeyValueStore::with_eviction_policy(u64::MAX, EvictionPolicy::NoEviction));
+    assert!(store.request_snapshot().is_err());
+
+    KeyValueStore::spawn_snapshotter(
+        &store,
+        SnapshotConfig {
+            dir: dir.path().to_path_buf(),
+            retain: 10,
+            interval: None,
+            write_threshold: 100,
+        },
+    );
+
+    store.set("key", b"value".to_vec()).unwrap();
+    store.request_snapshot().unwrap();
+    wait_for(|| snapshots_in(dir.path()) == 1);
+
+    std::thread::sleep(Duration::from_millis(2));
+    for i in 0..100 {
+        store.set(&format!("key-{}", i), b"value".to_vec()).unwrap();
+    }
+    wait_for(|| snapshots_in(dir.path()) == 2);
+}
+
+fn wait_for(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for snapshot");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
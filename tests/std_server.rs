@@ -0,0 +1,108 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tcp_server::client::Client;
+use tcp_server::config::ServerConfig;
+use tcp_server::protocol::{Message, OpCode};
+use tcp_server::server::StdServer;
+use tcp_server::storage::KeyValueStore;
+
+// serves from a runtime of its own until the test process exits
+fn start(config: ServerConfig) -> SocketAddr {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let server = StdServer::new(ServerConfig { port, ..config }).unwrap();
+    thread::spawn(move || tokio::runtime::Runtime::new().unwrap().block_on(server.run()));
+    thread::sleep(Duration::from_millis(100));
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+#[test]
+fn serves_the_key_value_protocol_by_default() {
+    let addr = start(ServerConfig::default());
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+
+    assert_eq!(client.ping().unwrap(), "PONG");
+    client.store("a", b"1".to_vec()).unwrap();
+    client.store("b", b"2".to_vec()).unwrap();
+    assert_eq!(client.retrieve("a").unwrap(), Some(b"1".to_vec()));
+
+    let mut keys = client.list().unwrap();
+    keys.sort();
+    assert_eq!(keys, ["a", "b"]);
+
+    client.delete("a").unwrap();
+    assert_eq!(client.retrieve("a").unwrap(), None);
+    assert_eq!(client.list().unwrap(), ["b"]);
+}
+
+#[test]
+fn echoes_bytes_only_in_echo_mode() {
+    let addr = start(ServerConfig {
+        echo_mode: true,
+        ..ServerConfig::default()
+    });
+    let mut stream = TcpStream::connect(addr).unwrap();
+    // not a valid frame, echoed back untouched
+    stream.write_all(b"hello, echo").unwrap();
+    let mut echoed = [0; 11];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"hello, echo");
+
+    std::env::remove_var("SERVER_ECHO_MODE");
+    assert!(!ServerConfig::new().unwrap().echo_mode);
+    std::env::set_var("SERVER_ECHO_MODE", "true");
+    assert!(ServerConfig::new().unwrap().echo_mode);
+    std::env::remove_var("SERVER_ECHO_MODE");
+}
+
+fn retrieve_request(request_id: u32, key: &str) -> Message {
+    Message::new_request(request_id, OpCode::Retrieve, bincode::serialize(&key).unwrap())
+}
+
+#[test]
+fn shutdown_answers_in_flight_requests_and_reports_forced_connections() {
+    let store = Arc::new(KeyValueStore::new(u64::MAX));
+    store.set("big", vec![0; 1024 * 1024]).unwrap();
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let config = ServerConfig {
+        port,
+        drain_timeout_ms: 2000,
+        ..ServerConfig::default()
+    };
+    let server = StdServer::with_store(config, store);
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || tokio::runtime::Runtime::new().unwrap().block_on(server.run()));
+    thread::sleep(Duration::from_millis(100));
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+
+    // reads only its first response, so the server's writes stall and it cannot drain
+    let mut stalled = TcpStream::connect(addr).unwrap();
+    for request_id in 1..=64 {
+        retrieve_request(request_id, "big").write_to(&mut stalled).unwrap();
+    }
+    assert!(Message::read_from(&mut stalled).unwrap().is_response());
+
+    // reads every response, but only after the shutdown, so most are still in flight
+    let mut waiting = TcpStream::connect(addr).unwrap();
+    for request_id in 1..=64 {
+        retrieve_request(request_id, "big").write_to(&mut waiting).unwrap();
+    }
+    assert!(Message::read_from(&mut waiting).unwrap().is_response());
+    thread::sleep(Duration::from_millis(200));
+
+    shutdown.shutdown();
+    for _ in 2..=64 {
+        let response = Message::read_from(&mut waiting).unwrap();
+        assert!(response.is_response(), "{:?}", response.request_id);
+        assert_eq!(response.payload.len(), 1024 * 1024);
+    }
+    assert!(Message::read_from(&mut waiting).unwrap().is_going_away());
+    assert_eq!(waiting.read(&mut [0; 1]).unwrap(), 0);
+
+    let summary = running.join().unwrap().unwrap();
+    assert_eq!(summary.drained_connections, 1, "{}", summary);
+    assert_eq!(summary.forced_connections, 1, "{}", summary);
+    assert!(summary.drain_time >= Duration::from_millis(2000));
+}
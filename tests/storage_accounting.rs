@@ -0,0 +1,170 @@
+use proptest::prelude::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tcp_server::storage::{entry_size, EvictionPolicy, KeyValueStore};
+
+const THREADS: u64 = 8;
+const OPS_PER_THREAD: u64 = 20_000;
+const KEYS: u64 = 64;
+
+// xorshift64*, so every thread gets a cheap, reproducible op sequence
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+fn live_size(store: &KeyValueStore) -> u64 {
+    store
+        .list_keys()
+        .unwrap()
+        .iter()
+        .filter_map(|key| store.get(key).unwrap().map(|value| entry_size(key, &value)))
+        .sum()
+}
+
+/// Runs set/overwrite/delete/expire traffic from several threads over a small
+/// key space while a watcher checks that `max_size` is never exceeded.
+fn hammer(store: Arc<KeyValueStore>, max_value_len: u64) {
+    let stop = Arc::new(AtomicBool::new(false));
+    let watcher = {
+        let store = store.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                let size = store.current_size();
+                assert!(size <= store.stats().max_size, "size {} exceeds max_size", size);
+            }
+        })
+    };
+
+    let workers: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let mut rng = Rng(0x9e37_79b9_7f4a_7c15 ^ (t + 1));
+                for _ in 0..OPS_PER_THREAD {
+                    let key = format!("key-{}", rng.below(KEYS));
+                    match rng.below(10) {
+                        0..=5 => {
+                            let value = vec![t as u8; rng.below(max_value_len) as usize];
+                            // capacity errors are expected under a tight limit
+                            let _ = store.set(&key, value);
+                        }
+                        6 => {
+                            let value = vec![t as u8; rng.below(max_value_len) as usize];
+                            let _ = store.set_with_ttl(&key, value, Duration::from_millis(1));
+                        }
+                        7 | 8 => store.delete(&key).unwrap(),
+                        _ => {
+                            store.get(&key).unwrap();
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    stop.store(true, Ordering::Relaxed);
+    watcher.join().unwrap();
+
+    thread::sleep(Duration::from_millis(5));
+    store.purge_expired();
+    assert_eq!(store.current_size(), live_size(&store));
+}
+
+#[test]
+fn size_matches_live_entries_after_concurrent_writes() {
+    hammer(Arc::new(KeyValueStore::new(u64::MAX)), 256);
+}
+
+#[test]
+fn size_stays_within_limit_when_rejecting_writes() {
+    let max_size = 16 * entry_size("key-00", &[0; 128]);
+    hammer(Arc::new(KeyValueStore::new(max_size)), 256);
+}
+
+#[test]
+fn size_stays_within_limit_when_evicting() {
+    for policy in [EvictionPolicy::Lru, EvictionPolicy::Lfu, EvictionPolicy::Random] {
+        let max_size = 16 * entry_size("key-00", &[0; 128]);
+        let store = Arc::new(KeyValueStore::with_eviction_policy(max_size, policy));
+        hammer(store.clone(), 256);
+        assert!(store.stats().evictions > 0, "{} never evicted", policy);
+    }
+}
+
+#[test]
+fn size_counts_keys_and_entry_overhead() {
+    let store = KeyValueStore::new(u64::MAX);
+    store.set("key", b"value".to_vec()).unwrap();
+    assert_eq!(store.current_size(), entry_size("key", b"value"));
+    assert!(store.current_size() > (b"key".len() + b"value".len()) as u64);
+
+    store.set("key", Vec::new()).unwrap();
+    assert_eq!(store.current_size(), entry_size("key", &[]));
+
+    store.delete("key").unwrap();
+    assert_eq!(store.current_size(), 0);
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(u8, usize),
+    Delete(u8),
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        3 => (0..8u8, 0..512usize).prop_map(|(key, len)| Op::Set(key, len)),
+        1 => (0..8u8).prop_map(Op::Delete),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn size_follows_model(ops in prop::collection::vec(op(), 1..200), limit in 1..8u64) {
+        let max_size = limit * entry_size("k0", &[0; 256]);
+        let store = KeyValueStore::new(max_size);
+        let mut model: HashMap<String, usize> = HashMap::new();
+
+        for op in ops {
+            match op {
+                Op::Set(key, len) => {
+                    let key = format!("k{}", key);
+                    let old = model.get(&key).map_or(0, |&len| entry_size(&key, &vec![0; len]));
+                    let fits = model_size(&model) - old + entry_size(&key, &vec![0; len]) <= max_size;
+                    prop_assert_eq!(store.set(&key, vec![0; len]).is_ok(), fits);
+                    if fits {
+                        model.insert(key, len);
+                    }
+                }
+                Op::Delete(key) => {
+                    let key = format!("k{}", key);
+                    store.delete(&key).unwrap();
+                    model.remove(&key);
+                }
+            }
+            prop_assert_eq!(store.current_size(), model_size(&model));
+        }
+    }
+}
+
+fn model_size(model: &HashMap<String, usize>) -> u64 {
+    model.iter().map(|(key, &len)| entry_size(key, &vec![0; len])).sum()
+}
@@ -0,0 +1,202 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tcp_server::storage::{
+    decode_integer, encode_integer, entry_size, EvictionPolicy, FsyncPolicy, KeyValueStore, TxOp, TxOpResult,
+    TxOutcome, WriteCondition,
+};
+
+fn store(key: &str, value: &[u8]) -> TxOp {
+    TxOp::Store {
+        key: key.into(),
+        value: value.to_vec(),
+    }
+}
+
+fn delete(key: &str) -> TxOp {
+    TxOp::Delete { key: key.into() }
+}
+
+fn check(key: &str, condition: WriteCondition) -> TxOp {
+    TxOp::Check {
+        key: key.into(),
+        condition,
+    }
+}
+
+#[test]
+fn commits_every_operation_in_order() {
+    let kv = KeyValueStore::new(u64::MAX);
+    kv.set("old", b"x".to_vec()).unwrap();
+
+    let outcome = kv
+        .transact(vec![
+            check("old", WriteCondition::IfValue(b"x".to_vec())),
+            store("a", b"1"),
+            store("a", b"2"),
+            delete("old"),
+            delete("never"),
+        ])
+        .unwrap();
+    let TxOutcome::Committed(results) = outcome else {
+        panic!("aborted: {:?}", outcome)
+    };
+
+    assert_eq!(results[0], TxOpResult::Checked);
+    assert!(matches!(results[1..3], [TxOpResult::Stored { .. }, TxOpResult::Stored { .. }]));
+    assert_eq!(results[3..], [TxOpResult::Deleted { existed: true }, TxOpResult::Deleted { existed: false }]);
+    assert_eq!(kv.get("a").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(kv.get("old").unwrap(), None);
+    assert_eq!(kv.current_size(), entry_size("a", b"2"));
+}
+
+#[test]
+fn failed_check_applies_nothing() {
+    let kv = KeyValueStore::new(u64::MAX);
+    kv.set("a", b"1".to_vec()).unwrap();
+    let version = kv.get_versioned("a").unwrap().unwrap().version;
+    kv.set("a", b"2".to_vec()).unwrap();
+
+    let outcome = kv
+        .transact(vec![
+            store("b", b"new"),
+            delete("a"),
+            check("missing", WriteCondition::IfAbsent),
+            check("a", WriteCondition::IfVersion(version)),
+        ])
+        .unwrap();
+    assert!(matches!(outcome, TxOutcome::Aborted { op: 3, ref reason } if reason.contains("version")));
+
+    assert_eq!(kv.get("a").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(kv.get("b").unwrap(), None);
+    assert_eq!(kv.current_size(), entry_size("a", b"2"));
+}
+
+#[test]
+fn capacity_is_checked_for_the_whole_batch() {
+    let kv = KeyValueStore::new(entry_size("a", &[0; 100]));
+    kv.set("a", vec![0; 100]).unwrap();
+
+    // storing "b" first would not fit on its own, but the batch as a whole does
+    let outcome = kv.transact(vec![store("b", &[0; 100]), delete("a")]).unwrap();
+    assert!(matches!(outcome, TxOutcome::Committed(_)));
+    assert_eq!(kv.current_size(), entry_size("b", &[0; 100]));
+
+    let err = kv.transact(vec![delete("b"), store("c", &[0; 50]), store("d", &[0; 50])]).unwrap_err();
+    assert!(err.to_string().contains("Storage capacity exceeded"));
+    assert_eq!(kv.get("b").unwrap(), Some(vec![0; 100]));
+    assert_eq!(kv.current_size(), entry_size("b", &[0; 100]));
+}
+
+#[test]
+fn batch_can_evict_other_keys() {
+    let kv = KeyValueStore::with_eviction_policy(2 * entry_size("k0", &[0; 10]), EvictionPolicy::Lru);
+    kv.set("k0", vec![0; 10]).unwrap();
+    kv.set("k1", vec![0; 10]).unwrap();
+
+    let outcome = kv.transact(vec![store("k2", &[0; 10])]).unwrap();
+    assert!(matches!(outcome, TxOutcome::Committed(_)));
+    assert_eq!(kv.entry_count(), 2);
+    assert!(kv.current_size() <= kv.max_size());
+}
+
+#[test]
+fn failed_check_on_a_full_store_neither_evicts_nor_fails() {
+    for policy in [EvictionPolicy::Lru, EvictionPolicy::NoEviction] {
+        let kv = KeyValueStore::with_eviction_policy(2 * entry_size("k0", &[0; 10]), policy);
+        kv.set("k0", vec![0; 10]).unwrap();
+        kv.set("k1", vec![0; 10]).unwrap();
+
+        let outcome = kv
+            .transact(vec![store("k2", &[0; 100]), check("k0", WriteCondition::IfAbsent)])
+            .unwrap();
+        assert!(matches!(outcome, TxOutcome::Aborted { op: 1, .. }), "{} {:?}", policy, outcome);
+        assert_eq!(kv.entry_count(), 2);
+        assert_eq!(kv.stats().evictions, 0);
+        assert_eq!(kv.current_size(), 2 * entry_size("k0", &[0; 10]));
+    }
+}
+
+#[test]
+fn batch_never_evicts_its_own_keys() {
+    let kv = KeyValueStore::with_eviction_policy(3 * entry_size("k0", &[0; 10]), EvictionPolicy::Lru);
+    for key in ["k0", "k1", "k2"] {
+        kv.set(key, vec![0; 10]).unwrap();
+    }
+    // access times have millisecond resolution
+    thread::sleep(Duration::from_millis(2));
+    kv.get("k2").unwrap();
+
+    // k0 is the least recently used, but the batch checks it
+    let outcome = kv
+        .transact(vec![store("k1", &[0; 20]), check("k0", WriteCondition::IfPresent)])
+        .unwrap();
+    assert!(matches!(outcome, TxOutcome::Committed(_)), "{:?}", outcome);
+    let mut keys = kv.list_keys().unwrap();
+    keys.sort();
+    assert_eq!(keys, ["k0", "k1"]);
+    assert_eq!(kv.stats().evictions, 1);
+}
+
+#[test]
+fn transfers_do_not_interleave_with_other_writers() {
+    let kv = Arc::new(KeyValueStore::new(u64::MAX));
+    kv.set("a", encode_integer(1000)).unwrap();
+    kv.set("b", encode_integer(0)).unwrap();
+
+    let mut workers: Vec<_> = (0..4)
+        .map(|_| {
+            let kv = kv.clone();
+            thread::spawn(move || {
+                for _ in 0..200 {
+                    loop {
+                        let a = kv.get("a").unwrap().unwrap();
+                        let b = kv.get("b").unwrap().unwrap();
+                        let ops = vec![
+                            check("a", WriteCondition::IfValue(a.clone())),
+                            check("b", WriteCondition::IfValue(b.clone())),
+                            store("a", &encode_integer(decode_integer(&a).unwrap() - 1)),
+                            store("b", &encode_integer(decode_integer(&b).unwrap() + 1)),
+                        ];
+                        if let TxOutcome::Committed(_) = kv.transact(ops).unwrap() {
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    let deposits = {
+        let kv = kv.clone();
+        thread::spawn(move || {
+            for _ in 0..500 {
+                kv.incr_by("a", 1).unwrap();
+            }
+        })
+    };
+    workers.push(deposits);
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    assert_eq!(kv.get("a").unwrap(), Some(encode_integer(1000 - 800 + 500)));
+    assert_eq!(kv.get("b").unwrap(), Some(encode_integer(800)));
+}
+
+#[test]
+fn committed_batches_survive_replay() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("appendonly.aof");
+    {
+        let kv = KeyValueStore::with_aof(u64::MAX, EvictionPolicy::NoEviction, &path, FsyncPolicy::Always).unwrap();
+        kv.set("gone", b"x".to_vec()).unwrap();
+        kv.transact(vec![store("a", b"1"), store("b", b"2"), delete("gone")]).unwrap();
+        kv.transact(vec![check("a", WriteCondition::IfAbsent), store("c", b"3")]).unwrap();
+    }
+
+    let kv = KeyValueStore::with_aof(u64::MAX, EvictionPolicy::NoEviction, &path, FsyncPolicy::Always).unwrap();
+    let mut keys = kv.list_keys().unwrap();
+    keys.sort();
+    assert_eq!(keys, ["a", "b"]);
+    assert_eq!(kv.current_size(), entry_size("a", b"1") + entry_size("b", b"2"));
+}
@@ -0,0 +1,133 @@
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::Arc;
+use tcp_server::client::Client;
+use tcp_server::config::ServerConfig;
+use tcp_server::error::ServerError;
+use tcp_server::server::{Server, ServerBuilder, ServerMode};
+use tcp_server::storage::KeyValueStore;
+use tcp_server::utils::ListenAddr;
+
+fn build(mode: ServerMode, path: &Path, echo_mode: bool) -> Box<dyn Server> {
+    let mut config = ServerConfig {
+        unix_socket_mode: 0o600,
+        echo_mode,
+        workers: 2,
+        ..ServerConfig::default()
+    };
+    config.set_listen_addr(format!("unix:{}", path.display()).parse().unwrap());
+    ServerBuilder::with_store(config, Arc::new(KeyValueStore::new(u64::MAX)))
+        .mode(mode)
+        .build()
+}
+
+fn start(server: &mut dyn Server, mode: ServerMode) -> bool {
+    match server.start() {
+        Ok(()) => true,
+        Err(ServerError::IoUringUnavailable(reason)) => {
+            eprintln!("skipping {}, io_uring unavailable: {}", mode, reason);
+            false
+        }
+        Err(e) => panic!("{} failed to start: {}", mode, e),
+    }
+}
+
+#[test]
+fn listen_addresses_parse_both_forms() {
+    assert_eq!(
+        "unix:/run/r-tcp.sock".parse(),
+        Ok(ListenAddr::Unix("/run/r-tcp.sock".into()))
+    );
+    assert_eq!("[::1]:9000".parse(), Ok(ListenAddr::Tcp("[::1]:9000".parse().unwrap())));
+    assert!("unix:".parse::<ListenAddr>().is_err());
+    assert!("localhost".parse::<ListenAddr>().is_err());
+
+    let mut config = ServerConfig::default();
+    config.set_listen_addr("unix:/tmp/a.sock".parse().unwrap());
+    assert_eq!(config.listen_addr().to_string(), "unix:/tmp/a.sock");
+    config.set_listen_addr("0.0.0.0:7000".parse().unwrap());
+    assert_eq!((config.unix_socket, config.port), (None, 7000));
+}
+
+#[test]
+fn every_mode_serves_on_a_unix_socket() {
+    for mode in [ServerMode::Tokio, ServerMode::RawEpoll, ServerMode::IoUring] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.sock");
+        let mut server = build(mode, &path, false);
+        if !start(server.as_mut(), mode) {
+            continue;
+        }
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(server.local_addr(), None);
+
+        let mut client = Client::connect(&format!("unix:{}", path.display())).unwrap();
+        assert_eq!(client.ping().unwrap(), "PONG");
+        client.store("mode", mode.to_string().into_bytes()).unwrap();
+        assert_eq!(client.retrieve("mode").unwrap(), Some(mode.to_string().into_bytes()));
+        assert_eq!(server.stats().accepted_connections, 1);
+
+        server.shutdown_handle().shutdown();
+        server.wait().unwrap();
+        assert!(!path.exists(), "{} left its socket file behind", mode);
+    }
+}
+
+#[test]
+fn echo_mode_works_over_a_unix_socket() {
+    use std::io::{Read, Write};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("echo.sock");
+    let mut server = build(ServerMode::Tokio, &path, true);
+    server.start().unwrap();
+
+    let mut stream = std::os::unix::net::UnixStream::connect(&path).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    server.shutdown_handle().shutdown();
+    server.wait().unwrap();
+}
+
+#[test]
+fn a_stale_socket_file_is_replaced() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("stale.sock");
+    // bound and closed: the file stays but nothing accepts on it
+    drop(UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let mut server = build(ServerMode::RawEpoll, &path, false);
+    server.start().unwrap();
+    let mut client = Client::connect(&format!("unix:{}", path.display())).unwrap();
+    assert_eq!(client.ping().unwrap(), "PONG");
+
+    server.shutdown_handle().shutdown();
+    server.wait().unwrap();
+}
+
+#[test]
+fn a_socket_in_use_or_a_regular_file_is_left_alone() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let live = dir.path().join("live.sock");
+    let _listener = UnixListener::bind(&live).unwrap();
+    let mut server = build(ServerMode::Tokio, &live, false);
+    let err = server.start().unwrap_err();
+    assert!(err.to_string().contains("in use"), "{}", err);
+    assert!(live.exists());
+
+    let file = dir.path().join("not-a-socket");
+    std::fs::write(&file, b"data").unwrap();
+    let mut server = build(ServerMode::RawEpoll, &file, false);
+    let err = server.start().unwrap_err();
+    assert!(err.to_string().contains("not a socket"), "{}", err);
+    assert_eq!(std::fs::read(&file).unwrap(), b"data");
+}
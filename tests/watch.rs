@@ -0,0 +1,176 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tcp_server::client::Client;
+use tcp_server::config::ServerConfig;
+use tcp_server::protocol::{Message, OpCode, ProtocolHandler};
+use tcp_server::pubsub::{Broker, WatchTarget};
+use tcp_server::server::StdServer;
+use tcp_server::storage::{
+    entry_size, BTreeStore, EvictionPolicy, KeyEvent, KeyEventKind, KeyListener, KeyValueStore, TxOp,
+    WriteCondition,
+};
+
+#[derive(Default)]
+struct Recorder(Mutex<Vec<KeyEvent>>);
+
+impl KeyListener for Recorder {
+    fn key_changed(&self, event: &KeyEvent) {
+        self.0.lock().unwrap().push(event.clone());
+    }
+}
+
+impl Recorder {
+    fn take(&self) -> Vec<(String, KeyEventKind)> {
+        let events = std::mem::take(&mut *self.0.lock().unwrap());
+        assert!(events.windows(2).all(|pair| pair[1].seq == pair[0].seq + 1), "{:?}", events);
+        events.into_iter().map(|event| (event.key, event.kind)).collect()
+    }
+}
+
+fn set(key: &str, value_len: u64) -> (String, KeyEventKind) {
+    (key.to_string(), KeyEventKind::Set { value_len })
+}
+
+fn deleted(key: &str) -> (String, KeyEventKind) {
+    (key.to_string(), KeyEventKind::Delete)
+}
+
+#[test]
+fn every_mutation_notifies_the_listener() {
+    let store = KeyValueStore::new(u64::MAX);
+    let recorder = Arc::new(Recorder::default());
+    store.set_key_listener(recorder.clone());
+
+    store.set("a", b"123".to_vec()).unwrap();
+    store.set_if("a", b"1".to_vec(), &WriteCondition::IfAbsent).unwrap();
+    store.set_if("b", b"22".to_vec(), &WriteCondition::IfAbsent).unwrap();
+    store.incr_by("n", 10).unwrap();
+    store.delete("a").unwrap();
+    store.delete("a").unwrap();
+    store.expire("b", Duration::from_secs(60)).unwrap();
+    assert_eq!(recorder.take(), [set("a", 3), set("b", 2), set("n", 2), deleted("a")]);
+
+    store
+        .transact(vec![
+            TxOp::Store {
+                key: "c".into(),
+                value: b"x".to_vec(),
+            },
+            TxOp::Delete { key: "b".into() },
+            TxOp::Delete { key: "missing".into() },
+        ])
+        .unwrap();
+    assert_eq!(recorder.take(), [set("c", 1), deleted("b")]);
+
+    store.set_with_ttl("t1", b"x".to_vec(), Duration::from_millis(10)).unwrap();
+    store.set_with_ttl("t2", b"x".to_vec(), Duration::from_millis(10)).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(store.get("t1").unwrap(), None);
+    assert_eq!(store.purge_expired(), 1);
+    assert_eq!(recorder.take(), [set("t1", 1), set("t2", 1), deleted("t1"), deleted("t2")]);
+}
+
+#[test]
+fn evictions_are_deletes() {
+    let store = KeyValueStore::with_eviction_policy(2 * entry_size("k0", &[0; 10]), EvictionPolicy::Lru);
+    let recorder = Arc::new(Recorder::default());
+    store.set_key_listener(recorder.clone());
+
+    for key in ["k0", "k1", "k2"] {
+        store.set(key, vec![0; 10]).unwrap();
+    }
+    let events = recorder.take();
+    assert_eq!(events[..2], [set("k0", 10), set("k1", 10)]);
+    // the victim depends on timing, but it is deleted before k2 lands
+    assert!(events[2] == deleted("k0") || events[2] == deleted("k1"), "{:?}", events);
+    assert_eq!(events[3..], [set("k2", 10)]);
+}
+
+#[test]
+fn broker_pushes_once_per_watching_connection() {
+    let store = Arc::new(KeyValueStore::new(u64::MAX));
+    let broker = Arc::new(Broker::default());
+    store.set_key_listener(broker.clone());
+
+    let (outbox, mut inbox) = broker.register();
+    let targets = [WatchTarget::Key("app/name".into()), WatchTarget::Prefix("app/".into())];
+    assert_eq!(broker.watch(&outbox, &targets), 2);
+
+    store.set("app/name", b"demo".to_vec()).unwrap();
+    store.set("other", b"x".to_vec()).unwrap();
+    store.delete("app/name").unwrap();
+
+    let push = inbox.blocking_recv().unwrap();
+    assert_eq!(push.op_code, OpCode::Watch);
+    let first: KeyEvent = bincode::deserialize(&push.payload).unwrap();
+    assert_eq!(first.kind, KeyEventKind::Set { value_len: 4 });
+    let second: KeyEvent = bincode::deserialize(&inbox.blocking_recv().unwrap().payload).unwrap();
+    assert_eq!((second.key.as_str(), second.kind), ("app/name", KeyEventKind::Delete));
+    // `other` was skipped, so its change still took a sequence number
+    assert_eq!(second.seq, first.seq + 2);
+
+    assert_eq!(broker.unwatch(outbox.id(), &targets[1..]), 1);
+    store.set("app/other", b"x".to_vec()).unwrap();
+    assert_eq!(broker.unwatch(outbox.id(), &[]), 0);
+    store.set("app/name", b"x".to_vec()).unwrap();
+    broker.watch(&outbox, &[WatchTarget::Key("marker".into())]);
+    store.set("marker", b"x".to_vec()).unwrap();
+    let next: KeyEvent = bincode::deserialize(&inbox.blocking_recv().unwrap().payload).unwrap();
+    assert_eq!(next.key, "marker");
+}
+
+#[test]
+fn watch_needs_a_store_that_reports_changes() {
+    let handler = ProtocolHandler::new(Arc::new(BTreeStore::new(u64::MAX)));
+    let (outbox, _inbox) = handler.broker().register();
+    let payload = bincode::serialize(&[WatchTarget::Key("k".into())][..]).unwrap();
+
+    let err = handler
+        .handle_connection_message(Message::new_request(1, OpCode::Watch, payload.clone()), &outbox)
+        .unwrap_err();
+    assert!(err.to_string().contains("WATCH is not supported"));
+    assert!(handler.handle_message(Message::new_request(1, OpCode::Watch, payload)).is_err());
+}
+
+#[tokio::test]
+async fn clients_see_changes_made_by_others() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let config = ServerConfig {
+        port,
+        ..ServerConfig::default()
+    };
+    let server = StdServer::with_store(config, Arc::new(KeyValueStore::new(u64::MAX)));
+    let shutdown = server.shutdown_handle();
+    let server = tokio::spawn(async move { server.run().await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let addr = format!("127.0.0.1:{}", port);
+    tokio::task::spawn_blocking(move || {
+        let mut watcher = Client::connect(&addr).unwrap();
+        assert_eq!(watcher.watch_prefix("config/").unwrap(), 1);
+        assert_eq!(watcher.watch_key("flag").unwrap(), 2);
+
+        let mut writer = Client::connect(&addr).unwrap();
+        writer.store("config/db", b"postgres://".to_vec()).unwrap();
+        writer.store("unrelated", b"x".to_vec()).unwrap();
+        writer.incr("flag").unwrap();
+        writer.delete("config/db").unwrap();
+
+        let events: Vec<_> = (0..3).map(|_| watcher.next_event().unwrap()).collect();
+        assert_eq!(events[0].key, "config/db");
+        assert_eq!(events[0].kind, KeyEventKind::Set { value_len: 11 });
+        assert_eq!((events[1].key.as_str(), events[1].kind), ("flag", KeyEventKind::Set { value_len: 1 }));
+        assert_eq!((events[2].key.as_str(), events[2].kind), ("config/db", KeyEventKind::Delete));
+        assert!(events[0].seq < events[1].seq && events[1].seq < events[2].seq);
+
+        // the watcher's own requests still get their responses
+        assert_eq!(watcher.retrieve("flag").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(watcher.unwatch(&[]).unwrap(), 0);
+    })
+    .await
+    .unwrap();
+
+    shutdown.shutdown();
+    server.await.unwrap().unwrap();
+}